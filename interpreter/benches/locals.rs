@@ -0,0 +1,67 @@
+use std::sync::Arc;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use supert::{Instruction, Machine, Program};
+
+/// Sum of squares of numbers from 1 to `n`, the loop of `test_for_loop`
+fn sum_of_squares(n: i64) -> Vec<u8> {
+    let mut code = vec![Instruction::LoadVal.into(), 0, 0, 0, 0, 0, 0, 0, 0];
+    code.extend([Instruction::WriteVar.into(), b't', b'e', b's', b't']);
+    code.extend([Instruction::LoadVal.into(), 0x01, 0, 0, 0, 0, 0, 0, 0]);
+    code.extend([Instruction::WriteVar.into(), b't', b'e', b'm', b'p']);
+    code.extend([Instruction::ReadVar.into(), b't', b'e', b'm', b'p']);
+    code.push(Instruction::LoadVal.into());
+    code.extend(n.to_le_bytes());
+    code.extend([Instruction::Lte.into(), Instruction::JumpIfFalse.into(), 44]);
+    code.extend([Instruction::ReadVar.into(), b't', b'e', b'm', b'p']);
+    code.extend([Instruction::ReadVar.into(), b't', b'e', b'm', b'p']);
+    code.push(Instruction::Mul.into());
+    code.extend([Instruction::ReadVar.into(), b't', b'e', b's', b't']);
+    code.push(Instruction::Add.into());
+    code.extend([Instruction::WriteVar.into(), b't', b'e', b's', b't']);
+    code.extend([Instruction::ReadVar.into(), b't', b'e', b'm', b'p']);
+    code.extend([Instruction::LoadVal.into(), 0x01, 0, 0, 0, 0, 0, 0, 0]);
+    code.push(Instruction::Add.into());
+    code.extend([Instruction::WriteVar.into(), b't', b'e', b'm', b'p']);
+    code.extend([Instruction::JumpBack.into(), 61]);
+    code.extend([Instruction::ReadVar.into(), b't', b'e', b's', b't']);
+    code.push(Instruction::Finish.into());
+    code
+}
+
+fn program(resolved: bool, fusion: bool) -> Arc<Program> {
+    let mut program = Program::new(sum_of_squares(1000)).unwrap();
+    if resolved {
+        program.resolve_locals().unwrap();
+    }
+    program.set_fusion(fusion).unwrap();
+    Arc::new(program)
+}
+
+fn bench_locals(c: &mut Criterion) {
+    let mut group = c.benchmark_group("sum_of_squares");
+    for (name, resolved, fusion) in [
+        ("named", false, false),
+        ("resolved", true, false),
+        ("named_fused", false, true),
+        ("resolved_fused", true, true),
+    ] {
+        let program = program(resolved, fusion);
+        group.bench_function(name, |b| {
+            b.iter(|| assert_eq!(Machine::new(program.clone()).interpret(), Ok(333_833_500)))
+        });
+    }
+    #[cfg(feature = "jit")]
+    for (name, resolved) in [("named_jit", false), ("resolved_jit", true)] {
+        let mut program = Arc::try_unwrap(program(resolved, true)).unwrap();
+        program.compile_jit().unwrap();
+        let program = Arc::new(program);
+        group.bench_function(name, |b| {
+            b.iter(|| assert_eq!(Machine::new(program.clone()).interpret(), Ok(333_833_500)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_locals);
+criterion_main!(benches);
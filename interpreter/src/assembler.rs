@@ -0,0 +1,345 @@
+use crate::error::VMError;
+use crate::instruction::Instruction;
+
+/// Position in the code to jump or call to, created with `Assembler::label` and placed with `Assembler::bind`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Label(usize);
+
+/// Piece of the assembled code
+#[derive(Debug)]
+enum Item {
+    /// Instruction with its operands
+    Code(Vec<u8>),
+    /// Jump to a label, `instruction` is the short form of the jump and `size` the length of its encoding
+    Jump { instruction: Instruction, label: Label, size: usize },
+    /// `Call`, `TailCall`, `Spawn`, `Try` or `Coroutine` with the `u32` index of a label
+    Index { instruction: Instruction, label: Label },
+    /// `Switch` with its default target and table
+    Switch { default: Label, targets: Vec<Label> },
+    /// `Closure` of the function at a label capturing the slots
+    Closure { label: Label, slots: Vec<u16> },
+}
+
+/// Builds bytecode with jumps and calls to labels, so offsets never have to be counted by hand.
+///
+/// Every jump gets the shortest encoding that reaches its label: the `u8` offset forms (`JumpBack` for short
+/// backward jumps), then the `i16` `Wide` and the `i32` `Long` ones. `JumpIfFalseOrPop` and `JumpIfTrueOrPop`
+/// only have the short form.
+#[derive(Debug, Default)]
+pub struct Assembler {
+    items: Vec<Item>,
+    /// Index of the item every label is bound before
+    labels: Vec<Option<usize>>,
+    /// Index in the code of every bound label, known once the code is assembled
+    addresses: Vec<Option<usize>>,
+}
+
+/// Encoding of a jump with the given length and offset from the next instruction, if it can express it
+fn encode_jump(instruction: Instruction, size: usize, offset: i64) -> Option<Vec<u8>> {
+    let (wide, long) = match instruction {
+        Instruction::Jump => (Instruction::JumpWide, Instruction::JumpLong),
+        Instruction::JumpIfTrue => (Instruction::JumpIfTrueWide, Instruction::JumpIfTrueLong),
+        Instruction::JumpIfFalse => (Instruction::JumpIfFalseWide, Instruction::JumpIfFalseLong),
+        // no wide forms
+        _ => (instruction, instruction),
+    };
+    let mut code = Vec::with_capacity(size);
+    match size {
+        2 if (0..=255).contains(&offset) => code.extend([instruction.into(), offset as u8]),
+        2 if instruction == Instruction::Jump && (-255..0).contains(&offset) => {
+            code.extend([Instruction::JumpBack.into(), -offset as u8]);
+        },
+        3 if wide != instruction => {
+            code.push(wide.into());
+            code.extend_from_slice(&i16::try_from(offset).ok()?.to_le_bytes());
+        },
+        5 if long != instruction => {
+            code.push(long.into());
+            code.extend_from_slice(&i32::try_from(offset).ok()?.to_le_bytes());
+        },
+        _ => return None,
+    }
+    Some(code)
+}
+
+impl Assembler {
+    pub fn new() -> Assembler {
+        Assembler::default()
+    }
+
+    /// Append an instruction with its operands
+    pub fn emit(&mut self, instruction: Instruction, operands: &[u8]) {
+        let mut code = vec![instruction.into()];
+        code.extend_from_slice(operands);
+        self.items.push(Item::Code(code));
+    }
+
+    /// Append an instruction without operands
+    pub fn instruction(&mut self, instruction: Instruction) {
+        self.emit(instruction, &[]);
+    }
+
+    /// Append `LoadVal`
+    pub fn load_val(&mut self, val: i64) {
+        self.emit(Instruction::LoadVal, &val.to_le_bytes());
+    }
+
+    /// New label, not bound to any position yet
+    pub fn label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    /// Bind the label to the position of the next instruction
+    pub fn bind(&mut self, label: Label) {
+        self.labels[label.0] = Some(self.items.len());
+    }
+
+    fn jump_to(&mut self, instruction: Instruction, label: Label) {
+        self.items.push(Item::Jump { instruction, label, size: 2 });
+    }
+
+    pub fn jump(&mut self, label: Label) {
+        self.jump_to(Instruction::Jump, label);
+    }
+
+    pub fn jump_if_true(&mut self, label: Label) {
+        self.jump_to(Instruction::JumpIfTrue, label);
+    }
+
+    pub fn jump_if_false(&mut self, label: Label) {
+        self.jump_to(Instruction::JumpIfFalse, label);
+    }
+
+    pub fn jump_if_false_or_pop(&mut self, label: Label) {
+        self.jump_to(Instruction::JumpIfFalseOrPop, label);
+    }
+
+    pub fn jump_if_true_or_pop(&mut self, label: Label) {
+        self.jump_to(Instruction::JumpIfTrueOrPop, label);
+    }
+
+    /// Append `Call` of the function at the label
+    pub fn call(&mut self, label: Label) {
+        self.items.push(Item::Index { instruction: Instruction::Call, label });
+    }
+
+    /// Append `TailCall` of the function at the label, which has to be followed by `Return`
+    pub fn tail_call(&mut self, label: Label) {
+        self.items.push(Item::Index { instruction: Instruction::TailCall, label });
+    }
+
+    /// Append `Spawn` of the code at the label
+    pub fn spawn(&mut self, label: Label) {
+        self.items.push(Item::Index { instruction: Instruction::Spawn, label });
+    }
+
+    /// Append `Closure`, pushing the function at the label with the values of the local slots.
+    /// Panics if there are more than `u8::MAX` slots.
+    pub fn closure(&mut self, label: Label, slots: &[u16]) {
+        assert!(slots.len() <= u8::MAX as usize, "too many captured slots");
+        self.items.push(Item::Closure { label, slots: slots.to_vec() });
+    }
+
+    /// Append `Try`, entering a region whose errors are handled by the code at the label
+    pub fn try_region(&mut self, handler: Label) {
+        self.items.push(Item::Index { instruction: Instruction::Try, label: handler });
+    }
+
+    /// Append `Coroutine`, pushing a new coroutine running the function at the label
+    pub fn coroutine(&mut self, label: Label) {
+        self.items.push(Item::Index { instruction: Instruction::Coroutine, label });
+    }
+
+    /// Append `Switch`, jumping to the label at the index given by the popped value or to `default`.
+    /// Panics if there are more than `u16::MAX` targets.
+    pub fn switch(&mut self, targets: &[Label], default: Label) {
+        assert!(targets.len() <= u16::MAX as usize, "too many switch targets");
+        self.items.push(Item::Switch { default, targets: targets.to_vec() });
+    }
+
+    /// Pop a value and jump to the label of the first arm matching it, or to `default` if none does.
+    ///
+    /// Dense values become a `Switch` on the value minus the smallest one, sparse ones a chain of comparisons.
+    pub fn match_int(&mut self, arms: &[(i64, Label)], default: Label) {
+        let (Some(min), Some(max)) = (arms.iter().map(|&(val, _)| val).min(), arms.iter().map(|&(val, _)| val).max()) else {
+            self.instruction(Instruction::Drop);
+            self.jump(default);
+            return;
+        };
+        // at most four table entries per arm, compared before adding one so arms at both ends of `i64` do
+        // not overflow
+        if arms.len() >= 3 && max.abs_diff(min) < 4 * arms.len() as u64 {
+            let mut targets = vec![None; max.abs_diff(min) as usize + 1];
+            for &(val, label) in arms {
+                let target = &mut targets[val.abs_diff(min) as usize];
+                target.get_or_insert(label);
+            }
+            // wrapping, so values far below `min` do not overflow and land out of the table
+            self.load_val(min);
+            self.instruction(Instruction::WrappingSub);
+            let targets = targets.into_iter().map(|target| target.unwrap_or(default)).collect::<Vec<_>>();
+            self.switch(&targets, default);
+            return;
+        }
+
+        let matched = arms.iter().map(|_| self.label()).collect::<Vec<_>>();
+        for (&(val, _), &label) in arms.iter().zip(&matched) {
+            self.instruction(Instruction::Dup);
+            self.load_val(val);
+            self.instruction(Instruction::Eq);
+            self.jump_if_true(label);
+        }
+        self.instruction(Instruction::Drop);
+        self.jump(default);
+        for (&(_, target), &label) in arms.iter().zip(&matched) {
+            self.bind(label);
+            self.instruction(Instruction::Drop);
+            self.jump(target);
+        }
+    }
+
+    /// Index in the code of a label, once the code is assembled
+    pub fn address(&self, label: Label) -> Option<usize> {
+        self.addresses.get(label.0).copied().flatten()
+    }
+
+    fn size(item: &Item) -> usize {
+        match item {
+            Item::Code(code) => code.len(),
+            Item::Jump { size, .. } => *size,
+            Item::Index { .. } => 5,
+            Item::Switch { targets, .. } => 7 + 4 * targets.len(),
+            Item::Closure { slots, .. } => 6 + 2 * slots.len(),
+        }
+    }
+
+    /// Index in the code of every item, followed by the length of the code
+    fn layout(&self) -> Vec<usize> {
+        let mut offsets = Vec::with_capacity(self.items.len() + 1);
+        let mut offset = 0;
+        for item in &self.items {
+            offsets.push(offset);
+            offset += Self::size(item);
+        }
+        offsets.push(offset);
+        offsets
+    }
+
+    /// Turn every `Call` directly followed by `Return` into a `TailCall`, unless it is in a `Try` region of its
+    /// function, which has to catch the errors of the callee. Regions go from `Try` to `EndTry` in the order of
+    /// the code and every function starts outside of them, like the verifier sees them.
+    fn tail_calls(&mut self) {
+        let mut functions = vec![false; self.items.len() + 1];
+        for item in &self.items {
+            let label = match item {
+                Item::Index { instruction: Instruction::Call | Instruction::TailCall | Instruction::Spawn | Instruction::Coroutine, label }
+                | Item::Closure { label, .. } => label,
+                _ => continue,
+            };
+            if let Some(index) = self.labels[label.0] {
+                functions[index] = true;
+            }
+        }
+
+        let mut regions = 0usize;
+        for (index, &function) in functions[..self.items.len()].iter().enumerate() {
+            if function {
+                regions = 0;
+            }
+            let is_return = match &self.items[index] {
+                Item::Index { instruction: Instruction::Try, .. } => {
+                    regions += 1;
+                    false
+                },
+                Item::Code(code) if code[0] == Instruction::Try.into() => {
+                    regions += 1;
+                    false
+                },
+                Item::Code(code) if code[0] == Instruction::EndTry.into() => {
+                    regions = regions.saturating_sub(1);
+                    false
+                },
+                Item::Code(code) => code[..] == [Instruction::Return.into()],
+                _ => false,
+            };
+            if is_return && regions == 0 && index > 0 {
+                if let Item::Index { instruction: instruction @ Instruction::Call, .. } = &mut self.items[index - 1] {
+                    *instruction = Instruction::TailCall;
+                }
+            }
+        }
+    }
+
+    /// Resolve the labels and return the code.
+    ///
+    /// A `Call` directly followed by `Return` becomes a `TailCall`, so recursion in tail position runs in
+    /// constant space. Calls in `Try` regions of their function stay calls, a region has to catch the errors
+    /// of the callee.
+    ///
+    /// Jumps start with the short encoding and grow until every one reaches its label, growing a jump can
+    /// only move labels further away, so this settles. Fails with `VMError::InvalidJumpTarget` at the jump
+    /// or call if its label is not bound or out of reach.
+    pub fn assemble(&mut self) -> Result<Vec<u8>, VMError> {
+        self.tail_calls();
+        loop {
+            let offsets = self.layout();
+            let mut grown = false;
+            for index in 0..self.items.len() {
+                let Item::Jump { instruction, label, size } = self.items[index] else {
+                    continue;
+                };
+                let error = || VMError::InvalidJumpTarget(offsets[index]);
+                let target = offsets[self.labels[label.0].ok_or_else(error)?] as i64;
+                let reaches = |size: usize| encode_jump(instruction, size, target - (offsets[index] + size) as i64).is_some();
+                let new_size = [2, 3, 5].into_iter().filter(|&new_size| new_size >= size).find(|&new_size| reaches(new_size));
+                let new_size = new_size.ok_or_else(error)?;
+                if new_size != size {
+                    self.items[index] = Item::Jump { instruction, label, size: new_size };
+                    grown = true;
+                }
+            }
+            if !grown {
+                break;
+            }
+        }
+
+        let offsets = self.layout();
+        self.addresses = self.labels.iter().map(|item| item.map(|item| offsets[item])).collect();
+        let mut code = Vec::with_capacity(offsets[self.items.len()]);
+        for (index, item) in self.items.iter().enumerate() {
+            let error = || VMError::InvalidJumpTarget(offsets[index]);
+            match item {
+                Item::Code(bytes) => code.extend_from_slice(bytes),
+                Item::Jump { instruction, label, size } => {
+                    let target = self.addresses[label.0].ok_or_else(error)?;
+                    let offset = target as i64 - (offsets[index] + size) as i64;
+                    code.extend(encode_jump(*instruction, *size, offset).ok_or_else(error)?);
+                },
+                Item::Index { instruction, label } => {
+                    let address = self.addresses[label.0].ok_or_else(error)?;
+                    code.push((*instruction).into());
+                    code.extend_from_slice(&(address as u32).to_le_bytes());
+                },
+                Item::Switch { default, targets } => {
+                    code.push(Instruction::Switch.into());
+                    code.extend_from_slice(&(targets.len() as u16).to_le_bytes());
+                    for label in [default].into_iter().chain(targets) {
+                        let address = self.addresses[label.0].ok_or_else(error)?;
+                        code.extend_from_slice(&(address as u32).to_le_bytes());
+                    }
+                },
+                Item::Closure { label, slots } => {
+                    let address = self.addresses[label.0].ok_or_else(error)?;
+                    code.push(Instruction::Closure.into());
+                    code.extend_from_slice(&(address as u32).to_le_bytes());
+                    code.push(slots.len() as u8);
+                    for slot in slots {
+                        code.extend_from_slice(&slot.to_le_bytes());
+                    }
+                },
+            }
+        }
+        Ok(code)
+    }
+}
@@ -0,0 +1,125 @@
+use std::collections::HashSet;
+
+use crate::error::VMError;
+
+/// Set of operations a program is allowed to perform.
+///
+/// Everything is allowed by default. Instructions outside of the set fail with `VMError::PermissionDenied`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Capabilities {
+    /// Host functions that may be called, `None` allows all of them
+    pub natives: Option<HashSet<String>>,
+    /// Host functions that may never be called, takes precedence over `natives`
+    pub denied_natives: HashSet<String>,
+    /// `SendChannel` is allowed
+    pub send: bool,
+    /// `RecvChannel` is allowed
+    pub recv: bool,
+    /// `Spawn` is allowed
+    pub spawn: bool,
+    /// Capabilities handed to children created with `Spawn`, `None` hands down the same capabilities.
+    /// Children never get more than their parent, these are attenuated by the parent's own.
+    pub children: Option<Box<Capabilities>>,
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Capabilities::all()
+    }
+}
+
+impl Capabilities {
+    /// Allow everything
+    pub fn all() -> Capabilities {
+        Capabilities {
+            natives: None,
+            denied_natives: HashSet::new(),
+            send: true,
+            recv: true,
+            spawn: true,
+            children: None,
+        }
+    }
+
+    /// Allow nothing, pure computation only
+    pub fn none() -> Capabilities {
+        Capabilities {
+            natives: Some(HashSet::new()),
+            denied_natives: HashSet::new(),
+            send: false,
+            recv: false,
+            spawn: false,
+            children: None,
+        }
+    }
+
+    /// Allow calling the host function with the given name
+    pub fn allow_native(&mut self, name: &str) {
+        self.denied_natives.remove(name);
+        if let Some(natives) = &mut self.natives {
+            natives.insert(name.to_string());
+        }
+    }
+
+    /// Deny calling the host function with the given name
+    pub fn deny_native(&mut self, name: &str) {
+        self.denied_natives.insert(name.to_string());
+    }
+
+    /// Capabilities allowed by both `self` and `other`
+    pub fn attenuate(&self, other: &Capabilities) -> Capabilities {
+        let natives = match (&self.natives, &other.natives) {
+            (None, None) => None,
+            (Some(natives), None) | (None, Some(natives)) => Some(natives.clone()),
+            (Some(a), Some(b)) => Some(a.intersection(b).cloned().collect()),
+        };
+        let children = match (&self.children, &other.children) {
+            (None, None) => None,
+            (Some(children), None) | (None, Some(children)) => Some(children.clone()),
+            (Some(a), Some(b)) => Some(Box::new(a.attenuate(b))),
+        };
+        Capabilities {
+            natives,
+            denied_natives: self.denied_natives.union(&other.denied_natives).cloned().collect(),
+            send: self.send && other.send,
+            recv: self.recv && other.recv,
+            spawn: self.spawn && other.spawn,
+            children,
+        }
+    }
+
+    /// Capabilities of a child created with `Spawn`
+    pub fn child(&self) -> Capabilities {
+        match &self.children {
+            Some(children) => self.attenuate(children),
+            None => self.clone(),
+        }
+    }
+
+    /// Check that the host function with the given name may be called
+    pub fn check_native(&self, name: &str) -> Result<(), VMError> {
+        let allowed = !self.denied_natives.contains(name)
+            && self.natives.as_ref().is_none_or(|natives| natives.contains(name));
+        check(allowed)
+    }
+
+    pub fn check_send(&self) -> Result<(), VMError> {
+        check(self.send)
+    }
+
+    pub fn check_recv(&self) -> Result<(), VMError> {
+        check(self.recv)
+    }
+
+    pub fn check_spawn(&self) -> Result<(), VMError> {
+        check(self.spawn)
+    }
+}
+
+fn check(allowed: bool) -> Result<(), VMError> {
+    if allowed {
+        Ok(())
+    } else {
+        Err(VMError::PermissionDenied)
+    }
+}
@@ -0,0 +1,248 @@
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::mpsc::{RecvError, RecvTimeoutError, SendError, TryRecvError};
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+use crate::error::VMError;
+
+/// Values sent and not received yet, with what is needed to tell the receiver about new ones
+#[derive(Debug)]
+struct State<T> {
+    values: VecDeque<T>,
+    senders: usize,
+    receiver: bool,
+    /// Error the sending side stopped with, see `Sender::fail`
+    error: Option<VMError>,
+    /// Task waiting in `Receiver::poll_recv`, woken by the next send or when the last sender is dropped
+    waker: Option<Waker>,
+}
+
+#[derive(Debug)]
+struct Shared<T> {
+    state: Mutex<State<T>>,
+    /// Notified for threads blocked in `Receiver::recv`
+    available: Condvar,
+}
+
+impl<T> Shared<T> {
+    fn notify(&self, state: &mut State<T>) {
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+        self.available.notify_one();
+    }
+}
+
+/// Sending half of a channel, sending never blocks
+#[derive(Debug)]
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+/// Receiving half of a channel, receiving can block the thread or wait in a task
+#[derive(Debug)]
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+/// Unbounded channel that works like `std::sync::mpsc::channel` from threads and from async tasks
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State { values: VecDeque::new(), senders: 1, receiver: true, error: None, waker: None }),
+        available: Condvar::new(),
+    });
+    (Sender { shared: shared.clone() }, Receiver { shared })
+}
+
+impl<T> Sender<T> {
+    /// Send a value, fails with the value if the receiver is gone
+    pub fn send(&self, value: T) -> Result<(), SendError<T>> {
+        let mut state = self.shared.state.lock().unwrap();
+        if !state.receiver {
+            return Err(SendError(value));
+        }
+        state.values.push_back(value);
+        self.shared.notify(&mut state);
+        Ok(())
+    }
+
+    /// Record the error the sending side stopped with, the receiver reads it with `Receiver::error` once
+    /// the channel is closed
+    pub fn fail(&self, error: VMError) {
+        self.shared.state.lock().unwrap().error = Some(error);
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Sender<T> {
+        self.shared.state.lock().unwrap().senders += 1;
+        Sender { shared: self.shared.clone() }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.senders -= 1;
+        if state.senders == 0 {
+            self.shared.notify(&mut state);
+        }
+    }
+}
+
+impl<T> Receiver<T> {
+    /// Receive a value, blocking the thread until one is sent. Fails once every sender is gone.
+    pub fn recv(&self) -> Result<T, RecvError> {
+        let mut state = self.shared.state.lock().unwrap();
+        loop {
+            if let Some(value) = state.values.pop_front() {
+                return Ok(value);
+            }
+            if state.senders == 0 {
+                return Err(RecvError);
+            }
+            state = self.shared.available.wait(state).unwrap();
+        }
+    }
+
+    /// Receive a value, blocking the thread for at most `timeout`
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.shared.state.lock().unwrap();
+        loop {
+            if let Some(value) = state.values.pop_front() {
+                return Ok(value);
+            }
+            if state.senders == 0 {
+                return Err(RecvTimeoutError::Disconnected);
+            }
+            let Some(left) = deadline.checked_duration_since(Instant::now()) else {
+                return Err(RecvTimeoutError::Timeout);
+            };
+            state = self.shared.available.wait_timeout(state, left).unwrap().0;
+        }
+    }
+
+    /// Receive a value if one was sent
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        let mut state = self.shared.state.lock().unwrap();
+        match state.values.pop_front() {
+            Some(value) => Ok(value),
+            None if state.senders == 0 => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+
+    /// Receive a value if one was sent, otherwise wake the task of `cx` on the next send
+    pub fn poll_recv(&self, cx: &mut Context<'_>) -> Poll<Result<T, RecvError>> {
+        let mut state = self.shared.state.lock().unwrap();
+        match state.values.pop_front() {
+            Some(value) => Poll::Ready(Ok(value)),
+            None if state.senders == 0 => Poll::Ready(Err(RecvError)),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            },
+        }
+    }
+
+    /// Receive a value, waiting in the task until one is sent
+    pub fn recv_async(&self) -> Recv<'_, T> {
+        Recv { receiver: self }
+    }
+
+    /// Error a sender failed with, see `Sender::fail`
+    pub fn error(&self) -> Option<VMError> {
+        self.shared.state.lock().unwrap().error.clone()
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().receiver = false;
+    }
+}
+
+/// Future returned by `Receiver::recv_async`
+#[derive(Debug)]
+pub struct Recv<'a, T> {
+    receiver: &'a Receiver<T>,
+}
+
+impl<T> Future for Recv<'_, T> {
+    type Output = Result<T, RecvError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::task::Wake;
+    use std::thread::Thread;
+
+    struct Unpark(Thread);
+
+    impl Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    #[test]
+    fn test_channel() {
+        let (sender, receiver) = channel();
+        sender.send(1).unwrap();
+        let thread = std::thread::spawn(move || {
+            sender.send(2).unwrap();
+            sender.clone().send(3).unwrap();
+        });
+        assert_eq!((receiver.recv(), receiver.recv(), receiver.recv()), (Ok(1), Ok(2), Ok(3)));
+        thread.join().unwrap();
+        // every sender is gone
+        assert_eq!(receiver.try_recv(), Err(TryRecvError::Disconnected));
+        assert_eq!(receiver.recv(), Err(RecvError));
+        assert_eq!(receiver.recv_timeout(Duration::from_secs(1)), Err(RecvTimeoutError::Disconnected));
+        assert_eq!(receiver.error(), None);
+
+        // a sender that failed leaves its error to the receiver
+        let (sender, receiver) = channel::<i64>();
+        sender.fail(VMError::PermissionDenied);
+        drop(sender);
+        assert_eq!((receiver.recv(), receiver.error()), (Err(RecvError), Some(VMError::PermissionDenied)));
+
+        let (sender, receiver) = channel();
+        assert_eq!(receiver.recv_timeout(Duration::from_millis(1)), Err(RecvTimeoutError::Timeout));
+        sender.send(4).unwrap();
+        assert_eq!(receiver.recv_timeout(Duration::from_millis(1)), Ok(4));
+
+        let (sender, receiver) = channel::<i64>();
+        drop(receiver);
+        assert_eq!(sender.send(1), Err(SendError(1)));
+    }
+
+    #[test]
+    fn test_recv_async() {
+        let (sender, receiver) = channel();
+        let waker = Waker::from(Arc::new(Unpark(std::thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        let mut recv = std::pin::pin!(receiver.recv_async());
+        assert_eq!(recv.as_mut().poll(&mut cx), Poll::Pending);
+        let thread = std::thread::spawn(move || sender.send(5).unwrap());
+        // woken by the send
+        loop {
+            if let Poll::Ready(value) = recv.as_mut().poll(&mut cx) {
+                assert_eq!(value, Ok(5));
+                break;
+            }
+            std::thread::park();
+        }
+        thread.join().unwrap();
+        assert_eq!(recv.poll(&mut cx), Poll::Ready(Err(RecvError)));
+    }
+}
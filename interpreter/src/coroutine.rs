@@ -0,0 +1,59 @@
+use std::sync::{Arc, Mutex};
+
+use crate::error::VMError;
+use crate::stack::StackValue;
+use crate::vm::{Frame, Handler};
+
+/// Execution state a coroutine runs in, swapped with the one of the machine while it is resumed
+#[derive(Debug)]
+pub(crate) struct Context {
+    pub(crate) stack: Vec<StackValue>,
+    pub(crate) locals: Vec<Option<StackValue>>,
+    pub(crate) frames: Vec<Frame>,
+    pub(crate) handlers: Vec<Handler>,
+    pub(crate) ip: usize,
+}
+
+#[derive(Debug)]
+enum State {
+    Suspended(Context),
+    Running,
+    Finished,
+}
+
+/// Coroutine value created by `Coroutine`.
+///
+/// Copies share the coroutine, resuming any of them continues the same execution.
+#[derive(Debug, Clone)]
+pub struct Coroutine(Arc<Mutex<State>>);
+
+impl Coroutine {
+    pub(crate) fn new(context: Context) -> Coroutine {
+        Coroutine(Arc::new(Mutex::new(State::Suspended(context))))
+    }
+
+    /// Take the context of a suspended coroutine, it is running until `suspend` or `finish`
+    pub(crate) fn resume(&self) -> Result<Context, VMError> {
+        let mut state = self.0.lock().unwrap();
+        match std::mem::replace(&mut *state, State::Running) {
+            State::Suspended(context) => Ok(context),
+            other => {
+                *state = other;
+                Err(VMError::CoroutineNotResumable)
+            },
+        }
+    }
+
+    pub(crate) fn suspend(&self, context: Context) {
+        *self.0.lock().unwrap() = State::Suspended(context);
+    }
+
+    pub(crate) fn finish(&self) {
+        *self.0.lock().unwrap() = State::Finished;
+    }
+
+    /// Whether the coroutine returned or failed, so it can not be resumed again
+    pub fn is_finished(&self) -> bool {
+        matches!(*self.0.lock().unwrap(), State::Finished)
+    }
+}
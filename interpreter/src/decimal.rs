@@ -0,0 +1,308 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::error::VMError;
+
+/// Maximum number of digits after the decimal point
+pub const MAX_SCALE: u8 = 18;
+
+/// Fixed point decimal number, the value is `mantissa / 10^scale`.
+///
+/// All operations are done on integers, so results are the same on every platform.
+/// Equality and ordering compare values, i.e `1.50 == 1.5`.
+#[derive(Debug, Clone, Copy)]
+pub struct Decimal {
+    mantissa: i128,
+    scale: u8,
+}
+
+/// How to round a result that has more digits than the requested scale
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RoundingMode {
+    /// Towards zero, i.e truncate
+    Down,
+    /// Away from zero
+    Up,
+    /// Towards negative infinity
+    Floor,
+    /// Towards positive infinity
+    Ceiling,
+    /// To nearest, ties away from zero
+    HalfUp,
+    /// To nearest, ties towards zero
+    HalfDown,
+    /// To nearest, ties to the even neighbour (banker's rounding)
+    HalfEven,
+}
+
+impl TryFrom<u8> for RoundingMode {
+    type Error = VMError;
+
+    fn try_from(byte: u8) -> Result<Self, Self::Error> {
+        match byte {
+            0 => Ok(RoundingMode::Down),
+            1 => Ok(RoundingMode::Up),
+            2 => Ok(RoundingMode::Floor),
+            3 => Ok(RoundingMode::Ceiling),
+            4 => Ok(RoundingMode::HalfUp),
+            5 => Ok(RoundingMode::HalfDown),
+            6 => Ok(RoundingMode::HalfEven),
+            _ => Err(VMError::InvalidOperand),
+        }
+    }
+}
+
+impl From<RoundingMode> for u8 {
+    fn from(mode: RoundingMode) -> u8 {
+        match mode {
+            RoundingMode::Down => 0,
+            RoundingMode::Up => 1,
+            RoundingMode::Floor => 2,
+            RoundingMode::Ceiling => 3,
+            RoundingMode::HalfUp => 4,
+            RoundingMode::HalfDown => 5,
+            RoundingMode::HalfEven => 6,
+        }
+    }
+}
+
+/// `10^exp`, fails if it does not fit into `i128`
+fn pow10(exp: u32) -> Result<i128, VMError> {
+    10i128.checked_pow(exp).ok_or(VMError::ArithmeticOverflow)
+}
+
+/// Divide `n` by `d` and round the quotient according to `mode`
+fn div_round(n: i128, d: i128, mode: RoundingMode) -> Result<i128, VMError> {
+    if d == 0 {
+        return Err(VMError::DivisionByZero);
+    }
+    let q = n.checked_div(d).ok_or(VMError::ArithmeticOverflow)?;
+    let r = n % d;
+    if r == 0 {
+        return Ok(q);
+    }
+
+    let negative = (n < 0) != (d < 0);
+    let (r, d) = (r.unsigned_abs(), d.unsigned_abs());
+    // compare the remainder with the other half without doubling it
+    let half = r.cmp(&(d - r));
+    let away_from_zero = match mode {
+        RoundingMode::Down => false,
+        RoundingMode::Up => true,
+        RoundingMode::Floor => negative,
+        RoundingMode::Ceiling => !negative,
+        RoundingMode::HalfUp => half != Ordering::Less,
+        RoundingMode::HalfDown => half == Ordering::Greater,
+        RoundingMode::HalfEven => half == Ordering::Greater || (half == Ordering::Equal && q % 2 != 0),
+    };
+
+    if !away_from_zero {
+        Ok(q)
+    } else if negative {
+        q.checked_sub(1).ok_or(VMError::ArithmeticOverflow)
+    } else {
+        q.checked_add(1).ok_or(VMError::ArithmeticOverflow)
+    }
+}
+
+impl Decimal {
+    pub fn new(mantissa: i128, scale: u8) -> Result<Decimal, VMError> {
+        if scale > MAX_SCALE {
+            return Err(VMError::InvalidOperand);
+        }
+        Ok(Decimal { mantissa, scale })
+    }
+
+    /// Change the number of digits after the decimal point
+    pub fn rescale(&self, scale: u8, mode: RoundingMode) -> Result<Decimal, VMError> {
+        if scale > MAX_SCALE {
+            return Err(VMError::InvalidOperand);
+        }
+        let mantissa = if scale >= self.scale {
+            self.mantissa
+                .checked_mul(pow10((scale - self.scale) as u32)?)
+                .ok_or(VMError::ArithmeticOverflow)?
+        } else {
+            div_round(self.mantissa, pow10((self.scale - scale) as u32)?, mode)?
+        };
+        Ok(Decimal { mantissa, scale })
+    }
+
+    /// Exact sum, the scale of the result is the larger one of the operands
+    pub fn checked_add(&self, other: &Decimal) -> Result<Decimal, VMError> {
+        let scale = self.scale.max(other.scale);
+        let (a, b) = (self.rescale(scale, RoundingMode::Down)?, other.rescale(scale, RoundingMode::Down)?);
+        let mantissa = a.mantissa.checked_add(b.mantissa).ok_or(VMError::ArithmeticOverflow)?;
+        Ok(Decimal { mantissa, scale })
+    }
+
+    /// Exact difference, the scale of the result is the larger one of the operands
+    pub fn checked_sub(&self, other: &Decimal) -> Result<Decimal, VMError> {
+        let scale = self.scale.max(other.scale);
+        let (a, b) = (self.rescale(scale, RoundingMode::Down)?, other.rescale(scale, RoundingMode::Down)?);
+        let mantissa = a.mantissa.checked_sub(b.mantissa).ok_or(VMError::ArithmeticOverflow)?;
+        Ok(Decimal { mantissa, scale })
+    }
+
+    /// Product rounded to `scale` digits
+    pub fn checked_mul(&self, other: &Decimal, scale: u8, mode: RoundingMode) -> Result<Decimal, VMError> {
+        if scale > MAX_SCALE {
+            return Err(VMError::InvalidOperand);
+        }
+        let mantissa = self.mantissa.checked_mul(other.mantissa).ok_or(VMError::ArithmeticOverflow)?;
+        let exact_scale = (self.scale + other.scale) as u32;
+        let mantissa = if scale as u32 >= exact_scale {
+            mantissa
+                .checked_mul(pow10(scale as u32 - exact_scale)?)
+                .ok_or(VMError::ArithmeticOverflow)?
+        } else {
+            div_round(mantissa, pow10(exact_scale - scale as u32)?, mode)?
+        };
+        Ok(Decimal { mantissa, scale })
+    }
+
+    /// Quotient rounded to `scale` digits
+    pub fn checked_div(&self, other: &Decimal, scale: u8, mode: RoundingMode) -> Result<Decimal, VMError> {
+        if scale > MAX_SCALE {
+            return Err(VMError::InvalidOperand);
+        }
+        // a / 10^sa / (b / 10^sb) * 10^s = a * 10^(s + sb - sa) / b
+        let exp = scale as i32 + other.scale as i32 - self.scale as i32;
+        let (n, d) = if exp >= 0 {
+            (self.mantissa.checked_mul(pow10(exp as u32)?).ok_or(VMError::ArithmeticOverflow)?, other.mantissa)
+        } else {
+            (self.mantissa, other.mantissa.checked_mul(pow10((-exp) as u32)?).ok_or(VMError::ArithmeticOverflow)?)
+        };
+        let mantissa = div_round(n, d, mode)?;
+        Ok(Decimal { mantissa, scale })
+    }
+
+    /// Round to an integer
+    pub fn to_i64(self, mode: RoundingMode) -> Result<i64, VMError> {
+        let integer = self.rescale(0, mode)?;
+        i64::try_from(integer.mantissa).map_err(|_| VMError::ArithmeticOverflow)
+    }
+
+    /// Split into integer part rounded towards negative infinity and the fraction scaled to `MAX_SCALE` digits
+    fn parts(&self) -> (i128, i128) {
+        let one = 10i128.pow(self.scale as u32);
+        let fraction = self.mantissa.rem_euclid(one) * 10i128.pow((MAX_SCALE - self.scale) as u32);
+        (self.mantissa.div_euclid(one), fraction)
+    }
+}
+
+impl From<i64> for Decimal {
+    fn from(value: i64) -> Decimal {
+        Decimal { mantissa: value as i128, scale: 0 }
+    }
+}
+
+impl PartialEq for Decimal {
+    fn eq(&self, other: &Decimal) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Decimal {}
+
+impl PartialOrd for Decimal {
+    fn partial_cmp(&self, other: &Decimal) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Decimal {
+    fn cmp(&self, other: &Decimal) -> Ordering {
+        self.parts().cmp(&other.parts())
+    }
+}
+
+impl fmt::Display for Decimal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let digits = self.mantissa.unsigned_abs().to_string();
+        let sign = if self.mantissa < 0 { "-" } else { "" };
+        let scale = self.scale as usize;
+        if scale == 0 {
+            return write!(f, "{}{}", sign, digits);
+        }
+        let digits = format!("{:0>width$}", digits, width = scale + 1);
+        let (integer, fraction) = digits.split_at(digits.len() - scale);
+        write!(f, "{}{}.{}", sign, integer, fraction)
+    }
+}
+
+impl FromStr for Decimal {
+    type Err = VMError;
+
+    /// Parses `[-]digits[.digits]`, the scale is the number of digits after the point
+    fn from_str(s: &str) -> Result<Decimal, VMError> {
+        let (negative, unsigned) = match s.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, s),
+        };
+        let (integer, fraction) = unsigned.split_once('.').unwrap_or((unsigned, ""));
+        let all_digits = |part: &str| part.bytes().all(|byte| byte.is_ascii_digit());
+        if integer.is_empty() || !all_digits(integer) || !all_digits(fraction) || (unsigned.contains('.') && fraction.is_empty()) {
+            return Err(VMError::InvalidDecimal);
+        }
+        if fraction.len() > MAX_SCALE as usize {
+            return Err(VMError::InvalidDecimal);
+        }
+
+        let mantissa: i128 = format!("{}{}", integer, fraction)
+            .parse()
+            .map_err(|_| VMError::ArithmeticOverflow)?;
+        Ok(Decimal {
+            mantissa: if negative { -mantissa } else { mantissa },
+            scale: fraction.len() as u8,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dec(s: &str) -> Decimal {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_rounding_modes() {
+        let cases = [
+            ("2.5", [2, 3, 2, 3, 3, 2, 2]),
+            ("3.5", [3, 4, 3, 4, 4, 3, 4]),
+            ("-2.5", [-2, -3, -3, -2, -3, -2, -2]),
+            ("2.51", [2, 3, 2, 3, 3, 3, 3]),
+            ("-2.49", [-2, -3, -3, -2, -2, -2, -2]),
+        ];
+        let modes = [
+            RoundingMode::Down,
+            RoundingMode::Up,
+            RoundingMode::Floor,
+            RoundingMode::Ceiling,
+            RoundingMode::HalfUp,
+            RoundingMode::HalfDown,
+            RoundingMode::HalfEven,
+        ];
+
+        for (value, expected) in cases {
+            for (mode, expected) in modes.iter().zip(expected) {
+                assert_eq!(dec(value).to_i64(*mode), Ok(expected), "{} {:?}", value, mode);
+            }
+        }
+    }
+
+    #[test]
+    fn test_string_conversion() {
+        assert_eq!(dec("-0.05").to_string(), "-0.05");
+        assert_eq!(dec("120.500").to_string(), "120.500");
+        assert_eq!(dec("7").to_string(), "7");
+        assert_eq!(dec("1.50"), dec("1.5"));
+        assert!(dec("-0.01") < dec("0"));
+        assert_eq!("1.".parse::<Decimal>(), Err(VMError::InvalidDecimal));
+        assert_eq!("1.2.3".parse::<Decimal>(), Err(VMError::InvalidDecimal));
+        assert_eq!("-".parse::<Decimal>(), Err(VMError::InvalidDecimal));
+    }
+}
@@ -1,7 +1,108 @@
 /// VM error type
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum VMError {
     DivisionByZero,
     StackOverflow,
     StackUnderflow,
+    /// Channels own their receiver, so they can be moved around the stack but not copied
+    ChannelNotCopyable,
+    /// Result of an arithmetic operation does not fit into `i64`
+    ArithmeticOverflow,
+    /// Value on the stack has a different type than the instruction expects
+    TypeMismatch,
+    /// Operand of an instruction is out of range, e.g unknown rounding mode or too large decimal scale
+    InvalidOperand,
+    /// String can not be parsed as a decimal number
+    InvalidDecimal,
+    /// `CallNative` refers to a host function that is not registered
+    UnknownHostFunction,
+    /// Host function returned an error
+    HostError(String),
+    /// Operation is not allowed by the capabilities of the program
+    PermissionDenied,
+    /// Other end of the channel is closed, nothing can be received or sent anymore
+    ChannelClosed,
+    /// No function is exported with the given name
+    UnknownFunction,
+    /// Function is called with a different number of arguments than it expects
+    ArityMismatch,
+    /// Byte is not a valid instruction
+    InvalidInstruction(u8),
+    /// Operands of the instruction at the given index are cut off by the end of the program
+    TruncatedInstruction(usize),
+    /// Instruction at the given index jumps into the middle of another instruction or out of the program
+    InvalidJumpTarget(usize),
+    /// `Block`, `Loop` or `If` at the given index is never closed, or `Else` or `End` at the given index does
+    /// not close one
+    UnbalancedBlock(usize),
+    /// `TailCall` at the given index is not directly followed by `Return` or is in a `Try` region of its function
+    NotInTailPosition(usize),
+    /// Instruction at the given index refers to a constant that does not exist or has the wrong type
+    InvalidConstant(usize),
+    /// Bytes do not start with the magic bytes of a supert module
+    BadMagic,
+    /// Module is written in a format version this interpreter does not support
+    UnsupportedVersion(u16),
+    /// Section table or a section of the module is cut off or inconsistent
+    MalformedModule,
+    /// Code generator failed to compile the program to native code
+    JitError(String),
+    /// Value thrown with `Throw` that no `Try` region caught
+    Uncaught(Payload),
+    /// `Resume` of a coroutine that is already running or has finished
+    CoroutineNotResumable,
+    /// `Yield` outside of a coroutine
+    YieldOutsideCoroutine,
+    /// Machine was stopped with its `InterruptHandle` at the instruction at the given index
+    Interrupted(usize),
+    /// Deadline of the machine passed at the instruction at the given index
+    Timeout(usize),
+    /// Thread started with `Spawn` failed with the given error, its channel is closed
+    ChildFailed(Box<VMError>),
+}
+
+/// Value thrown with `Throw`
+#[derive(Debug, Clone, PartialEq)]
+pub enum Payload {
+    Int(i64),
+    Str(String),
+}
+
+impl VMError {
+    /// Number identifying the kind of error, handed to the handler of a `Try` region along with the error.
+    /// A value thrown with `Throw` is 0.
+    pub fn code(&self) -> i64 {
+        match self {
+            VMError::Uncaught(_) => 0,
+            VMError::DivisionByZero => 1,
+            VMError::StackOverflow => 2,
+            VMError::StackUnderflow => 3,
+            VMError::ChannelNotCopyable => 4,
+            VMError::ArithmeticOverflow => 5,
+            VMError::TypeMismatch => 6,
+            VMError::InvalidOperand => 7,
+            VMError::InvalidDecimal => 8,
+            VMError::UnknownHostFunction => 9,
+            VMError::HostError(_) => 10,
+            VMError::PermissionDenied => 11,
+            VMError::ChannelClosed => 12,
+            VMError::UnknownFunction => 13,
+            VMError::ArityMismatch => 14,
+            VMError::InvalidInstruction(_) => 15,
+            VMError::TruncatedInstruction(_) => 16,
+            VMError::InvalidJumpTarget(_) => 17,
+            VMError::UnbalancedBlock(_) => 18,
+            VMError::InvalidConstant(_) => 19,
+            VMError::BadMagic => 20,
+            VMError::UnsupportedVersion(_) => 21,
+            VMError::MalformedModule => 22,
+            VMError::JitError(_) => 23,
+            VMError::NotInTailPosition(_) => 24,
+            VMError::CoroutineNotResumable => 25,
+            VMError::YieldOutsideCoroutine => 26,
+            VMError::Interrupted(_) => 27,
+            VMError::Timeout(_) => 28,
+            VMError::ChildFailed(_) => 29,
+        }
+    }
 }
@@ -0,0 +1,97 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+use crate::error::VMError;
+use crate::stack::StackValue;
+
+/// Rust function callable from bytecode with `CallNative`.
+///
+/// Receives the arguments in the order they were pushed and returns the results to push,
+/// an `Err` is propagated to the program as `VMError::HostError`.
+pub type HostFn = dyn Fn(Vec<StackValue>) -> Result<Vec<StackValue>, String> + Send + Sync;
+
+/// Registered host function together with its signature
+#[derive(Clone)]
+pub struct HostFunction {
+    /// Name the function was registered with
+    pub name: String,
+    /// Number of arguments popped from the stack
+    pub args: usize,
+    /// Number of results pushed onto the stack
+    pub returns: usize,
+    func: Arc<HostFn>,
+}
+
+impl HostFunction {
+    /// Call the function, checking that it returned as many values as its signature says
+    pub fn call(&self, args: Vec<StackValue>) -> Result<Vec<StackValue>, VMError> {
+        let results = (self.func)(args).map_err(VMError::HostError)?;
+        if results.len() != self.returns {
+            return Err(VMError::HostError(format!(
+                "{} returned {} values, expected {}",
+                self.name,
+                results.len(),
+                self.returns
+            )));
+        }
+        Ok(results)
+    }
+}
+
+/// Registry of host functions available to a program.
+///
+/// Functions are identified by the index they were registered at, which is what `CallNative` refers to.
+#[derive(Clone, Default)]
+pub struct HostFunctions {
+    functions: Vec<HostFunction>,
+    ids: HashMap<String, u16>,
+}
+
+impl HostFunctions {
+    pub fn new() -> HostFunctions {
+        HostFunctions::default()
+    }
+
+    /// Register a function and return its id.
+    /// Registering a name again replaces the previous function but keeps its id.
+    ///
+    /// Panics if 65536 functions are registered already, ids of `CallNative` are `u16`.
+    pub fn register<F>(&mut self, name: &str, args: usize, returns: usize, func: F) -> u16
+    where
+        F: Fn(Vec<StackValue>) -> Result<Vec<StackValue>, String> + Send + Sync + 'static,
+    {
+        let function = HostFunction { name: name.to_string(), args, returns, func: Arc::new(func) };
+        if let Some(&id) = self.ids.get(name) {
+            self.functions[id as usize] = function;
+            return id;
+        }
+        let id = u16::try_from(self.functions.len()).expect("more than 65536 host functions registered");
+        self.functions.push(function);
+        self.ids.insert(name.to_string(), id);
+        id
+    }
+
+    /// Id of the function registered with the given name
+    pub fn id(&self, name: &str) -> Option<u16> {
+        self.ids.get(name).copied()
+    }
+
+    /// Function registered with the given id
+    pub fn get(&self, id: u16) -> Result<&HostFunction, VMError> {
+        self.functions.get(id as usize).ok_or(VMError::UnknownHostFunction)
+    }
+
+    /// Function registered with the given name
+    pub fn get_by_name(&self, name: &str) -> Result<&HostFunction, VMError> {
+        self.id(name).ok_or(VMError::UnknownHostFunction).and_then(|id| self.get(id))
+    }
+}
+
+impl fmt::Debug for HostFunctions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.functions.iter().map(|function| &function.name))
+            .finish()
+    }
+}
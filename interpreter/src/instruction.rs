@@ -1,5 +1,7 @@
+use crate::error::VMError;
+
 /// Types of instructions that can be performed on the stack.
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Instruction {
     /// Push a value onto the stack
     LoadVal,
@@ -8,6 +10,8 @@ pub enum Instruction {
     /// Read value from a variable
     ReadVar,
     /// Call a method
+    /// Next four bytes are the little endian `u32` index of the function, followed by the `u8` number of
+    /// arguments `n` and `n` little endian `i64` arguments pushed before the call
     FuncCall,
     /// Add top two values on stack
     Add,
@@ -44,17 +48,216 @@ pub enum Instruction {
     SendChannel,
     /// Pops the channel from the stack, receives a value from the channel (this may block) and pushes it onto the stack
     RecvChannel,
-    /// Pops the channel from the stack and closes the channel
+    /// Start the code at the given index in a new thread, connected to the current one with a channel
+    /// Next four bytes are the little endian `u32` start index, the channel is pushed onto the stack of both
+    /// threads
     Spawn,
     /// Returns return index of the function
+    /// Next four bytes are the little endian `u32` index
     ReturnIndex,
     /// Jump to a specific instruction if top of stack is zero
     Finish,
+    /// Logical and, pushes 1 if both of the top two values are non-zero
+    And,
+    /// Logical or, pushes 1 if either of the top two values is non-zero
+    Or,
+    /// Logical not, pushes 1 if top value is zero, 0 otherwise
+    Not,
+    /// Logical exclusive or, pushes 1 if exactly one of the top two values is non-zero
+    Xor,
+    /// Shift left, amounts outside of `0..64` produce 0
+    Shl,
+    /// Arithmetic shift right, amounts outside of `0..64` produce 0 or -1 depending on the sign
+    Shr,
+    /// Bitwise and of top two values
+    BitAnd,
+    /// Bitwise or of top two values
+    BitOr,
+    /// Bitwise exclusive or of top two values
+    BitXor,
+    /// Negate top value on stack
+    Neg,
+    /// Jump if top value on stack is 0 and keep it, otherwise pop it and continue
+    /// Next byte is the offset
+    JumpIfFalseOrPop,
+    /// Jump if top value on stack is not 0 and keep it, otherwise pop it and continue
+    /// Next byte is the offset
+    JumpIfTrueOrPop,
+    /// Duplicate top value on stack
+    Dup,
+    /// Swap top two values on stack
+    Swap,
+    /// Pop and discard top value on stack
+    Drop,
+    /// Copy second value on stack to the top
+    Over,
+    /// Rotate top three values, moving the third value to the top
+    Rot,
+    /// Copy the n-th value from the top of the stack to the top, `Pick 0` is `Dup`
+    /// Next byte is n
+    Pick,
+    /// Add top two values on stack, wrapping around on overflow
+    WrappingAdd,
+    /// Subtract top two values on stack, wrapping around on overflow
+    WrappingSub,
+    /// Multiply top two values on stack, wrapping around on overflow
+    WrappingMul,
+    /// Add top two values on stack, clamping to `i64::MIN..=i64::MAX` on overflow
+    SaturatingAdd,
+    /// Subtract top two values on stack, clamping to `i64::MIN..=i64::MAX` on overflow
+    SaturatingSub,
+    /// Multiply top two values on stack, clamping to `i64::MIN..=i64::MAX` on overflow
+    SaturatingMul,
+    /// Push a big integer onto the stack
+    /// Next byte is the length `n`, followed by `n` bytes of little endian two's complement value
+    LoadBig,
+    /// Promote top value on stack to a big integer
+    ToBig,
+    /// Convert top value on stack back to a primitive value, fails if it does not fit into `i64`
+    ToInt,
+    /// Push a decimal onto the stack
+    /// Next 8 bytes are the `i64` mantissa, followed by a `u8` scale
+    LoadDec,
+    /// Convert top value on stack to a decimal with scale 0
+    ToDec,
+    /// Round top decimal on stack to a primitive value
+    /// Next byte is the rounding mode
+    DecToInt,
+    /// Add top two decimals on stack, the result has the larger scale of the two
+    DecAdd,
+    /// Subtract top two decimals on stack, the result has the larger scale of the two
+    DecSub,
+    /// Multiply top two decimals on stack
+    /// Next byte is the scale of the result, followed by the rounding mode
+    DecMul,
+    /// Divide top two decimals on stack
+    /// Next byte is the scale of the result, followed by the rounding mode
+    DecDiv,
+    /// Change the scale of the top decimal on stack
+    /// Next byte is the new scale, followed by the rounding mode
+    DecRescale,
+    /// Compare top two decimals on stack, pushes -1, 0 or 1
+    DecCmp,
+    /// Push a string onto the stack
+    /// Next byte is the length `n`, followed by `n` bytes of UTF-8
+    LoadStr,
+    /// Format top decimal on stack as a string
+    DecToStr,
+    /// Parse top string on stack as a decimal
+    StrToDec,
+    /// Call a host function registered on the interpreter
+    /// Next two bytes are the little endian id of the function
+    CallNative,
+    /// Call a host function registered on the interpreter by name
+    /// Next byte is the length `n` of the name, followed by `n` bytes of the name
+    CallNativeNamed,
+    /// Call the function at the given index, `Return` continues after this instruction
+    /// Next four bytes are the little endian `u32` index of the function
+    Call,
+    /// Return from the current function, finishes the program if it is the outermost one
+    Return,
+    /// Push a constant from the constant pool onto the stack
+    /// Next two bytes are the little endian index of the constant
+    LoadConst,
+    /// Read value from a variable named by an identifier constant
+    /// Next two bytes are the little endian index of the constant
+    ReadVarConst,
+    /// Write value to a variable named by an identifier constant
+    /// Next two bytes are the little endian index of the constant
+    WriteVarConst,
+    /// Push a copy of a local variable of the current function
+    /// Next two bytes are the little endian slot of the variable
+    LoadLocal,
+    /// Pop a value into a local variable of the current function
+    /// Next two bytes are the little endian slot of the variable
+    StoreLocal,
+    /// Jump in either direction
+    /// Next two bytes are the little endian `i16` offset from the next instruction
+    JumpWide,
+    /// Jump in either direction if top value on stack is true
+    /// Next two bytes are the little endian `i16` offset from the next instruction
+    JumpIfTrueWide,
+    /// Jump in either direction if top value on stack is 0
+    /// Next two bytes are the little endian `i16` offset from the next instruction
+    JumpIfFalseWide,
+    /// Jump in either direction
+    /// Next four bytes are the little endian `i32` offset from the next instruction
+    JumpLong,
+    /// Jump in either direction if top value on stack is true
+    /// Next four bytes are the little endian `i32` offset from the next instruction
+    JumpIfTrueLong,
+    /// Jump in either direction if top value on stack is 0
+    /// Next four bytes are the little endian `i32` offset from the next instruction
+    JumpIfFalseLong,
+    /// Jump to the given index
+    /// Next four bytes are the little endian `u32` index
+    JumpAbs,
+    /// Jump to the given index if top value on stack is true
+    /// Next four bytes are the little endian `u32` index
+    JumpIfTrueAbs,
+    /// Jump to the given index if top value on stack is 0
+    /// Next four bytes are the little endian `u32` index
+    JumpIfFalseAbs,
+    /// Start a block, closed by `End`. `Break` continues after its `End`
+    Block,
+    /// Start a loop, closed by `End`. `Continue` goes back to its start, `Break` and reaching `End` leave it
+    Loop,
+    /// Start a conditional block, closed by `End`. Pops the top value and skips to after `Else`, or `End`
+    /// if there is none, if it is 0
+    If,
+    /// End the true branch of an `If` and start the false one
+    Else,
+    /// Close the innermost `Block`, `Loop` or `If`
+    End,
+    /// Continue after the `End` of an enclosing block
+    /// Next byte is the depth of the block, 0 is the innermost one
+    Break,
+    /// Go back to the start of an enclosing `Loop`
+    /// Next byte is the depth of the loop, 0 is the innermost block
+    Continue,
+    /// Pop a value and jump to the target at that index of the table, or to the default target if it is out of it
+    /// Next two bytes are the little endian `u16` number of targets `n`, followed by the default target and
+    /// `n` targets, each a little endian `u32` index
+    Switch,
+    /// Start a region whose errors are handled by the code at the given index, until `EndTry`
+    /// Next four bytes are the little endian `u32` index of the handler
+    Try,
+    /// End the innermost `Try` region
+    EndTry,
+    /// Pop an integer or a string and throw it to the handler of the innermost `Try` region
+    Throw,
+    /// Push a function value starting at the given index, capturing local slots of the current function
+    /// Next four bytes are the little endian `u32` index of the function, followed by the `u8` number of
+    /// captured slots `n` and `n` little endian `u16` slots
+    Closure,
+    /// Pop a function value and call it, `Return` continues after this instruction
+    CallValue,
+    /// Call the function at the given index in place of the current one, its `Return` returns to our caller
+    /// Next four bytes are the little endian `u32` index of the function, the next instruction has to be
+    /// `Return`
+    TailCall,
+    /// Push a new coroutine running the function at the given index, it starts on the first `Resume`
+    /// Next four bytes are the little endian `u32` index of the function
+    Coroutine,
+    /// Pop a value and hand it to the resumer of the current coroutine, which continues after its `Resume`.
+    /// The value passed to the next `Resume` is pushed when the coroutine continues
+    Yield,
+    /// Pop a coroutine and a value and continue the coroutine with the value pushed onto its stack.
+    /// Pushes the value it yields and `1`, or the value it returns and `0`
+    Resume,
+    /// Push a copy of a variable resolved with `Program::resolve_locals`, shared by every function
+    /// Next two bytes are the little endian slot of the variable
+    LoadGlobal,
+    /// Pop a value into a variable resolved with `Program::resolve_locals`, shared by every function
+    /// Next two bytes are the little endian slot of the variable
+    StoreGlobal,
 }
 
-impl From<u8> for Instruction {
-    fn from(byte: u8) -> Self {
-        match byte {
+impl TryFrom<u8> for Instruction {
+    type Error = VMError;
+
+    fn try_from(byte: u8) -> Result<Self, Self::Error> {
+        Ok(match byte {
             0 => Instruction::LoadVal,
             1 => Instruction::WriteVar,
             2 => Instruction::ReadVar,
@@ -79,14 +282,149 @@ impl From<u8> for Instruction {
             21 => Instruction::Spawn,
             22 => Instruction::ReturnIndex,
             23 => Instruction::Finish,
-            _ => panic!("Invalid instruction byte: {}", byte),
-        }
+            24 => Instruction::And,
+            25 => Instruction::Or,
+            26 => Instruction::Not,
+            27 => Instruction::Xor,
+            28 => Instruction::Shl,
+            29 => Instruction::Shr,
+            30 => Instruction::BitAnd,
+            31 => Instruction::BitOr,
+            32 => Instruction::BitXor,
+            33 => Instruction::Neg,
+            34 => Instruction::JumpIfFalseOrPop,
+            35 => Instruction::JumpIfTrueOrPop,
+            36 => Instruction::Dup,
+            37 => Instruction::Swap,
+            38 => Instruction::Drop,
+            39 => Instruction::Over,
+            40 => Instruction::Rot,
+            41 => Instruction::Pick,
+            42 => Instruction::WrappingAdd,
+            43 => Instruction::WrappingSub,
+            44 => Instruction::WrappingMul,
+            45 => Instruction::SaturatingAdd,
+            46 => Instruction::SaturatingSub,
+            47 => Instruction::SaturatingMul,
+            48 => Instruction::LoadBig,
+            49 => Instruction::ToBig,
+            50 => Instruction::ToInt,
+            51 => Instruction::LoadDec,
+            52 => Instruction::ToDec,
+            53 => Instruction::DecToInt,
+            54 => Instruction::DecAdd,
+            55 => Instruction::DecSub,
+            56 => Instruction::DecMul,
+            57 => Instruction::DecDiv,
+            58 => Instruction::DecRescale,
+            59 => Instruction::DecCmp,
+            60 => Instruction::LoadStr,
+            61 => Instruction::DecToStr,
+            62 => Instruction::StrToDec,
+            63 => Instruction::CallNative,
+            64 => Instruction::CallNativeNamed,
+            65 => Instruction::Call,
+            66 => Instruction::Return,
+            67 => Instruction::LoadConst,
+            68 => Instruction::ReadVarConst,
+            69 => Instruction::WriteVarConst,
+            70 => Instruction::LoadLocal,
+            71 => Instruction::StoreLocal,
+            72 => Instruction::JumpWide,
+            73 => Instruction::JumpIfTrueWide,
+            74 => Instruction::JumpIfFalseWide,
+            75 => Instruction::JumpLong,
+            76 => Instruction::JumpIfTrueLong,
+            77 => Instruction::JumpIfFalseLong,
+            78 => Instruction::JumpAbs,
+            79 => Instruction::JumpIfTrueAbs,
+            80 => Instruction::JumpIfFalseAbs,
+            81 => Instruction::Block,
+            82 => Instruction::Loop,
+            83 => Instruction::If,
+            84 => Instruction::Else,
+            85 => Instruction::End,
+            86 => Instruction::Break,
+            87 => Instruction::Continue,
+            88 => Instruction::Switch,
+            89 => Instruction::Try,
+            90 => Instruction::EndTry,
+            91 => Instruction::Throw,
+            92 => Instruction::Closure,
+            93 => Instruction::CallValue,
+            94 => Instruction::TailCall,
+            95 => Instruction::Coroutine,
+            96 => Instruction::Yield,
+            97 => Instruction::Resume,
+            98 => Instruction::LoadGlobal,
+            99 => Instruction::StoreGlobal,
+            _ => return Err(VMError::InvalidInstruction(byte)),
+        })
     }
 }
 
-impl Into<u8> for Instruction {
-    fn into(self) -> u8 {
+impl Instruction {
+    /// Number of operand bytes that follow the instruction.
+    ///
+    /// `operands` are the bytes after the instruction, needed for instructions with variable length operands.
+    /// Returns `None` if the length of the operands can not be read.
+    pub fn operand_size(&self, operands: &[u8]) -> Option<usize> {
         match self {
+            Instruction::LoadVal => Some(8),
+            Instruction::WriteVar | Instruction::ReadVar => Some(4),
+            // start index, number of arguments and the arguments themselves
+            Instruction::FuncCall => operands.get(4).map(|&num_args| 5 + 8 * num_args as usize),
+            Instruction::Jump
+            | Instruction::JumpBack
+            | Instruction::JumpIfTrue
+            | Instruction::JumpIfFalse
+            | Instruction::JumpIfFalseOrPop
+            | Instruction::JumpIfTrueOrPop
+            | Instruction::Pick
+            | Instruction::DecToInt
+            | Instruction::Break
+            | Instruction::Continue => Some(1),
+            Instruction::CallNative
+            | Instruction::DecMul
+            | Instruction::DecDiv
+            | Instruction::DecRescale
+            | Instruction::LoadConst
+            | Instruction::ReadVarConst
+            | Instruction::WriteVarConst
+            | Instruction::LoadLocal
+            | Instruction::StoreLocal
+            | Instruction::LoadGlobal
+            | Instruction::StoreGlobal
+            | Instruction::JumpWide
+            | Instruction::JumpIfTrueWide
+            | Instruction::JumpIfFalseWide => Some(2),
+            Instruction::JumpLong
+            | Instruction::JumpIfTrueLong
+            | Instruction::JumpIfFalseLong
+            | Instruction::JumpAbs
+            | Instruction::JumpIfTrueAbs
+            | Instruction::JumpIfFalseAbs
+            | Instruction::Call
+            | Instruction::TailCall
+            | Instruction::Spawn
+            | Instruction::ReturnIndex
+            | Instruction::Try
+            | Instruction::Coroutine => Some(4),
+            Instruction::LoadDec => Some(9),
+            Instruction::Closure => operands.get(4).map(|&count| 5 + 2 * count as usize),
+            Instruction::Switch => operands.get(..2).map(|count| 6 + 4 * u16::from_le_bytes([count[0], count[1]]) as usize),
+            // length prefixed
+            Instruction::LoadBig | Instruction::LoadStr | Instruction::CallNativeNamed => {
+                operands.first().map(|&len| 1 + len as usize)
+            },
+            _ => Some(0),
+        }
+    }
+}
+
+impl From<Instruction> for u8 {
+    fn from(instruction: Instruction) -> u8 {
+        match instruction {
             Instruction::LoadVal => 0,
             Instruction::WriteVar => 1,
             Instruction::ReadVar => 2,
@@ -111,6 +449,82 @@ impl Into<u8> for Instruction {
             Instruction::Spawn => 21,
             Instruction::ReturnIndex => 22,
             Instruction::Finish => 23,
+            Instruction::And => 24,
+            Instruction::Or => 25,
+            Instruction::Not => 26,
+            Instruction::Xor => 27,
+            Instruction::Shl => 28,
+            Instruction::Shr => 29,
+            Instruction::BitAnd => 30,
+            Instruction::BitOr => 31,
+            Instruction::BitXor => 32,
+            Instruction::Neg => 33,
+            Instruction::JumpIfFalseOrPop => 34,
+            Instruction::JumpIfTrueOrPop => 35,
+            Instruction::Dup => 36,
+            Instruction::Swap => 37,
+            Instruction::Drop => 38,
+            Instruction::Over => 39,
+            Instruction::Rot => 40,
+            Instruction::Pick => 41,
+            Instruction::WrappingAdd => 42,
+            Instruction::WrappingSub => 43,
+            Instruction::WrappingMul => 44,
+            Instruction::SaturatingAdd => 45,
+            Instruction::SaturatingSub => 46,
+            Instruction::SaturatingMul => 47,
+            Instruction::LoadBig => 48,
+            Instruction::ToBig => 49,
+            Instruction::ToInt => 50,
+            Instruction::LoadDec => 51,
+            Instruction::ToDec => 52,
+            Instruction::DecToInt => 53,
+            Instruction::DecAdd => 54,
+            Instruction::DecSub => 55,
+            Instruction::DecMul => 56,
+            Instruction::DecDiv => 57,
+            Instruction::DecRescale => 58,
+            Instruction::DecCmp => 59,
+            Instruction::LoadStr => 60,
+            Instruction::DecToStr => 61,
+            Instruction::StrToDec => 62,
+            Instruction::CallNative => 63,
+            Instruction::CallNativeNamed => 64,
+            Instruction::Call => 65,
+            Instruction::Return => 66,
+            Instruction::LoadConst => 67,
+            Instruction::ReadVarConst => 68,
+            Instruction::WriteVarConst => 69,
+            Instruction::LoadLocal => 70,
+            Instruction::StoreLocal => 71,
+            Instruction::JumpWide => 72,
+            Instruction::JumpIfTrueWide => 73,
+            Instruction::JumpIfFalseWide => 74,
+            Instruction::JumpLong => 75,
+            Instruction::JumpIfTrueLong => 76,
+            Instruction::JumpIfFalseLong => 77,
+            Instruction::JumpAbs => 78,
+            Instruction::JumpIfTrueAbs => 79,
+            Instruction::JumpIfFalseAbs => 80,
+            Instruction::Block => 81,
+            Instruction::Loop => 82,
+            Instruction::If => 83,
+            Instruction::Else => 84,
+            Instruction::End => 85,
+            Instruction::Break => 86,
+            Instruction::Continue => 87,
+            Instruction::Switch => 88,
+            Instruction::Try => 89,
+            Instruction::EndTry => 90,
+            Instruction::Throw => 91,
+            Instruction::Closure => 92,
+            Instruction::CallValue => 93,
+            Instruction::TailCall => 94,
+            Instruction::Coroutine => 95,
+            Instruction::Yield => 96,
+            Instruction::Resume => 97,
+            Instruction::LoadGlobal => 98,
+            Instruction::StoreGlobal => 99,
         }
     }
 }
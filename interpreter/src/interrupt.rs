@@ -0,0 +1,107 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::Waker;
+use std::time::Instant;
+
+#[derive(Debug, Default)]
+struct Interrupt {
+    requested: AtomicBool,
+    /// Set once the host took a handle, clones for spawned threads do not count
+    shared: AtomicBool,
+    /// Task of an `Interpret` future waiting on a channel, woken so it sees the interrupt
+    waker: Mutex<Option<Waker>>,
+}
+
+/// Stops a machine from any thread, obtained with `Machine::interrupt_handle`.
+///
+/// The machine and the threads it spawns fail with `VMError::Interrupted` at their next backward jump, call
+/// or channel wait. The request stays until `clear`, so running again fails the same way.
+#[derive(Debug, Clone, Default)]
+pub struct InterruptHandle(Arc<Interrupt>);
+
+impl InterruptHandle {
+    /// Ask the machine to stop
+    pub fn interrupt(&self) {
+        // pairs with the load of a machine that was woken or checks on its own
+        self.0.requested.store(true, Ordering::Release);
+        if let Some(waker) = self.0.waker.lock().unwrap().take() {
+            waker.wake();
+        }
+    }
+
+    /// Withdraw the request, so the machine can run again
+    pub fn clear(&self) {
+        self.0.requested.store(false, Ordering::Release);
+    }
+
+    pub fn is_interrupted(&self) -> bool {
+        self.0.requested.load(Ordering::Acquire)
+    }
+
+    /// Handle for the host, the machine and its threads look at the interrupt from now on
+    pub(crate) fn share(&self) -> InterruptHandle {
+        self.0.shared.store(true, Ordering::Release);
+        self.clone()
+    }
+
+    /// Whether a handle was handed out, otherwise nothing can interrupt the machine
+    pub(crate) fn is_shared(&self) -> bool {
+        self.0.shared.load(Ordering::Acquire)
+    }
+
+    pub(crate) fn register(&self, waker: &Waker) {
+        *self.0.waker.lock().unwrap() = Some(waker.clone());
+    }
+}
+
+#[derive(Debug, Default)]
+struct Alarm {
+    /// Task to wake at the deadline
+    waker: Option<Waker>,
+    cancelled: bool,
+}
+
+/// Wakes the task of an `Interpret` future waiting on a channel once the deadline of the machine passed, so
+/// it fails with `VMError::Timeout` even if nothing is sent. A thread sleeps until the deadline, dropping the
+/// timer stops it.
+#[derive(Debug)]
+pub(crate) struct Timer {
+    alarm: Arc<(Mutex<Alarm>, Condvar)>,
+    deadline: Instant,
+}
+
+impl Timer {
+    pub(crate) fn start(deadline: Instant) -> Timer {
+        let alarm = Arc::new((Mutex::new(Alarm::default()), Condvar::new()));
+        let shared = alarm.clone();
+        std::thread::spawn(move || {
+            let (alarm, cancel) = &*shared;
+            let mut state = alarm.lock().unwrap();
+            while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+                if state.cancelled {
+                    return;
+                }
+                state = cancel.wait_timeout(state, left).unwrap().0;
+            }
+            if let Some(waker) = state.waker.take() {
+                waker.wake();
+            }
+        });
+        Timer { alarm, deadline }
+    }
+
+    /// Wake the task of `waker` at the deadline, right away if it passed before it was registered
+    pub(crate) fn register(&self, waker: &Waker) {
+        self.alarm.0.lock().unwrap().waker = Some(waker.clone());
+        if Instant::now() >= self.deadline {
+            waker.wake_by_ref();
+        }
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        self.alarm.0.lock().unwrap().cancelled = true;
+        self.alarm.1.notify_one();
+    }
+}
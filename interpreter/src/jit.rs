@@ -0,0 +1,910 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use cranelift::codegen::ir::{MemFlags, UserFuncName};
+use cranelift::prelude::*;
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, Linkage, Module};
+
+use crate::error::VMError;
+use crate::op::{fused_len, fused_peak, Comparison, Op};
+use crate::vm::MAX_STACK_SIZE;
+
+/// Variable a compiled function reads or writes
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Cell {
+    Var(Box<str>),
+    Local(u16),
+    Global(u16),
+}
+
+/// Code compiled from the instruction at some index until an instruction it can not run.
+///
+/// The native function takes a pointer to its state: the stack depth, the values of its cells, whether each
+/// cell is set, then the values on top of the stack it works on. It returns the instruction pointer it
+/// stopped at, the interpreter continues there. Instructions that would fail, `Return`, `Finish` and
+/// anything not compiled are left to the interpreter, so it fails or goes on exactly as if it ran everything.
+pub(crate) struct NativeFunction {
+    /// Number of values the function takes from the top of the stack
+    pub(crate) depth: usize,
+    /// Largest number of values on the stack, counted from the first one it takes
+    pub(crate) max_depth: usize,
+    /// Variables the function uses, in the order of the state
+    pub(crate) cells: Vec<Cell>,
+    code: extern "C" fn(*mut i64) -> i64,
+}
+
+impl NativeFunction {
+    /// Empty state for a call
+    pub(crate) fn state(&self) -> Vec<i64> {
+        vec![0; self.stack_base() + self.max_depth]
+    }
+
+    /// Index of the first stack value in the state
+    pub(crate) fn stack_base(&self) -> usize {
+        1 + 2 * self.cells.len()
+    }
+
+    /// Run the function on the state, the result is the instruction pointer after the last instruction it ran
+    pub(crate) fn call(&self, state: &mut [i64]) -> usize {
+        assert_eq!(state.len(), self.stack_base() + self.max_depth);
+        (self.code)(state.as_mut_ptr()) as usize
+    }
+}
+
+/// Native code of the integer-only parts of a program, keyed by the index of the instruction they start at
+pub(crate) struct Jit {
+    functions: HashMap<usize, NativeFunction>,
+    // owns the memory of the compiled code
+    _module: JITModule,
+}
+
+// The module is only used to compile, once finalized the code is immutable and can be called from any thread.
+unsafe impl Send for Jit {}
+unsafe impl Sync for Jit {}
+
+impl fmt::Debug for Jit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.functions.keys()).finish()
+    }
+}
+
+impl Jit {
+    /// Compile the code starting at every entry, given as an instruction index.
+    /// Entries starting with an instruction that can not be compiled are left to the interpreter.
+    pub(crate) fn compile(ops: &[Op], entries: &[usize]) -> Result<Jit, VMError> {
+        let error = |err: &dyn fmt::Display| VMError::JitError(err.to_string());
+        let mut flags = settings::builder();
+        flags.set("opt_level", "speed").map_err(|err| error(&err))?;
+        let isa = cranelift_native::builder()
+            .map_err(|err| error(&err))?
+            .finish(settings::Flags::new(flags))
+            .map_err(|err| error(&err))?;
+        let mut module = JITModule::new(JITBuilder::with_isa(isa, default_libcall_names()));
+
+        let mut compiled = Vec::new();
+        for &entry in entries {
+            if ops.get(entry).and_then(stack_effect).is_none() {
+                continue;
+            }
+            let Some(analysis) = analyze(ops, entry) else {
+                continue;
+            };
+            let mut context = module.make_context();
+            context.func.signature.params.push(AbiParam::new(types::I64));
+            context.func.signature.returns.push(AbiParam::new(types::I64));
+            context.func.name = UserFuncName::user(0, entry as u32);
+            let id = module
+                .declare_function(&format!("supert_{}", entry), Linkage::Local, &context.func.signature)
+                .map_err(|err| error(&err))?;
+
+            let mut builder_context = FunctionBuilderContext::new();
+            let builder = FunctionBuilder::new(&mut context.func, &mut builder_context);
+            Translator::new(builder, ops, &analysis).translate(entry);
+
+            module.define_function(id, &mut context).map_err(|err| error(&err))?;
+            module.clear_context(&mut context);
+            compiled.push((entry, analysis, id));
+        }
+        module.finalize_definitions().map_err(|err| error(&err))?;
+
+        let functions = compiled
+            .into_iter()
+            .map(|(entry, analysis, id)| {
+                // SAFETY: the function was defined with this signature and the module is kept alive with it
+                let code = unsafe { std::mem::transmute::<*const u8, extern "C" fn(*mut i64) -> i64>(module.get_finalized_function(id)) };
+                let function = NativeFunction { depth: analysis.depth, max_depth: analysis.max_depth, cells: analysis.cells, code };
+                (entry, function)
+            })
+            .collect();
+        Ok(Jit { functions, _module: module })
+    }
+
+    /// Function compiled for the instruction at `ip`
+    pub(crate) fn function(&self, ip: usize) -> Option<&NativeFunction> {
+        self.functions.get(&ip)
+    }
+
+    /// Number of compiled functions
+    pub(crate) fn len(&self) -> usize {
+        self.functions.len()
+    }
+}
+
+/// Stack depth at every reachable instruction and the variables the code uses
+struct Analysis {
+    /// Number of values taken from the stack at the entry, the most any path pops below its depth there
+    depth: usize,
+    /// `depths[ip]` is the stack depth before the instruction at `ip`, `None` if it is not reachable.
+    /// The last entry is the end of the program.
+    depths: Vec<Option<usize>>,
+    max_depth: usize,
+    cells: Vec<Cell>,
+}
+
+/// Number of values an op pops and pushes, `None` if it can not be compiled
+fn stack_effect(op: &Op) -> Option<(usize, usize)> {
+    Some(match op {
+        Op::LoadVal(_) | Op::ReadVar(_) | Op::LoadLocal(_) | Op::LoadGlobal(_) => (0, 1),
+        Op::WriteVar(_) | Op::StoreLocal(_) | Op::StoreGlobal(_) => (1, 0),
+        Op::Drop | Op::JumpIfTrue(_) | Op::JumpIfFalse(_) => (1, 0),
+        Op::CompareJumpIfFalse(..) => (1, 0),
+        Op::Add
+        | Op::Sub
+        | Op::Mul
+        | Op::Div
+        | Op::Mod
+        | Op::WrappingAdd
+        | Op::WrappingSub
+        | Op::WrappingMul
+        | Op::SaturatingAdd
+        | Op::SaturatingSub
+        | Op::SaturatingMul
+        | Op::NotEq
+        | Op::Eq
+        | Op::Gt
+        | Op::Lt
+        | Op::Gte
+        | Op::Lte
+        | Op::And
+        | Op::Or
+        | Op::Xor
+        | Op::Shl
+        | Op::Shr
+        | Op::BitAnd
+        | Op::BitOr
+        | Op::BitXor => (2, 1),
+        Op::Not | Op::Neg => (1, 1),
+        Op::Dup => (1, 2),
+        Op::Over => (2, 3),
+        Op::Swap => (2, 2),
+        Op::Rot => (3, 3),
+        Op::Pick(n) => (*n as usize + 1, *n as usize + 2),
+        // keep the condition when jumping, pop it otherwise
+        Op::JumpIfFalseOrPop(_) | Op::JumpIfTrueOrPop(_) => (1, 1),
+        Op::Jump(_) | Op::AddVar(..) | Op::AddLocal(..) | Op::AddGlobal(..) | Op::Nop => (0, 0),
+        _ => return None,
+    })
+}
+
+/// Indexes of the instructions that may run after the one at `ip`
+fn successors(op: &Op, ip: usize) -> Vec<usize> {
+    match op {
+        Op::Jump(target) => vec![*target],
+        Op::JumpIfTrue(target) | Op::JumpIfFalse(target) => vec![*target, ip + 1],
+        Op::CompareJumpIfFalse(_, _, target) => vec![*target as usize, ip + fused_len(op)],
+        Op::AddVar(..) | Op::AddLocal(..) | Op::AddGlobal(..) => vec![ip + fused_len(op)],
+        _ => vec![ip + 1],
+    }
+}
+
+fn cell(op: &Op) -> Option<Cell> {
+    match op {
+        Op::ReadVar(name) | Op::WriteVar(name) | Op::AddVar(name, _) => Some(Cell::Var(name.clone())),
+        Op::LoadLocal(slot) | Op::StoreLocal(slot) | Op::AddLocal(slot, _) => Some(Cell::Local(*slot)),
+        Op::LoadGlobal(slot) | Op::StoreGlobal(slot) | Op::AddGlobal(slot, _) => Some(Cell::Global(*slot)),
+        _ => None,
+    }
+}
+
+/// Instructions the machine may start native code at besides the program entry and the exports: the
+/// functions called, spawned or wrapped into closures and coroutines, and the targets of backward jumps,
+/// which start loops
+pub(crate) fn entries(ops: &[Op]) -> Vec<usize> {
+    let mut entries = Vec::new();
+    for (ip, op) in ops.iter().enumerate() {
+        let target = match op {
+            Op::Call(target) | Op::TailCall(target) | Op::Spawn(target) | Op::Coroutine(target) => *target,
+            Op::FuncCall(_, target) | Op::Closure(target, _) => *target as usize,
+            Op::Jump(target)
+            | Op::JumpIfTrue(target)
+            | Op::JumpIfFalse(target)
+            | Op::JumpIfFalseOrPop(target)
+            | Op::JumpIfTrueOrPop(target) if *target <= ip => *target,
+            Op::CompareJumpIfFalse(_, _, target) if *target as usize <= ip => *target as usize,
+            _ => continue,
+        };
+        if !entries.contains(&target) {
+            entries.push(target);
+        }
+    }
+    entries
+}
+
+/// Find the stack depth at every instruction reachable from `entry` until instructions that can not be
+/// compiled, counting from the lowest value any path pops.
+/// Returns `None` if the depth at an instruction depends on the path taken to it.
+fn analyze(ops: &[Op], entry: usize) -> Option<Analysis> {
+    // depths relative to the one at the entry, negative below it
+    let mut depths: Vec<Option<isize>> = vec![None; ops.len() + 1];
+    let (mut low, mut high) = (0, 0);
+    let mut cells = Vec::new();
+    let mut work = vec![(entry, 0)];
+    while let Some((ip, depth)) = work.pop() {
+        match depths.get(ip)? {
+            Some(known) if *known == depth => continue,
+            Some(_) => return None,
+            None => depths[ip] = Some(depth),
+        }
+        // the interpreter continues at the end of the program and at instructions that are not compiled
+        let Some((pops, pushes)) = ops.get(ip).and_then(stack_effect) else {
+            continue;
+        };
+        let op = &ops[ip];
+        if let Some(cell) = cell(op) {
+            if !cells.contains(&cell) {
+                cells.push(cell);
+            }
+        }
+        let after = depth - pops as isize + pushes as isize;
+        low = low.min(depth - pops as isize);
+        // the machine checks that native code stays below the limit, like the unfused ops would
+        high = high.max(after).max(depth + fused_peak(op) as isize);
+        if (high - low) as usize >= MAX_STACK_SIZE {
+            return None;
+        }
+        match op {
+            Op::JumpIfFalseOrPop(target) | Op::JumpIfTrueOrPop(target) => {
+                work.push((*target, depth));
+                work.push((ip + 1, depth - 1));
+            },
+            _ => work.extend(successors(op, ip).into_iter().map(|next| (next, after))),
+        }
+    }
+    let depths = depths.into_iter().map(|depth| depth.map(|depth| (depth - low) as usize)).collect();
+    Some(Analysis { depth: -low as usize, depths, max_depth: (high - low) as usize, cells })
+}
+
+/// Translates the reachable instructions to Cranelift IR, every stack slot and cell is a variable
+struct Translator<'a> {
+    builder: FunctionBuilder<'a>,
+    ops: &'a [Op],
+    analysis: &'a Analysis,
+    blocks: Vec<Option<Block>>,
+    state: Value,
+}
+
+impl<'a> Translator<'a> {
+    fn new(mut builder: FunctionBuilder<'a>, ops: &'a [Op], analysis: &'a Analysis) -> Translator<'a> {
+        let entry = builder.create_block();
+        builder.append_block_params_for_function_params(entry);
+        builder.switch_to_block(entry);
+        let state = builder.block_params(entry)[0];
+        let blocks = analysis.depths.iter().map(|depth| depth.map(|_| builder.create_block())).collect();
+        Translator { builder, ops, analysis, blocks, state }
+    }
+
+    fn stack(&self, index: usize) -> Variable {
+        Variable::new(index)
+    }
+
+    fn value(&self, cell: usize) -> Variable {
+        Variable::new(self.analysis.max_depth + 2 * cell)
+    }
+
+    fn is_set(&self, cell: usize) -> Variable {
+        Variable::new(self.analysis.max_depth + 2 * cell + 1)
+    }
+
+    fn cell_index(&self, op: &Op) -> usize {
+        let cell = cell(op).unwrap();
+        self.analysis.cells.iter().position(|known| *known == cell).unwrap()
+    }
+
+    /// Offset in bytes of the `index`-th `i64` of the state
+    fn offset(index: usize) -> i32 {
+        (index * 8) as i32
+    }
+
+    fn load(&mut self, index: usize) -> Value {
+        self.builder.ins().load(types::I64, MemFlags::trusted(), self.state, Self::offset(index))
+    }
+
+    fn store(&mut self, index: usize, value: Value) {
+        self.builder.ins().store(MemFlags::trusted(), value, self.state, Self::offset(index));
+    }
+
+    fn get(&mut self, var: Variable) -> Value {
+        self.builder.use_var(var)
+    }
+
+    fn set(&mut self, var: Variable, value: Value) {
+        self.builder.def_var(var, value);
+    }
+
+    fn translate(mut self, entry: usize) {
+        for index in 0..self.analysis.max_depth {
+            let value = if index < self.analysis.depth {
+                self.load(1 + 2 * self.analysis.cells.len() + index)
+            } else {
+                self.builder.ins().iconst(types::I64, 0)
+            };
+            self.builder.declare_var(self.stack(index), types::I64);
+            self.set(self.stack(index), value);
+        }
+        for cell in 0..self.analysis.cells.len() {
+            let (value, is_set) = (self.load(1 + cell), self.load(1 + self.analysis.cells.len() + cell));
+            self.builder.declare_var(self.value(cell), types::I64);
+            self.builder.declare_var(self.is_set(cell), types::I64);
+            self.set(self.value(cell), value);
+            self.set(self.is_set(cell), is_set);
+        }
+        self.jump(entry);
+
+        for ip in 0..self.blocks.len() {
+            let (Some(block), Some(depth)) = (self.blocks[ip], self.analysis.depths[ip]) else {
+                continue;
+            };
+            self.builder.switch_to_block(block);
+            match self.ops.get(ip) {
+                Some(op) if stack_effect(op).is_some() => self.translate_op(op, ip, depth),
+                _ => self.exit(depth, ip),
+            }
+        }
+        self.builder.seal_all_blocks();
+        self.builder.finalize();
+    }
+
+    fn jump(&mut self, ip: usize) {
+        let block = self.blocks[ip].unwrap();
+        self.builder.ins().jump(block, &[]);
+    }
+
+    fn branch(&mut self, condition: Value, then_ip: usize, else_ip: usize) {
+        let (then_block, else_block) = (self.blocks[then_ip].unwrap(), self.blocks[else_ip].unwrap());
+        self.builder.ins().brif(condition, then_block, &[], else_block, &[]);
+    }
+
+    /// Write the stack and the cells to the state and stop before the instruction at `ip`
+    fn exit(&mut self, depth: usize, ip: usize) {
+        let cells = self.analysis.cells.len();
+        for cell in 0..cells {
+            let (value, is_set) = (self.get(self.value(cell)), self.get(self.is_set(cell)));
+            self.store(1 + cell, value);
+            self.store(1 + cells + cell, is_set);
+        }
+        let base = 1 + 2 * cells;
+        for index in 0..depth {
+            let value = self.get(self.stack(index));
+            self.store(base + index, value);
+        }
+        let depth = self.builder.ins().iconst(types::I64, depth as i64);
+        self.store(0, depth);
+        let ip = self.builder.ins().iconst(types::I64, ip as i64);
+        self.builder.ins().return_(&[ip]);
+    }
+
+    /// Stop before the instruction at `ip` if `condition` is true, so the interpreter runs it and fails,
+    /// otherwise continue in a new block
+    fn exit_if(&mut self, condition: Value, depth: usize, ip: usize) {
+        let (fail, next) = (self.builder.create_block(), self.builder.create_block());
+        self.builder.ins().brif(condition, fail, &[], next, &[]);
+        self.builder.switch_to_block(fail);
+        self.exit(depth, ip);
+        self.builder.switch_to_block(next);
+    }
+
+    /// Value of a cell, stopping before the instruction at `ip` if it was never set, reading an unknown
+    /// variable fails
+    fn read_cell(&mut self, cell: usize, depth: usize, ip: usize) -> Value {
+        let is_set = self.get(self.is_set(cell));
+        let unset = self.builder.ins().icmp_imm(IntCC::Equal, is_set, 0);
+        self.exit_if(unset, depth, ip);
+        self.get(self.value(cell))
+    }
+
+    fn write_cell(&mut self, cell: usize, value: Value) {
+        let one = self.builder.ins().iconst(types::I64, 1);
+        self.set(self.value(cell), value);
+        self.set(self.is_set(cell), one);
+    }
+
+    /// `n`-th value from the top of the stack, zero if the stack is not that deep
+    fn peek(&mut self, depth: usize, n: usize) -> Value {
+        match depth.checked_sub(n + 1) {
+            Some(index) => self.get(self.stack(index)),
+            None => self.builder.ins().iconst(types::I64, 0),
+        }
+    }
+
+    fn bool_to_int(&mut self, value: Value) -> Value {
+        self.builder.ins().uextend(types::I64, value)
+    }
+
+    fn translate_op(&mut self, op: &Op, ip: usize, depth: usize) {
+        // top and second value of the stack, `a` and `b` in the interpreter, only used by ops that pop them
+        let a = self.peek(depth, 0);
+        let b = self.peek(depth, 1);
+
+        let result = match op {
+            Op::LoadVal(val) => Some(self.builder.ins().iconst(types::I64, *val)),
+            Op::ReadVar(_) | Op::LoadLocal(_) | Op::LoadGlobal(_) => {
+                let cell = self.cell_index(op);
+                Some(self.read_cell(cell, depth, ip))
+            },
+            Op::WriteVar(_) | Op::StoreLocal(_) | Op::StoreGlobal(_) => {
+                let cell = self.cell_index(op);
+                self.write_cell(cell, a);
+                None
+            },
+            Op::AddVar(_, n) | Op::AddLocal(_, n) | Op::AddGlobal(_, n) => {
+                let cell = self.cell_index(op);
+                let value = self.read_cell(cell, depth, ip);
+                let n = self.builder.ins().iconst(types::I64, *n as i64);
+                let (sum, overflow) = self.builder.ins().sadd_overflow(value, n);
+                self.exit_if(overflow, depth, ip);
+                self.write_cell(cell, sum);
+                None
+            },
+            Op::Add | Op::Sub | Op::Mul => {
+                let (val, overflow) = match op {
+                    Op::Add => self.builder.ins().sadd_overflow(b, a),
+                    Op::Sub => self.builder.ins().ssub_overflow(b, a),
+                    _ => self.builder.ins().smul_overflow(b, a),
+                };
+                self.exit_if(overflow, depth, ip);
+                Some(val)
+            },
+            Op::Div => {
+                // the interpreter divides the top value by the second one
+                let zero = self.builder.ins().icmp_imm(IntCC::Equal, b, 0);
+                self.exit_if(zero, depth, ip);
+                let is_min = self.builder.ins().icmp_imm(IntCC::Equal, a, i64::MIN);
+                let minus_one = self.builder.ins().icmp_imm(IntCC::Equal, b, -1);
+                let overflow = self.builder.ins().band(is_min, minus_one);
+                self.exit_if(overflow, depth, ip);
+                Some(self.builder.ins().sdiv(a, b))
+            },
+            Op::Mod => {
+                let zero = self.builder.ins().icmp_imm(IntCC::Equal, a, 0);
+                self.exit_if(zero, depth, ip);
+                let is_min = self.builder.ins().icmp_imm(IntCC::Equal, b, i64::MIN);
+                let minus_one = self.builder.ins().icmp_imm(IntCC::Equal, a, -1);
+                let overflow = self.builder.ins().band(is_min, minus_one);
+                self.exit_if(overflow, depth, ip);
+                Some(self.builder.ins().srem(b, a))
+            },
+            Op::WrappingAdd => Some(self.builder.ins().iadd(b, a)),
+            Op::WrappingSub => Some(self.builder.ins().isub(b, a)),
+            Op::WrappingMul => Some(self.builder.ins().imul(b, a)),
+            Op::SaturatingAdd | Op::SaturatingSub | Op::SaturatingMul => {
+                let max = self.builder.ins().iconst(types::I64, i64::MAX);
+                let min = self.builder.ins().iconst(types::I64, i64::MIN);
+                let (val, overflow, negative) = match op {
+                    // too large if `a` is positive, too small otherwise
+                    Op::SaturatingAdd => {
+                        let (val, overflow) = self.builder.ins().sadd_overflow(b, a);
+                        (val, overflow, self.builder.ins().icmp_imm(IntCC::SignedLessThan, a, 0))
+                    },
+                    Op::SaturatingSub => {
+                        let (val, overflow) = self.builder.ins().ssub_overflow(b, a);
+                        (val, overflow, self.builder.ins().icmp_imm(IntCC::SignedGreaterThan, a, 0))
+                    },
+                    _ => {
+                        let (val, overflow) = self.builder.ins().smul_overflow(b, a);
+                        let sign = self.builder.ins().bxor(a, b);
+                        (val, overflow, self.builder.ins().icmp_imm(IntCC::SignedLessThan, sign, 0))
+                    },
+                };
+                let saturated = self.builder.ins().select(negative, min, max);
+                Some(self.builder.ins().select(overflow, saturated, val))
+            },
+            Op::Eq | Op::NotEq | Op::Gt | Op::Lt | Op::Gte | Op::Lte => {
+                let condition = self.builder.ins().icmp(int_cc(op), b, a);
+                Some(self.bool_to_int(condition))
+            },
+            Op::And | Op::Or | Op::Xor => {
+                let (b, a) = (self.builder.ins().icmp_imm(IntCC::NotEqual, b, 0), self.builder.ins().icmp_imm(IntCC::NotEqual, a, 0));
+                let condition = match op {
+                    Op::And => self.builder.ins().band(b, a),
+                    Op::Or => self.builder.ins().bor(b, a),
+                    _ => self.builder.ins().bxor(b, a),
+                };
+                Some(self.bool_to_int(condition))
+            },
+            Op::Not => {
+                let condition = self.builder.ins().icmp_imm(IntCC::Equal, a, 0);
+                Some(self.bool_to_int(condition))
+            },
+            Op::Shl | Op::Shr => {
+                // amounts outside of `0..64` shift every bit out
+                let in_range = self.builder.ins().icmp_imm(IntCC::UnsignedLessThan, a, 64);
+                let (shifted, out_of_range) = match op {
+                    Op::Shl => (self.builder.ins().ishl(b, a), self.builder.ins().iconst(types::I64, 0)),
+                    _ => (self.builder.ins().sshr(b, a), self.builder.ins().sshr_imm(b, 63)),
+                };
+                Some(self.builder.ins().select(in_range, shifted, out_of_range))
+            },
+            Op::BitAnd => Some(self.builder.ins().band(b, a)),
+            Op::BitOr => Some(self.builder.ins().bor(b, a)),
+            Op::BitXor => Some(self.builder.ins().bxor(b, a)),
+            Op::Neg => {
+                let is_min = self.builder.ins().icmp_imm(IntCC::Equal, a, i64::MIN);
+                self.exit_if(is_min, depth, ip);
+                Some(self.builder.ins().ineg(a))
+            },
+            Op::Dup => Some(a),
+            Op::Over => Some(b),
+            Op::Pick(n) => Some(self.peek(depth, *n as usize)),
+            Op::Swap => {
+                self.set(self.stack(depth - 1), b);
+                self.set(self.stack(depth - 2), a);
+                None
+            },
+            Op::Rot => {
+                let c = self.peek(depth, 2);
+                self.set(self.stack(depth - 3), b);
+                self.set(self.stack(depth - 2), a);
+                self.set(self.stack(depth - 1), c);
+                None
+            },
+            Op::Drop | Op::Jump(_) | Op::Nop => None,
+            Op::JumpIfTrue(_) | Op::JumpIfFalse(_) | Op::JumpIfFalseOrPop(_) | Op::JumpIfTrueOrPop(_) => None,
+            Op::CompareJumpIfFalse(..) => None,
+            _ => unreachable!("{:?} is not compiled", op),
+        };
+
+        let (pops, pushes) = stack_effect(op).unwrap();
+        if let Some(result) = result {
+            let index = depth - pops + pushes - 1;
+            self.set(self.stack(index), result);
+        }
+
+        match op {
+            Op::Jump(target) => self.jump(*target),
+            Op::JumpIfTrue(target) | Op::JumpIfTrueOrPop(target) => self.branch(a, *target, ip + 1),
+            Op::JumpIfFalse(target) | Op::JumpIfFalseOrPop(target) => self.branch(a, ip + 1, *target),
+            Op::CompareJumpIfFalse(comparison, n, target) => {
+                let condition = self.builder.ins().icmp_imm(comparison_cc(*comparison), a, *n);
+                self.branch(condition, ip + fused_len(op), *target as usize);
+            },
+            _ => self.jump(ip + fused_len(op)),
+        }
+    }
+}
+
+fn int_cc(op: &Op) -> IntCC {
+    match op {
+        Op::Eq => IntCC::Equal,
+        Op::NotEq => IntCC::NotEqual,
+        Op::Gt => IntCC::SignedGreaterThan,
+        Op::Lt => IntCC::SignedLessThan,
+        Op::Gte => IntCC::SignedGreaterThanOrEqual,
+        _ => IntCC::SignedLessThanOrEqual,
+    }
+}
+
+fn comparison_cc(comparison: Comparison) -> IntCC {
+    match comparison {
+        Comparison::Eq => IntCC::Equal,
+        Comparison::NotEq => IntCC::NotEqual,
+        Comparison::Gt => IntCC::SignedGreaterThan,
+        Comparison::Lt => IntCC::SignedLessThan,
+        Comparison::Gte => IntCC::SignedGreaterThanOrEqual,
+        Comparison::Lte => IntCC::SignedLessThanOrEqual,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::instruction::Instruction;
+    use crate::program::Program;
+    use crate::stack::StackValue;
+    use crate::vm::Machine;
+
+    /// Interpret the code and run it compiled, checking both give the same result, instruction pointer,
+    /// stack and variables, also when they fail. Also returns how many functions were compiled.
+    fn run_both(code: Vec<u8>) -> (Result<i64, VMError>, usize) {
+        run_both_with(code, |_| {})
+    }
+
+    /// `run_both` on machines prepared by `setup`
+    fn run_both_with(code: Vec<u8>, setup: impl Fn(&mut Machine)) -> (Result<i64, VMError>, usize) {
+        let mut interpreter = Machine::new(Arc::new(Program::new(code.clone()).unwrap()));
+        setup(&mut interpreter);
+        let interpreted = interpreter.interpret();
+
+        let mut program = Program::new(code).unwrap();
+        let compiled = program.compile_jit().unwrap();
+        let mut native = Machine::new(Arc::new(program));
+        setup(&mut native);
+        let result = native.interpret();
+
+        assert_eq!(result, interpreted);
+        assert_eq!(native.ip, interpreter.ip);
+        assert_eq!(format!("{:?}", native.stack), format!("{:?}", interpreter.stack));
+        assert_eq!(native.variables.len(), interpreter.variables.len());
+        for (name, value) in &interpreter.variables {
+            assert_eq!(format!("{:?}", native.variables.get(name)), format!("{:?}", Some(value)));
+        }
+        (result, compiled)
+    }
+
+    fn load(val: i64) -> Vec<u8> {
+        [vec![Instruction::LoadVal.into()], val.to_le_bytes().to_vec()].concat()
+    }
+
+    fn var(instruction: Instruction, name: &[u8; 4]) -> Vec<u8> {
+        [vec![instruction.into()], name.to_vec()].concat()
+    }
+
+    /// let sum = 0
+    /// for i in 1..=n:
+    ///    sum += i * i
+    /// sum
+    fn sum_of_squares(n: i64) -> Vec<u8> {
+        [
+            load(0),
+            var(Instruction::WriteVar, b"sum\0"),
+            load(1),
+            var(Instruction::WriteVar, b"i\0\0\0"),
+            var(Instruction::ReadVar, b"i\0\0\0"),
+            load(n),
+            vec![Instruction::Lte.into(), Instruction::JumpIfFalse.into(), 44],
+            var(Instruction::ReadVar, b"i\0\0\0"),
+            var(Instruction::ReadVar, b"i\0\0\0"),
+            vec![Instruction::Mul.into()],
+            var(Instruction::ReadVar, b"sum\0"),
+            vec![Instruction::Add.into()],
+            var(Instruction::WriteVar, b"sum\0"),
+            var(Instruction::ReadVar, b"i\0\0\0"),
+            load(1),
+            vec![Instruction::Add.into()],
+            var(Instruction::WriteVar, b"i\0\0\0"),
+            vec![Instruction::JumpBack.into(), 61],
+            var(Instruction::ReadVar, b"sum\0"),
+            vec![Instruction::Finish.into()],
+        ].concat()
+    }
+
+    /// Sum the squares from `n` received on the channel on the stack down to 1, sending every partial sum on
+    /// the channel. The loop runs native code up to `SendChannel` in every iteration.
+    fn sum_of_squares_sent() -> Vec<u8> {
+        let body = [
+            var(Instruction::ReadVar, b"sum\0"),
+            var(Instruction::ReadVar, b"n\0\0\0"),
+            var(Instruction::ReadVar, b"n\0\0\0"),
+            vec![Instruction::Mul.into(), Instruction::Add.into()],
+            var(Instruction::WriteVar, b"sum\0"),
+            var(Instruction::ReadVar, b"sum\0"),
+            vec![Instruction::SendChannel.into()],
+            var(Instruction::ReadVar, b"n\0\0\0"),
+            load(1),
+            vec![Instruction::Sub.into()],
+            var(Instruction::WriteVar, b"n\0\0\0"),
+        ].concat();
+        let condition = [var(Instruction::ReadVar, b"n\0\0\0"), vec![Instruction::JumpIfFalse.into(), body.len() as u8 + 2]].concat();
+        let back = (condition.len() + body.len()) as u8 + 2;
+        [
+            vec![Instruction::RecvChannel.into()],
+            var(Instruction::WriteVar, b"n\0\0\0"),
+            load(0),
+            var(Instruction::WriteVar, b"sum\0"),
+            condition,
+            body,
+            vec![Instruction::JumpBack.into(), back],
+            var(Instruction::ReadVar, b"sum\0"),
+            vec![Instruction::Finish.into()],
+        ].concat()
+    }
+
+    #[test]
+    fn test_jit_loop() {
+        // the program entry and the loop
+        assert_eq!(run_both(sum_of_squares(10)), (Ok(385), 2));
+        assert_eq!(run_both(sum_of_squares(1000)), (Ok(333_833_500), 2));
+
+        // unfused and with resolved variables
+        for resolve in [false, true] {
+            let mut program = Program::new(sum_of_squares(1000)).unwrap();
+            program.set_fusion(false).unwrap();
+            if resolve {
+                program.resolve_locals().unwrap();
+            }
+            assert_eq!(program.compile_jit().unwrap(), 2);
+            assert!(program.native(0).is_some());
+            let mut vm = Machine::new(Arc::new(program));
+            assert_eq!(vm.interpret().unwrap(), 333_833_500);
+            if resolve {
+                assert!(matches!(vm.local("i"), Some(StackValue::Int(1001))));
+            } else {
+                assert!(matches!(vm.variables.get("i"), Some(StackValue::Int(1001))));
+            }
+        }
+    }
+
+    #[test]
+    fn test_jit_errors() {
+        let finish = || vec![Instruction::Finish.into()];
+        let binary = |b: i64, a: i64, instruction: Instruction| [load(b), load(a), vec![instruction.into()], finish()].concat();
+
+        assert_eq!(run_both(binary(i64::MAX, 1, Instruction::Add)), (Err(VMError::ArithmeticOverflow), 1));
+        assert_eq!(run_both(binary(i64::MIN, 1, Instruction::Sub)).0, Err(VMError::ArithmeticOverflow));
+        assert_eq!(run_both(binary(0, 7, Instruction::Div)).0, Err(VMError::DivisionByZero));
+        assert_eq!(run_both(binary(-1, i64::MIN, Instruction::Div)).0, Err(VMError::ArithmeticOverflow));
+        assert_eq!(run_both(binary(7, 0, Instruction::Mod)).0, Err(VMError::DivisionByZero));
+        assert_eq!(run_both(binary(i64::MIN, -1, Instruction::Mod)).0, Err(VMError::ArithmeticOverflow));
+        assert_eq!(run_both([load(i64::MIN), vec![Instruction::Neg.into()], finish()].concat()).0, Err(VMError::ArithmeticOverflow));
+        // reading a variable that was never written
+        assert_eq!(run_both([var(Instruction::ReadVar, b"x\0\0\0"), finish()].concat()).0, Err(VMError::StackUnderflow));
+        assert_eq!(run_both([load(1), vec![Instruction::Add.into()], finish()].concat()).0, Err(VMError::StackUnderflow));
+        // nothing to compile, `Finish` is left to the interpreter
+        assert_eq!(run_both(finish()), (Err(VMError::StackUnderflow), 0));
+
+        assert_eq!(run_both(binary(i64::MAX, 2, Instruction::SaturatingMul)).0, Ok(i64::MAX));
+        assert_eq!(run_both(binary(i64::MIN, 2, Instruction::SaturatingMul)).0, Ok(i64::MIN));
+        assert_eq!(run_both(binary(i64::MIN, 1, Instruction::SaturatingSub)).0, Ok(i64::MIN));
+        assert_eq!(run_both(binary(i64::MAX, 1, Instruction::WrappingAdd)).0, Ok(i64::MIN));
+        assert_eq!(run_both(binary(3, 64, Instruction::Shl)).0, Ok(0));
+        assert_eq!(run_both(binary(-48, -1, Instruction::Shr)).0, Ok(-1));
+    }
+
+    #[test]
+    fn test_jit_export() {
+        // fn square(x) { x * x }
+        // fn sum_of_squares(x, y) { square(x) + square(y) }
+        let mut program = Program::new(vec![
+            Instruction::Dup.into(),
+            Instruction::Mul.into(),
+            Instruction::Return.into(),
+            Instruction::Call.into(), 0x00, 0x00, 0x00, 0x00,
+            Instruction::Swap.into(),
+            Instruction::Call.into(), 0x00, 0x00, 0x00, 0x00,
+            Instruction::Add.into(),
+            Instruction::Return.into(),
+        ]).unwrap();
+        program.export("square", 0, 1, 1).unwrap();
+        program.export("sum_of_squares", 3, 2, 1).unwrap();
+        // `square`, which is also the program entry, `sum_of_squares` starts with `Call` which is left to the
+        // interpreter
+        assert_eq!(program.compile_jit().unwrap(), 1);
+        assert!(program.native(0).is_some() && program.native(3).is_none());
+
+        let mut vm = Machine::new(Arc::new(program));
+        assert!(matches!(vm.call("square", &[StackValue::Int(9)]).unwrap()[..], [StackValue::Int(81)]));
+        assert_eq!(vm.call("square", &[StackValue::Int(i64::MAX)]).unwrap_err(), VMError::ArithmeticOverflow);
+        let results = vm.call("sum_of_squares", &[StackValue::Int(3), StackValue::Int(4)]).unwrap();
+        assert!(matches!(results[..], [StackValue::Int(25)]));
+        // big integers are interpreted
+        let results = vm.call("square", &[StackValue::BigInt(3.into())]).unwrap();
+        assert!(matches!(&results[..], [StackValue::BigInt(val)] if *val == 9.into()));
+
+        // changing the instructions drops the native code
+        let mut program = Arc::try_unwrap(vm.program).unwrap();
+        program.set_fusion(false).unwrap();
+        assert!(program.native(0).is_none());
+    }
+
+    #[test]
+    fn test_jit_fallback() {
+        let code = vec![
+            Instruction::LoadVal.into(), 0x01, 0, 0, 0, 0, 0, 0, 0,
+            Instruction::SendChannel.into(),
+            Instruction::RecvChannel.into(),
+            Instruction::Finish.into(),
+        ];
+        // `LoadVal` runs natively, the interpreter sends and receives
+        let mut program = Program::new(code).unwrap();
+        assert_eq!(program.compile_jit().unwrap(), 1);
+
+        let (sender, receiver) = crate::channel::channel();
+        let mut vm = Machine::new(Arc::new(program));
+        vm.stack.push(StackValue::Channel(sender, receiver));
+        assert_eq!(vm.interpret().unwrap(), 1);
+
+        // a variable holding something other than a primitive integer runs in the interpreter
+        let mut program = Program::new([var(Instruction::ReadVar, b"x\0\0\0"), vec![Instruction::Finish.into()]].concat()).unwrap();
+        assert_eq!(program.compile_jit().unwrap(), 1);
+        let mut vm = Machine::new(Arc::new(program));
+        vm.variables.insert("x".to_string(), StackValue::Str("x".to_string()));
+        assert_eq!(vm.interpret().unwrap_err(), VMError::TypeMismatch);
+        assert_eq!(vm.ip, 2);
+    }
+
+    #[test]
+    fn test_jit_partial() {
+        let channel = |n: i64| {
+            move |vm: &mut Machine| {
+                let (sender, receiver) = crate::channel::channel();
+                sender.send(n).unwrap();
+                vm.stack.push(StackValue::Channel(sender, receiver));
+            }
+        };
+        // only the loop is compiled, the program starts with `RecvChannel`
+        let mut program = Program::new(sum_of_squares_sent()).unwrap();
+        assert_eq!(program.compile_jit().unwrap(), 1);
+        assert!(program.native(0).is_none() && program.native(4).is_some());
+
+        assert_eq!(run_both_with(sum_of_squares_sent(), channel(10)), (Ok(385), 1));
+        assert_eq!(run_both_with(sum_of_squares_sent(), channel(1000)), (Ok(333_833_500), 1));
+        // the sum overflows in the second iteration, both stop at the same `Add` with the same state
+        assert_eq!(run_both_with(sum_of_squares_sent(), channel(3_037_000_499)), (Err(VMError::ArithmeticOverflow), 1));
+        // the first `Mul` overflows
+        assert_eq!(run_both_with(sum_of_squares_sent(), channel(i64::MAX)), (Err(VMError::ArithmeticOverflow), 1));
+    }
+
+    /// Random straight-line programs give the same result compiled and interpreted
+    #[test]
+    fn test_jit_random() {
+        let instructions = [
+            Instruction::Add,
+            Instruction::Sub,
+            Instruction::Mul,
+            Instruction::Div,
+            Instruction::Mod,
+            Instruction::WrappingAdd,
+            Instruction::WrappingSub,
+            Instruction::WrappingMul,
+            Instruction::SaturatingAdd,
+            Instruction::SaturatingSub,
+            Instruction::SaturatingMul,
+            Instruction::NotEq,
+            Instruction::Eq,
+            Instruction::Gt,
+            Instruction::Lt,
+            Instruction::Gte,
+            Instruction::Lte,
+            Instruction::And,
+            Instruction::Or,
+            Instruction::Not,
+            Instruction::Xor,
+            Instruction::Shl,
+            Instruction::Shr,
+            Instruction::BitAnd,
+            Instruction::BitOr,
+            Instruction::BitXor,
+            Instruction::Neg,
+            Instruction::Dup,
+            Instruction::Swap,
+            Instruction::Drop,
+            Instruction::Over,
+            Instruction::Rot,
+        ];
+        let values = [0, 1, -1, 2, 3, 7, 63, 64, -100, i64::MAX, i64::MIN, 1 << 40];
+
+        let mut seed: u64 = 0x5eed;
+        let mut next = |bound: usize| {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (seed >> 33) as usize % bound
+        };
+        let mut compiled = 0;
+        for _ in 0..200 {
+            let mut code = Vec::new();
+            for _ in 0..next(24) + 1 {
+                match next(8) {
+                    0..=2 => code.extend(load(values[next(values.len())])),
+                    3 => code.extend(var(Instruction::WriteVar, [b"x\0\0\0", b"y\0\0\0"][next(2)])),
+                    4 => code.extend(var(Instruction::ReadVar, [b"x\0\0\0", b"y\0\0\0"][next(2)])),
+                    5 => code.extend([Instruction::Pick.into(), next(3) as u8]),
+                    _ => code.push(instructions[next(instructions.len())].into()),
+                }
+            }
+            code.push(Instruction::Finish.into());
+            compiled += run_both(code).1;
+        }
+        assert_eq!(compiled, 200);
+    }
+}
@@ -5,12 +5,34 @@ mod vm;
 mod instruction;
 mod error;
 mod stack;
+mod decimal;
+mod host;
+mod capability;
+mod program;
+mod module;
+mod op;
+mod assembler;
+mod channel;
+mod coroutine;
+mod interrupt;
+#[cfg(feature = "jit")]
+mod jit;
 
-use instruction::Instruction;
-use vm::Bytecode;
+pub use assembler::{Assembler, Label};
+pub use capability::Capabilities;
+pub use channel::{channel, Receiver, Recv, Sender};
+pub use coroutine::Coroutine;
+pub use error::{Payload, VMError};
+pub use host::{HostFn, HostFunction, HostFunctions};
+pub use instruction::Instruction;
+pub use interrupt::InterruptHandle;
+pub use stack::{Function, StackValue};
+pub use module::{CustomSection, LineEntry, Module, SectionId};
+pub use program::{Constant, Export, Program};
+pub use vm::{Frame, Handler, Interpret, Machine, Status};
 
 pub fn main() {
-    let mut vm = Bytecode::new(vec![
+    let program = Program::new(vec![
         Instruction::LoadVal.into(), 0x01, 0, 0, 0, 0, 0, 0, 0,
         Instruction::WriteVar.into(), 0x78, 0x00, 0x00, 0x00,
         Instruction::LoadVal.into(), 0x02, 0, 0, 0, 0, 0, 0, 0,
@@ -21,7 +43,8 @@ pub fn main() {
         Instruction::ReadVar.into(), 0x79, 0x00, 0x00, 0x00,
         Instruction::Mul.into(),
         Instruction::Finish.into(),
-    ]);
+    ]).unwrap();
+    let mut vm = Machine::new(std::sync::Arc::new(program));
 
     println!("{:?}", vm.interpret().unwrap());
 }
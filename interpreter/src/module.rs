@@ -0,0 +1,313 @@
+use std::collections::HashMap;
+
+use num_bigint::BigInt;
+
+use crate::error::VMError;
+use crate::program::{Constant, Export};
+
+/// First bytes of every supert module
+pub const MAGIC: [u8; 4] = *b"\0spt";
+
+/// Version of the module format written by `Module::write`.
+///
+/// Version 2 encodes the addresses of `Call`, `TailCall`, `Spawn`, `FuncCall` and `ReturnIndex` as little
+/// endian `u32` like every other address and the id of `CallNative` as little endian `u16`, version 1 code
+/// used big endian `u16` for both and is not read anymore.
+pub const VERSION: u16 = 2;
+
+/// Size of the header: magic, version and number of sections
+const HEADER_SIZE: usize = 8;
+
+/// Size of a section table entry: id, offset and length
+const SECTION_ENTRY_SIZE: usize = 9;
+
+/// Kinds of sections a module can contain
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SectionId {
+    /// Instructions bytecode
+    Code,
+    /// Constants referenced by the instructions
+    Constants,
+    /// Functions exported to the host
+    Exports,
+    /// Mapping from instruction indexes to source lines
+    DebugInfo,
+    /// Named section the interpreter does not look into
+    Custom,
+}
+
+impl TryFrom<u8> for SectionId {
+    type Error = VMError;
+
+    fn try_from(byte: u8) -> Result<Self, Self::Error> {
+        match byte {
+            0 => Ok(SectionId::Code),
+            1 => Ok(SectionId::Constants),
+            2 => Ok(SectionId::Exports),
+            3 => Ok(SectionId::DebugInfo),
+            4 => Ok(SectionId::Custom),
+            _ => Err(VMError::MalformedModule),
+        }
+    }
+}
+
+impl From<SectionId> for u8 {
+    fn from(id: SectionId) -> u8 {
+        match id {
+            SectionId::Code => 0,
+            SectionId::Constants => 1,
+            SectionId::Exports => 2,
+            SectionId::DebugInfo => 3,
+            SectionId::Custom => 4,
+        }
+    }
+}
+
+/// Source line of the instruction at `ip`
+#[derive(Debug, Clone, PartialEq)]
+pub struct LineEntry {
+    pub ip: u32,
+    pub line: u32,
+}
+
+/// Named section with data for tools other than the interpreter
+#[derive(Debug, Clone, PartialEq)]
+pub struct CustomSection {
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+/// Binary container of a program.
+///
+/// The layout is:
+///
+/// - magic `\0spt` (**4 bytes**), format version `u16` and number of sections `u16`
+/// - section table, for every section its id `u8`, offset `u32` from the start of the module and length `u32`
+/// - section payloads
+///
+/// All integers are little endian. Every section kind except `Custom` appears at most once.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Module {
+    /// Instructions bytecode
+    pub code: Vec<u8>,
+    /// Constant pool
+    pub constants: Vec<Constant>,
+    /// Functions exported to the host by name
+    pub exports: HashMap<String, Export>,
+    /// Source lines of instructions, sorted by `ip`
+    pub debug_info: Vec<LineEntry>,
+    /// Custom sections in the order they appear
+    pub custom_sections: Vec<CustomSection>,
+}
+
+/// Reads little endian integers and byte strings from a section, failing at the end of it
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8]) -> Reader<'a> {
+        Reader { bytes, position: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.position >= self.bytes.len()
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], VMError> {
+        let end = self.position.checked_add(len).ok_or(VMError::MalformedModule)?;
+        let bytes = self.bytes.get(self.position..end).ok_or(VMError::MalformedModule)?;
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, VMError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, VMError> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, VMError> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    /// String prefixed with its `u8` length
+    fn name(&mut self) -> Result<String, VMError> {
+        let len = self.u8()? as usize;
+        String::from_utf8(self.bytes(len)?.to_vec()).map_err(|_| VMError::MalformedModule)
+    }
+}
+
+/// Tags of constant pool entries
+const CONSTANT_INT: u8 = 0;
+const CONSTANT_BIG_INT: u8 = 1;
+const CONSTANT_STR: u8 = 2;
+const CONSTANT_IDENT: u8 = 3;
+
+/// Append a string prefixed with its `u8` length
+fn write_name(out: &mut Vec<u8>, name: &str) -> Result<(), VMError> {
+    let len = u8::try_from(name.len()).map_err(|_| VMError::MalformedModule)?;
+    out.push(len);
+    out.extend_from_slice(name.as_bytes());
+    Ok(())
+}
+
+impl Module {
+    /// Parse a module, checking the magic bytes and the version first
+    pub fn read(bytes: &[u8]) -> Result<Module, VMError> {
+        let mut header = Reader::new(bytes);
+        if header.bytes(4).map_err(|_| VMError::BadMagic)? != MAGIC {
+            return Err(VMError::BadMagic);
+        }
+        let version = header.u16()?;
+        if version != VERSION {
+            return Err(VMError::UnsupportedVersion(version));
+        }
+        let count = header.u16()? as usize;
+
+        let mut module = Module::default();
+        let mut seen = Vec::new();
+        for _ in 0..count {
+            let id = SectionId::try_from(header.u8()?)?;
+            let offset = header.u32()? as usize;
+            let len = header.u32()? as usize;
+            if id != SectionId::Custom {
+                if seen.contains(&id) {
+                    return Err(VMError::MalformedModule);
+                }
+                seen.push(id);
+            }
+            let payload = Reader { bytes, position: offset }.bytes(len)?;
+            module.read_section(id, payload)?;
+        }
+        Ok(module)
+    }
+
+    fn read_section(&mut self, id: SectionId, payload: &[u8]) -> Result<(), VMError> {
+        let mut reader = Reader::new(payload);
+        match id {
+            SectionId::Code => self.code = payload.to_vec(),
+            SectionId::Constants => {
+                let count = reader.u16()?;
+                for _ in 0..count {
+                    let tag = reader.u8()?;
+                    let constant = match tag {
+                        CONSTANT_INT => Constant::Int(i64::from_le_bytes(reader.bytes(8)?.try_into().unwrap())),
+                        CONSTANT_BIG_INT => {
+                            let len = reader.u32()? as usize;
+                            Constant::BigInt(BigInt::from_signed_bytes_le(reader.bytes(len)?))
+                        },
+                        CONSTANT_STR | CONSTANT_IDENT => {
+                            let len = reader.u32()? as usize;
+                            let string = String::from_utf8(reader.bytes(len)?.to_vec()).map_err(|_| VMError::MalformedModule)?;
+                            if tag == CONSTANT_STR {
+                                Constant::Str(string)
+                            } else {
+                                Constant::Ident(string)
+                            }
+                        },
+                        _ => return Err(VMError::MalformedModule),
+                    };
+                    self.constants.push(constant);
+                }
+            },
+            SectionId::Exports => {
+                let count = reader.u16()?;
+                for _ in 0..count {
+                    let name = reader.name()?;
+                    let address = reader.u32()? as usize;
+                    let arity = reader.u8()? as usize;
+                    let returns = reader.u8()? as usize;
+                    self.exports.insert(name, Export { address, arity, returns });
+                }
+            },
+            SectionId::DebugInfo => {
+                while !reader.is_empty() {
+                    let ip = reader.u32()?;
+                    let line = reader.u32()?;
+                    self.debug_info.push(LineEntry { ip, line });
+                }
+            },
+            SectionId::Custom => {
+                let name = reader.name()?;
+                let data = payload[reader.position..].to_vec();
+                self.custom_sections.push(CustomSection { name, data });
+            },
+        }
+        Ok(())
+    }
+
+    /// Serialize the module, empty sections are left out
+    pub fn write(&self) -> Result<Vec<u8>, VMError> {
+        let length = |len: usize| u32::try_from(len).map_err(|_| VMError::MalformedModule);
+        let mut sections: Vec<(SectionId, Vec<u8>)> = Vec::new();
+        if !self.code.is_empty() {
+            sections.push((SectionId::Code, self.code.clone()));
+        }
+        if !self.constants.is_empty() {
+            let count = u16::try_from(self.constants.len()).map_err(|_| VMError::MalformedModule)?;
+            let mut payload = count.to_le_bytes().to_vec();
+            for constant in &self.constants {
+                let (tag, bytes) = match constant {
+                    Constant::Int(val) => (CONSTANT_INT, val.to_le_bytes().to_vec()),
+                    Constant::BigInt(val) => (CONSTANT_BIG_INT, val.to_signed_bytes_le()),
+                    Constant::Str(val) => (CONSTANT_STR, val.as_bytes().to_vec()),
+                    Constant::Ident(val) => (CONSTANT_IDENT, val.as_bytes().to_vec()),
+                };
+                payload.push(tag);
+                if tag != CONSTANT_INT {
+                    payload.extend_from_slice(&length(bytes.len())?.to_le_bytes());
+                }
+                payload.extend_from_slice(&bytes);
+            }
+            sections.push((SectionId::Constants, payload));
+        }
+        if !self.exports.is_empty() {
+            let count = u16::try_from(self.exports.len()).map_err(|_| VMError::MalformedModule)?;
+            let mut payload = count.to_le_bytes().to_vec();
+            // sorted, so the same module is always written the same way
+            let mut exports = self.exports.iter().collect::<Vec<_>>();
+            exports.sort_by_key(|(name, _)| name.as_str());
+            for (name, export) in exports {
+                write_name(&mut payload, name)?;
+                payload.extend_from_slice(&length(export.address)?.to_le_bytes());
+                payload.push(u8::try_from(export.arity).map_err(|_| VMError::MalformedModule)?);
+                payload.push(u8::try_from(export.returns).map_err(|_| VMError::MalformedModule)?);
+            }
+            sections.push((SectionId::Exports, payload));
+        }
+        if !self.debug_info.is_empty() {
+            let mut payload = Vec::new();
+            for entry in &self.debug_info {
+                payload.extend_from_slice(&entry.ip.to_le_bytes());
+                payload.extend_from_slice(&entry.line.to_le_bytes());
+            }
+            sections.push((SectionId::DebugInfo, payload));
+        }
+        for section in &self.custom_sections {
+            let mut payload = Vec::new();
+            write_name(&mut payload, &section.name)?;
+            payload.extend_from_slice(&section.data);
+            sections.push((SectionId::Custom, payload));
+        }
+
+        let mut out = MAGIC.to_vec();
+        out.extend_from_slice(&VERSION.to_le_bytes());
+        let count = u16::try_from(sections.len()).map_err(|_| VMError::MalformedModule)?;
+        out.extend_from_slice(&count.to_le_bytes());
+        let mut offset = HEADER_SIZE + SECTION_ENTRY_SIZE * sections.len();
+        for (id, payload) in &sections {
+            out.push((*id).into());
+            out.extend_from_slice(&length(offset)?.to_le_bytes());
+            out.extend_from_slice(&length(payload.len())?.to_le_bytes());
+            offset += payload.len();
+        }
+        for (_, payload) in sections {
+            out.extend_from_slice(&payload);
+        }
+        Ok(out)
+    }
+}
@@ -0,0 +1,363 @@
+use num_bigint::BigInt;
+
+use crate::decimal::{Decimal, RoundingMode};
+use crate::error::VMError;
+use crate::instruction::Instruction;
+use crate::program::{closure_slots, jump_target, switch_targets, var_name, Constant};
+
+/// Instruction decoded once when the program is loaded, with its operands parsed.
+///
+/// Jump, call and spawn targets are indexes into the decoded instructions rather than byte offsets.
+/// The dispatch loop runs over these, the bytecode stays the format programs are stored and exchanged in.
+#[derive(Debug)]
+pub(crate) enum Op {
+    LoadVal(i64),
+    LoadBig(Box<BigInt>),
+    LoadDec(i64, u8),
+    LoadStr(Box<str>),
+    WriteVar(Box<str>),
+    ReadVar(Box<str>),
+    LoadLocal(u16),
+    StoreLocal(u16),
+    LoadGlobal(u16),
+    StoreGlobal(u16),
+    /// Arguments to push and the target, `u32` keeps the op small
+    FuncCall(Box<[i64]>, u32),
+    /// `None` if the target is not the start of an instruction, which fails when it is executed
+    ReturnIndex(Option<usize>),
+    Jump(usize),
+    JumpIfTrue(usize),
+    JumpIfFalse(usize),
+    JumpIfFalseOrPop(usize),
+    JumpIfTrueOrPop(usize),
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    WrappingAdd,
+    WrappingSub,
+    WrappingMul,
+    SaturatingAdd,
+    SaturatingSub,
+    SaturatingMul,
+    ToBig,
+    ToInt,
+    NotEq,
+    Eq,
+    Gt,
+    Lt,
+    Gte,
+    Lte,
+    And,
+    Or,
+    Not,
+    Xor,
+    Shl,
+    Shr,
+    BitAnd,
+    BitOr,
+    BitXor,
+    Neg,
+    Dup,
+    Swap,
+    Drop,
+    Over,
+    Rot,
+    Pick(u8),
+    ToDec,
+    DecToInt(RoundingMode),
+    DecAdd,
+    DecSub,
+    DecMul(u8, RoundingMode),
+    DecDiv(u8, RoundingMode),
+    DecRescale(u8, RoundingMode),
+    DecCmp,
+    DecToStr,
+    StrToDec,
+    CallNative(u16),
+    CallNativeNamed(Box<str>),
+    SendChannel,
+    RecvChannel,
+    Spawn(usize),
+    Call(usize),
+    TailCall(usize),
+    Return,
+    Finish,
+    /// Targets indexed by the popped value and the default target, `u32` keeps the op small
+    Switch(Box<[u32]>, u32),
+    /// Index of the function and the captured slots, `u32` keeps the op small
+    Closure(u32, Box<[u16]>),
+    CallValue,
+    /// Index of the function
+    Coroutine(usize),
+    Yield,
+    Resume,
+    /// Index of the handler
+    Try(usize),
+    EndTry,
+    Throw,
+    /// `Block`, `Loop` and `End`, which only mark where blocks start and end
+    Nop,
+    /// Fused `ReadVar x; LoadVal n; Add; WriteVar x`, only for `n` that fits into `i32` to keep the op small
+    AddVar(Box<str>, i32),
+    /// Fused `LoadLocal s; LoadVal n; Add; StoreLocal s`
+    AddLocal(u16, i32),
+    /// Fused `LoadGlobal s; LoadVal n; Add; StoreGlobal s`
+    AddGlobal(u16, i32),
+    /// Fused `LoadVal n; <comparison>; JumpIfFalse target`, compares the value on top of the stack with `n`
+    CompareJumpIfFalse(Comparison, i64, u32),
+}
+
+/// Comparison of a fused `CompareJumpIfFalse`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Comparison {
+    Eq,
+    NotEq,
+    Gt,
+    Lt,
+    Gte,
+    Lte,
+}
+
+impl Comparison {
+    fn from_op(op: &Op) -> Option<Comparison> {
+        match op {
+            Op::Eq => Some(Comparison::Eq),
+            Op::NotEq => Some(Comparison::NotEq),
+            Op::Gt => Some(Comparison::Gt),
+            Op::Lt => Some(Comparison::Lt),
+            Op::Gte => Some(Comparison::Gte),
+            Op::Lte => Some(Comparison::Lte),
+            _ => None,
+        }
+    }
+
+    /// `a <comparison> b`
+    pub(crate) fn test(self, a: i64, b: i64) -> bool {
+        match self {
+            Comparison::Eq => a == b,
+            Comparison::NotEq => a != b,
+            Comparison::Gt => a > b,
+            Comparison::Lt => a < b,
+            Comparison::Gte => a >= b,
+            Comparison::Lte => a <= b,
+        }
+    }
+}
+
+/// Number of ops a fused op stands for, including itself
+pub(crate) fn fused_len(op: &Op) -> usize {
+    match op {
+        Op::AddVar(..) | Op::AddLocal(..) | Op::AddGlobal(..) => 4,
+        Op::CompareJumpIfFalse(..) => 3,
+        _ => 1,
+    }
+}
+
+/// Number of values the ops a fused op stands for push above the stack it starts with at most. The fused op
+/// only takes its fast path if they fit, so it fails with `StackOverflow` exactly where they would.
+pub(crate) fn fused_peak(op: &Op) -> usize {
+    match op {
+        Op::AddVar(..) | Op::AddLocal(..) | Op::AddGlobal(..) => 2,
+        Op::CompareJumpIfFalse(..) => 1,
+        _ => 0,
+    }
+}
+
+/// Replace common sequences of ops with fused ones.
+///
+/// The fused op takes the place of the first op of the sequence, the rest stays where it is, so indexes do
+/// not change and a jump into the middle of a sequence still runs the original ops. A fused op that can not
+/// take its fast path, e.g because a value is not an `Int`, runs the first op of the sequence instead and
+/// the machine continues with the rest of it.
+pub(crate) fn fuse(ops: &mut [Op]) {
+    for i in 0..ops.len() {
+        let fused = match &ops[i..] {
+            [Op::ReadVar(read), Op::LoadVal(n), Op::Add, Op::WriteVar(write), ..] if read == write => {
+                i32::try_from(*n).ok().map(|n| Op::AddVar(read.clone(), n))
+            },
+            [Op::LoadLocal(load), Op::LoadVal(n), Op::Add, Op::StoreLocal(store), ..] if load == store => {
+                i32::try_from(*n).ok().map(|n| Op::AddLocal(*load, n))
+            },
+            [Op::LoadGlobal(load), Op::LoadVal(n), Op::Add, Op::StoreGlobal(store), ..] if load == store => {
+                i32::try_from(*n).ok().map(|n| Op::AddGlobal(*load, n))
+            },
+            [Op::LoadVal(n), comparison, Op::JumpIfFalse(target), ..] => Comparison::from_op(comparison)
+                .map(|comparison| Op::CompareJumpIfFalse(comparison, *n, *target as u32)),
+            _ => None,
+        };
+        if let Some(fused) = fused {
+            ops[i] = fused;
+        }
+    }
+}
+
+/// Decode verified code, `instructions` are the indexes and kinds of its instructions in order and `blocks`
+/// the targets of the structured control instructions among them
+pub(crate) fn decode(
+    code: &[u8],
+    instructions: &[(usize, Instruction)],
+    blocks: &[Option<usize>],
+    constants: &[Constant],
+) -> Result<Vec<Op>, VMError> {
+    // index of the instruction starting at `address`, the end of the code is one past the last instruction
+    let index = |address: usize| match instructions.binary_search_by_key(&address, |&(ip, _)| ip) {
+        Ok(index) => Some(index),
+        Err(index) if address >= code.len() => Some(index),
+        Err(_) => None,
+    };
+
+    let mut ops = Vec::with_capacity(instructions.len());
+    for (&(ip, instruction), &block) in instructions.iter().zip(blocks) {
+        let size = instruction.operand_size(&code[ip + 1..]).unwrap_or(0);
+        let operands = &code[ip + 1..ip + 1 + size];
+        // targets were checked by the verifier, except for `ReturnIndex`
+        let jump = || match jump_target(ip, instruction, operands) {
+            Ok(Some(target)) => index(target).ok_or(VMError::InvalidJumpTarget(ip)),
+            _ => Err(VMError::InvalidJumpTarget(ip)),
+        };
+        let absolute = || index(u32::from_le_bytes(operands[..4].try_into().unwrap()) as usize);
+        let slot = || u16::from_le_bytes([operands[0], operands[1]]);
+        let mode = |byte: u8| RoundingMode::try_from(byte);
+        let string = |bytes: &[u8]| String::from_utf8(bytes.to_vec()).map(String::into_boxed_str).map_err(|_| VMError::InvalidOperand);
+
+        let op = match instruction {
+            Instruction::LoadVal => Op::LoadVal(i64::from_le_bytes(operands.try_into().unwrap())),
+            Instruction::LoadBig => Op::LoadBig(Box::new(BigInt::from_signed_bytes_le(&operands[1..]))),
+            Instruction::LoadDec => {
+                let (mantissa, scale) = (i64::from_le_bytes(operands[..8].try_into().unwrap()), operands[8]);
+                Decimal::new(mantissa as i128, scale)?;
+                Op::LoadDec(mantissa, scale)
+            },
+            Instruction::LoadStr => Op::LoadStr(string(&operands[1..])?),
+            Instruction::LoadConst => match &constants[slot() as usize] {
+                Constant::Int(val) => Op::LoadVal(*val),
+                Constant::BigInt(val) => Op::LoadBig(Box::new(val.clone())),
+                Constant::Str(val) | Constant::Ident(val) => Op::LoadStr(val.as_str().into()),
+            },
+            Instruction::WriteVar => Op::WriteVar(var_name(operands).into()),
+            Instruction::ReadVar => Op::ReadVar(var_name(operands).into()),
+            Instruction::WriteVarConst | Instruction::ReadVarConst => {
+                let name: Box<str> = match &constants[slot() as usize] {
+                    Constant::Ident(name) => name.as_str().into(),
+                    _ => return Err(VMError::InvalidConstant(ip)),
+                };
+                if instruction == Instruction::WriteVarConst {
+                    Op::WriteVar(name)
+                } else {
+                    Op::ReadVar(name)
+                }
+            },
+            Instruction::LoadLocal => Op::LoadLocal(slot()),
+            Instruction::StoreLocal => Op::StoreLocal(slot()),
+            Instruction::LoadGlobal => Op::LoadGlobal(slot()),
+            Instruction::StoreGlobal => Op::StoreGlobal(slot()),
+            Instruction::FuncCall => {
+                let target = absolute().ok_or(VMError::InvalidJumpTarget(ip))?;
+                let args = operands[5..].chunks(8).map(|arg| i64::from_le_bytes(arg.try_into().unwrap())).collect();
+                Op::FuncCall(args, target as u32)
+            },
+            Instruction::ReturnIndex => Op::ReturnIndex(absolute()),
+            Instruction::Jump
+            | Instruction::JumpBack
+            | Instruction::JumpWide
+            | Instruction::JumpLong
+            | Instruction::JumpAbs => Op::Jump(jump()?),
+            Instruction::JumpIfTrue
+            | Instruction::JumpIfTrueWide
+            | Instruction::JumpIfTrueLong
+            | Instruction::JumpIfTrueAbs => Op::JumpIfTrue(jump()?),
+            Instruction::JumpIfFalse
+            | Instruction::JumpIfFalseWide
+            | Instruction::JumpIfFalseLong
+            | Instruction::JumpIfFalseAbs => Op::JumpIfFalse(jump()?),
+            Instruction::JumpIfFalseOrPop => Op::JumpIfFalseOrPop(jump()?),
+            Instruction::JumpIfTrueOrPop => Op::JumpIfTrueOrPop(jump()?),
+            Instruction::Add => Op::Add,
+            Instruction::Sub => Op::Sub,
+            Instruction::Mul => Op::Mul,
+            Instruction::Div => Op::Div,
+            Instruction::Mod => Op::Mod,
+            Instruction::WrappingAdd => Op::WrappingAdd,
+            Instruction::WrappingSub => Op::WrappingSub,
+            Instruction::WrappingMul => Op::WrappingMul,
+            Instruction::SaturatingAdd => Op::SaturatingAdd,
+            Instruction::SaturatingSub => Op::SaturatingSub,
+            Instruction::SaturatingMul => Op::SaturatingMul,
+            Instruction::ToBig => Op::ToBig,
+            Instruction::ToInt => Op::ToInt,
+            Instruction::NotEq => Op::NotEq,
+            Instruction::Eq => Op::Eq,
+            Instruction::Gt => Op::Gt,
+            Instruction::Lt => Op::Lt,
+            Instruction::Gte => Op::Gte,
+            Instruction::Lte => Op::Lte,
+            Instruction::And => Op::And,
+            Instruction::Or => Op::Or,
+            Instruction::Not => Op::Not,
+            Instruction::Xor => Op::Xor,
+            Instruction::Shl => Op::Shl,
+            Instruction::Shr => Op::Shr,
+            Instruction::BitAnd => Op::BitAnd,
+            Instruction::BitOr => Op::BitOr,
+            Instruction::BitXor => Op::BitXor,
+            Instruction::Neg => Op::Neg,
+            Instruction::Dup => Op::Dup,
+            Instruction::Swap => Op::Swap,
+            Instruction::Drop => Op::Drop,
+            Instruction::Over => Op::Over,
+            Instruction::Rot => Op::Rot,
+            Instruction::Pick => Op::Pick(operands[0]),
+            Instruction::ToDec => Op::ToDec,
+            Instruction::DecToInt => Op::DecToInt(mode(operands[0])?),
+            Instruction::DecAdd => Op::DecAdd,
+            Instruction::DecSub => Op::DecSub,
+            Instruction::DecMul => Op::DecMul(operands[0], mode(operands[1])?),
+            Instruction::DecDiv => Op::DecDiv(operands[0], mode(operands[1])?),
+            Instruction::DecRescale => Op::DecRescale(operands[0], mode(operands[1])?),
+            Instruction::DecCmp => Op::DecCmp,
+            Instruction::DecToStr => Op::DecToStr,
+            Instruction::StrToDec => Op::StrToDec,
+            Instruction::CallNative => Op::CallNative(u16::from_le_bytes([operands[0], operands[1]])),
+            Instruction::CallNativeNamed => Op::CallNativeNamed(string(&operands[1..])?),
+            Instruction::SendChannel => Op::SendChannel,
+            Instruction::RecvChannel => Op::RecvChannel,
+            Instruction::Spawn => Op::Spawn(absolute().ok_or(VMError::InvalidJumpTarget(ip))?),
+            Instruction::Call => Op::Call(absolute().ok_or(VMError::InvalidJumpTarget(ip))?),
+            Instruction::TailCall => Op::TailCall(absolute().ok_or(VMError::InvalidJumpTarget(ip))?),
+            Instruction::Return => Op::Return,
+            Instruction::Finish => Op::Finish,
+            Instruction::Switch => {
+                let mut targets = switch_targets(operands).map(|target| index(target).map(|index| index as u32));
+                let default = targets.next().flatten().ok_or(VMError::InvalidJumpTarget(ip))?;
+                let targets = targets.collect::<Option<_>>().ok_or(VMError::InvalidJumpTarget(ip))?;
+                Op::Switch(targets, default)
+            },
+            Instruction::Try => {
+                let target = u32::from_le_bytes(operands.try_into().unwrap()) as usize;
+                Op::Try(index(target).ok_or(VMError::InvalidJumpTarget(ip))?)
+            },
+            Instruction::Coroutine => {
+                let target = u32::from_le_bytes(operands.try_into().unwrap()) as usize;
+                Op::Coroutine(index(target).ok_or(VMError::InvalidJumpTarget(ip))?)
+            },
+            Instruction::Yield => Op::Yield,
+            Instruction::Resume => Op::Resume,
+            Instruction::Closure => {
+                let target = u32::from_le_bytes(operands[..4].try_into().unwrap()) as usize;
+                let target = index(target).ok_or(VMError::InvalidJumpTarget(ip))?;
+                Op::Closure(target as u32, closure_slots(operands).collect())
+            },
+            Instruction::CallValue => Op::CallValue,
+            Instruction::EndTry => Op::EndTry,
+            Instruction::Throw => Op::Throw,
+            // structured control flow is resolved to plain jumps
+            Instruction::Block | Instruction::Loop | Instruction::End => Op::Nop,
+            Instruction::If => Op::JumpIfFalse(block.unwrap()),
+            Instruction::Else | Instruction::Break | Instruction::Continue => Op::Jump(block.unwrap()),
+        };
+        ops.push(op);
+    }
+    Ok(ops)
+}
@@ -0,0 +1,625 @@
+use std::collections::HashMap;
+
+use num_bigint::BigInt;
+
+use crate::error::VMError;
+use crate::host::HostFunctions;
+use crate::instruction::Instruction;
+#[cfg(feature = "jit")]
+use crate::jit::{self, Jit, NativeFunction};
+use crate::module::{CustomSection, LineEntry, Module};
+use crate::op::{self, Op};
+use crate::stack::StackValue;
+
+/// Function the host can call by name with `Machine::call`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Export {
+    /// Index of the first instruction of the function
+    pub address: usize,
+    /// Number of arguments the function expects on the stack
+    pub arity: usize,
+    /// Number of values the function leaves on the stack
+    pub returns: usize,
+}
+
+/// Entry of the constant pool of a program
+#[derive(Debug, Clone, PartialEq)]
+pub enum Constant {
+    /// Primitive value
+    Int(i64),
+    /// Arbitrary precision integer
+    BigInt(BigInt),
+    /// String of any length
+    Str(String),
+    /// Name of a variable, referenced by `ReadVarConst` and `WriteVarConst`
+    Ident(String),
+}
+
+impl Constant {
+    /// Value pushed onto the stack by `LoadConst`
+    pub fn to_value(&self) -> StackValue {
+        match self {
+            Constant::Int(val) => StackValue::Int(*val),
+            Constant::BigInt(val) => StackValue::BigInt(val.clone()),
+            Constant::Str(val) | Constant::Ident(val) => StackValue::Str(val.clone()),
+        }
+    }
+}
+
+/// Loaded program: instructions and everything that does not change while it runs.
+///
+/// The instructions are verified once when the program is created. A program is immutable once it is
+/// shared with `Arc`, so any number of `Machine`s can run it at the same time.
+#[derive(Debug)]
+pub struct Program {
+    /// Instructions bytecode
+    pub(crate) code: Vec<u8>,
+    /// Functions exported to the host by name
+    pub(crate) exports: HashMap<String, Export>,
+    /// Rust functions the program can call with `CallNative`
+    pub(crate) host_functions: HostFunctions,
+    /// Constant pool, referenced by index from `LoadConst`, `ReadVarConst` and `WriteVarConst`
+    pub(crate) constants: Vec<Constant>,
+    /// Source lines of instructions, sorted by `ip`
+    debug_info: Vec<LineEntry>,
+    /// Custom sections of the module the program was loaded from
+    custom_sections: Vec<CustomSection>,
+    /// Number of `LoadLocal` and `StoreLocal` slots every function frame has
+    pub(crate) locals: usize,
+    /// Number of `LoadGlobal` and `StoreGlobal` slots shared by every function
+    pub(crate) globals: usize,
+    /// Names of the `LoadGlobal` and `StoreGlobal` slots assigned by `resolve_locals`
+    global_names: Vec<String>,
+    /// Decoded instructions the machine runs
+    pub(crate) ops: Vec<Op>,
+    /// Common sequences of decoded instructions are fused into single ones
+    fusion: bool,
+    /// Index in the code of every decoded instruction, followed by the length of the code
+    pub(crate) offsets: Vec<usize>,
+    /// Native code compiled by `compile_jit`, dropped whenever the instructions change
+    #[cfg(feature = "jit")]
+    jit: Option<Jit>,
+}
+
+impl Program {
+    /// Verify the instructions and load them as a program
+    pub fn new(code: Vec<u8>) -> Result<Program, VMError> {
+        Program::with_constants(code, Vec::new())
+    }
+
+    /// Verify the instructions against the constant pool and load them as a program
+    pub fn with_constants(code: Vec<u8>, constants: Vec<Constant>) -> Result<Program, VMError> {
+        let mut program = Program {
+            code,
+            exports: HashMap::new(),
+            host_functions: HostFunctions::new(),
+            constants,
+            debug_info: Vec::new(),
+            custom_sections: Vec::new(),
+            locals: 0,
+            globals: 0,
+            global_names: Vec::new(),
+            ops: Vec::new(),
+            fusion: true,
+            offsets: Vec::new(),
+            #[cfg(feature = "jit")]
+            jit: None,
+        };
+        program.decode()?;
+        Ok(program)
+    }
+
+    /// Verify the code and decode it into the instructions the machine runs
+    fn decode(&mut self) -> Result<(), VMError> {
+        let instructions = split(&self.code)?;
+        let (locals, globals) = verify(&self.code, &instructions, &self.constants)?;
+        self.locals = locals;
+        self.globals = globals.max(self.global_names.len());
+        let blocks = block_targets(&self.code, &instructions)?;
+        self.ops = op::decode(&self.code, &instructions, &blocks, &self.constants)?;
+        if self.fusion {
+            op::fuse(&mut self.ops);
+        }
+        self.offsets = instructions.iter().map(|&(ip, _)| ip).chain([self.code.len()]).collect();
+        #[cfg(feature = "jit")]
+        {
+            self.jit = None;
+        }
+        Ok(())
+    }
+
+    /// Compile the program, its functions and its loops to native code and return how many were compiled.
+    ///
+    /// Code that uses nothing but primitive integers, variables, jumps and stack manipulation is compiled,
+    /// native code stops at anything calling functions, host functions, channels or working with big
+    /// integers, decimals or strings and the interpreter goes on from there. The machine runs the native code
+    /// when it starts at the beginning of the program, an export, a function or a loop with only primitive
+    /// integers in the values on the stack and the variables the code uses, the result is the same as
+    /// interpreting it. Exports registered afterwards are not compiled until this is called again.
+    #[cfg(feature = "jit")]
+    pub fn compile_jit(&mut self) -> Result<usize, VMError> {
+        let mut entries = vec![0];
+        for export in self.exports.values() {
+            match self.op_index(export.address) {
+                Some(ip) if !entries.contains(&ip) => entries.push(ip),
+                _ => {},
+            }
+        }
+        for ip in jit::entries(&self.ops) {
+            if !entries.contains(&ip) {
+                entries.push(ip);
+            }
+        }
+        let jit = Jit::compile(&self.ops, &entries)?;
+        let compiled = jit.len();
+        self.jit = Some(jit);
+        Ok(compiled)
+    }
+
+    /// Native code compiled for the instruction at `ip`
+    #[cfg(feature = "jit")]
+    pub(crate) fn native(&self, ip: usize) -> Option<&NativeFunction> {
+        self.jit.as_ref()?.function(ip)
+    }
+
+    /// Turn fusion of common instruction sequences on or off, it is on by default.
+    /// Fused programs behave exactly the same, this is meant for comparing the two.
+    pub fn set_fusion(&mut self, fusion: bool) -> Result<(), VMError> {
+        self.fusion = fusion;
+        self.decode()
+    }
+
+    /// Index of the decoded instruction starting at `address` in the code
+    pub(crate) fn op_index(&self, address: usize) -> Option<usize> {
+        self.offsets.binary_search(&address).ok()
+    }
+
+    /// Index in the code of the instruction at `ip`, the instruction pointer of a `Machine`
+    pub fn offset(&self, ip: usize) -> Option<usize> {
+        self.offsets.get(ip).copied()
+    }
+
+    /// Load a program from the binary module format
+    pub fn load(bytes: &[u8]) -> Result<Program, VMError> {
+        Program::from_module(Module::read(bytes)?)
+    }
+
+    /// Verify the code of a module and load it as a program
+    pub fn from_module(module: Module) -> Result<Program, VMError> {
+        let mut program = Program::with_constants(module.code, module.constants)?;
+        for (name, export) in module.exports {
+            program.export(&name, export.address, export.arity, export.returns)?;
+        }
+        program.debug_info = module.debug_info;
+        program.custom_sections = module.custom_sections;
+        Ok(program)
+    }
+
+    /// Module to save the program in the binary format, host functions are not part of it
+    pub fn to_module(&self) -> Module {
+        Module {
+            code: self.code.clone(),
+            constants: self.constants.clone(),
+            exports: self.exports.clone(),
+            debug_info: self.debug_info.clone(),
+            custom_sections: self.custom_sections.clone(),
+        }
+    }
+
+    /// Source line of the instruction at `ip`, if the program has debug info
+    pub fn line(&self, ip: usize) -> Option<u32> {
+        let index = self.debug_info.partition_point(|entry| entry.ip as usize <= ip);
+        self.debug_info[..index].last().map(|entry| entry.line)
+    }
+
+    /// Custom sections of the module the program was loaded from
+    pub fn custom_sections(&self) -> &[CustomSection] {
+        &self.custom_sections
+    }
+
+    /// Constant pool of the program
+    pub fn constants(&self) -> &[Constant] {
+        &self.constants
+    }
+
+    /// Slot of the variable with the given name, if `resolve_locals` assigned one
+    pub fn local_slot(&self, name: &str) -> Option<usize> {
+        self.global_names.iter().position(|global| global == name)
+    }
+
+    /// Instructions of the program
+    pub fn code(&self) -> &[u8] {
+        &self.code
+    }
+
+    /// Functions exported to the host
+    pub fn exports(&self) -> &HashMap<String, Export> {
+        &self.exports
+    }
+
+    /// Host functions the program can call
+    pub fn host_functions(&self) -> &HostFunctions {
+        &self.host_functions
+    }
+
+    /// Export the function starting at `address`, so the host can call it by name
+    pub fn export(&mut self, name: &str, address: usize, arity: usize, returns: usize) -> Result<(), VMError> {
+        if !self.is_boundary(address) {
+            return Err(VMError::InvalidJumpTarget(address));
+        }
+        self.exports.insert(name.to_string(), Export { address, arity, returns });
+        Ok(())
+    }
+
+    /// Register a host function callable with `CallNative` and return its id, see `HostFunctions::register`
+    pub fn register_native<F>(&mut self, name: &str, args: usize, returns: usize, func: F) -> u16
+    where
+        F: Fn(Vec<StackValue>) -> Result<Vec<StackValue>, String> + Send + Sync + 'static,
+    {
+        self.host_functions.register(name, args, returns, func)
+    }
+
+    /// Resolve variable names to slots, so variables are accessed by index instead of being looked up by name.
+    ///
+    /// `ReadVar`, `WriteVar`, `ReadVarConst` and `WriteVarConst` are replaced with `LoadGlobal` and
+    /// `StoreGlobal`, every distinct name gets its own slot. The slots are shared by every function like the
+    /// variables were, unlike the `LoadLocal` and `StoreLocal` slots of a function frame. Jump and call targets,
+    /// exports and debug info are moved along with the instructions.
+    pub fn resolve_locals(&mut self) -> Result<(), VMError> {
+        let instructions = split(&self.code)?;
+        let operands = |ip: usize, instruction: Instruction| {
+            let size = instruction.operand_size(&self.code[ip + 1..]).unwrap_or(0);
+            &self.code[ip + 1..ip + 1 + size]
+        };
+
+        // new index of every byte, the bytes of an instruction move to its start
+        let mut moved = vec![0; self.code.len() + 1];
+        let mut new_ip = 0;
+        for &(ip, instruction) in &instructions {
+            let len = 1 + operands(ip, instruction).len();
+            moved[ip..ip + len].fill(new_ip);
+            new_ip += if is_variable(instruction) { 3 } else { len };
+        }
+        moved[self.code.len()] = new_ip;
+
+        let mut code = Vec::with_capacity(new_ip);
+        for &(ip, instruction) in &instructions {
+            let operands = operands(ip, instruction);
+            let next = ip + 1 + operands.len();
+            let address = || u32::from_le_bytes(operands[..4].try_into().unwrap()) as usize;
+            match instruction {
+                Instruction::ReadVar | Instruction::WriteVar | Instruction::ReadVarConst | Instruction::WriteVarConst => {
+                    let name = match instruction {
+                        Instruction::ReadVar | Instruction::WriteVar => var_name(operands),
+                        _ => match &self.constants[u16::from_le_bytes([operands[0], operands[1]]) as usize] {
+                            Constant::Ident(name) => name.clone(),
+                            _ => return Err(VMError::InvalidConstant(ip)),
+                        },
+                    };
+                    let slot = match self.local_slot(&name) {
+                        Some(slot) => slot,
+                        None => {
+                            self.global_names.push(name);
+                            self.global_names.len() - 1
+                        },
+                    };
+                    let slot = u16::try_from(slot).map_err(|_| VMError::InvalidOperand)?;
+                    let load = matches!(instruction, Instruction::ReadVar | Instruction::ReadVarConst);
+                    code.push(if load { Instruction::LoadGlobal } else { Instruction::StoreGlobal }.into());
+                    code.extend_from_slice(&slot.to_le_bytes());
+                },
+                _ if is_jump(instruction) => {
+                    // jumps only get shorter, so the offset still fits into the same encoding
+                    let target = jump_target(ip, instruction, operands)?.ok_or(VMError::InvalidJumpTarget(ip))?;
+                    let offset = moved[target] as i64 - moved[next] as i64;
+                    code.push(instruction.into());
+                    match instruction {
+                        Instruction::JumpBack => code.push(-offset as u8),
+                        Instruction::JumpWide | Instruction::JumpIfTrueWide | Instruction::JumpIfFalseWide => {
+                            code.extend_from_slice(&(offset as i16).to_le_bytes());
+                        },
+                        Instruction::JumpLong | Instruction::JumpIfTrueLong | Instruction::JumpIfFalseLong => {
+                            code.extend_from_slice(&(offset as i32).to_le_bytes());
+                        },
+                        Instruction::JumpAbs | Instruction::JumpIfTrueAbs | Instruction::JumpIfFalseAbs => {
+                            code.extend_from_slice(&(moved[target] as u32).to_le_bytes());
+                        },
+                        _ => code.push(offset as u8),
+                    }
+                },
+                Instruction::Switch => {
+                    code.push(instruction.into());
+                    code.extend_from_slice(&operands[..2]);
+                    for target in switch_targets(operands) {
+                        code.extend_from_slice(&(moved[target] as u32).to_le_bytes());
+                    }
+                },
+                Instruction::Call
+                | Instruction::TailCall
+                | Instruction::Spawn
+                | Instruction::FuncCall
+                | Instruction::ReturnIndex
+                | Instruction::Try
+                | Instruction::Coroutine
+                | Instruction::Closure => {
+                    // `ReturnIndex` may point past the end, which finishes the program wherever it points
+                    let target = moved.get(address()).copied().unwrap_or(address());
+                    code.push(instruction.into());
+                    code.extend_from_slice(&(target as u32).to_le_bytes());
+                    code.extend_from_slice(&operands[4..]);
+                },
+                _ => code.extend_from_slice(&self.code[ip..next]),
+            }
+        }
+
+        self.code = code;
+        self.decode()?;
+        for export in self.exports.values_mut() {
+            export.address = moved[export.address];
+        }
+        for entry in &mut self.debug_info {
+            entry.ip = moved.get(entry.ip as usize).copied().unwrap_or(new_ip) as u32;
+        }
+        Ok(())
+    }
+
+    fn is_boundary(&self, index: usize) -> bool {
+        self.op_index(index).is_some()
+    }
+}
+
+/// Name of a variable given by the 4 bytes of `ReadVar` and `WriteVar`, without the `0x00` padding
+pub(crate) fn var_name(bytes: &[u8]) -> String {
+    bytes.iter().map(|&byte| byte as char).collect::<String>().trim_end_matches('\0').to_string()
+}
+
+/// Instructions that access a variable by name
+fn is_variable(instruction: Instruction) -> bool {
+    matches!(
+        instruction,
+        Instruction::ReadVar | Instruction::WriteVar | Instruction::ReadVarConst | Instruction::WriteVarConst
+    )
+}
+
+/// Instructions that jump to a target given by their operands
+pub(crate) fn is_jump(instruction: Instruction) -> bool {
+    matches!(
+        instruction,
+        Instruction::Jump
+            | Instruction::JumpBack
+            | Instruction::JumpIfTrue
+            | Instruction::JumpIfFalse
+            | Instruction::JumpIfFalseOrPop
+            | Instruction::JumpIfTrueOrPop
+            | Instruction::JumpWide
+            | Instruction::JumpIfTrueWide
+            | Instruction::JumpIfFalseWide
+            | Instruction::JumpLong
+            | Instruction::JumpIfTrueLong
+            | Instruction::JumpIfFalseLong
+            | Instruction::JumpAbs
+            | Instruction::JumpIfTrueAbs
+            | Instruction::JumpIfFalseAbs
+    )
+}
+
+/// Instructions that start a function at the `u32` index given by their first operands
+fn is_call(instruction: Instruction) -> bool {
+    matches!(
+        instruction,
+        Instruction::Call
+            | Instruction::TailCall
+            | Instruction::Spawn
+            | Instruction::FuncCall
+            | Instruction::Coroutine
+            | Instruction::Closure
+    )
+}
+
+/// Index the jump instruction at `ip` with the given operands jumps to, `None` if it is not a jump.
+/// Relative offsets count from the next instruction, fails if the target is before the start of the code.
+pub(crate) fn jump_target(ip: usize, instruction: Instruction, operands: &[u8]) -> Result<Option<usize>, VMError> {
+    let next = (ip + 1 + operands.len()) as i64;
+    let relative = |offset: i64| usize::try_from(next + offset).map(Some).map_err(|_| VMError::InvalidJumpTarget(ip));
+    match instruction {
+        Instruction::Jump
+        | Instruction::JumpIfTrue
+        | Instruction::JumpIfFalse
+        | Instruction::JumpIfFalseOrPop
+        | Instruction::JumpIfTrueOrPop => relative(operands[0] as i64),
+        Instruction::JumpBack => relative(-(operands[0] as i64)),
+        Instruction::JumpWide | Instruction::JumpIfTrueWide | Instruction::JumpIfFalseWide => {
+            relative(i16::from_le_bytes([operands[0], operands[1]]) as i64)
+        },
+        Instruction::JumpLong | Instruction::JumpIfTrueLong | Instruction::JumpIfFalseLong => {
+            relative(i32::from_le_bytes(operands[..4].try_into().unwrap()) as i64)
+        },
+        Instruction::JumpAbs | Instruction::JumpIfTrueAbs | Instruction::JumpIfFalseAbs => {
+            Ok(Some(u32::from_le_bytes(operands[..4].try_into().unwrap()) as usize))
+        },
+        _ => Ok(None),
+    }
+}
+
+/// Match the structured control instructions and find where each one continues, as an index into
+/// `instructions`: `If` past its `Else` or `End` when the condition is false, `Else` and `Break` past the
+/// `End` of their block, `Continue` past the `Loop` it goes back to. Other instructions have no target.
+///
+/// Fails with `VMError::UnbalancedBlock` if blocks are not properly nested and with
+/// `VMError::InvalidJumpTarget` if `Break` or `Continue` refers to a block that does not enclose it, or
+/// `Continue` to one that is not a `Loop`.
+pub(crate) fn block_targets(code: &[u8], instructions: &[(usize, Instruction)]) -> Result<Vec<Option<usize>>, VMError> {
+    let mut targets = vec![None; instructions.len()];
+    // blocks that are open at the current instruction, with the `Else` of an `If`
+    let mut open: Vec<(usize, Option<usize>)> = Vec::new();
+    // `End` of every block, by the index of the instruction opening it
+    let mut ends = vec![0; instructions.len()];
+    // `Break` and `Continue` with the index of the block they refer to
+    let mut branches = Vec::new();
+
+    for (index, &(ip, instruction)) in instructions.iter().enumerate() {
+        match instruction {
+            Instruction::Block | Instruction::Loop | Instruction::If => open.push((index, None)),
+            Instruction::Else => match open.last_mut() {
+                Some((block, else_index @ None)) if instructions[*block].1 == Instruction::If => *else_index = Some(index),
+                _ => return Err(VMError::UnbalancedBlock(ip)),
+            },
+            Instruction::End => {
+                let (block, else_index) = open.pop().ok_or(VMError::UnbalancedBlock(ip))?;
+                ends[block] = index;
+                match else_index {
+                    Some(else_index) => {
+                        targets[block] = Some(else_index + 1);
+                        targets[else_index] = Some(index + 1);
+                    },
+                    None if instructions[block].1 == Instruction::If => targets[block] = Some(index + 1),
+                    None => {},
+                }
+            },
+            Instruction::Break | Instruction::Continue => {
+                let depth = code[ip + 1] as usize;
+                let (block, _) = open.iter().rev().nth(depth).ok_or(VMError::InvalidJumpTarget(ip))?;
+                if instruction == Instruction::Continue && instructions[*block].1 != Instruction::Loop {
+                    return Err(VMError::InvalidJumpTarget(ip));
+                }
+                branches.push((index, *block));
+            },
+            _ => {},
+        }
+    }
+    if let Some(&(block, _)) = open.first() {
+        return Err(VMError::UnbalancedBlock(instructions[block].0));
+    }
+
+    for (index, block) in branches {
+        targets[index] = Some(match instructions[index].1 {
+            Instruction::Break => ends[block] + 1,
+            _ => block + 1,
+        });
+    }
+    Ok(targets)
+}
+
+/// Local slots captured by a `Closure` with the given operands
+pub(crate) fn closure_slots(operands: &[u8]) -> impl Iterator<Item = u16> + '_ {
+    operands[5..].chunks(2).map(|slot| u16::from_le_bytes([slot[0], slot[1]]))
+}
+
+/// Default target followed by the targets of a `Switch` with the given operands
+pub(crate) fn switch_targets(operands: &[u8]) -> impl Iterator<Item = usize> + '_ {
+    operands[2..].chunks(4).map(|target| u32::from_le_bytes(target.try_into().unwrap()) as usize)
+}
+
+/// Split the code into instructions, returning the index of each one.
+/// Fails if a byte is not an instruction or operands run past the end.
+fn split(code: &[u8]) -> Result<Vec<(usize, Instruction)>, VMError> {
+    let mut instructions = Vec::new();
+    let mut ip = 0;
+    while ip < code.len() {
+        let instruction = Instruction::try_from(code[ip])?;
+        let size = instruction
+            .operand_size(&code[ip + 1..])
+            .ok_or(VMError::TruncatedInstruction(ip))?;
+        if ip + 1 + size > code.len() {
+            return Err(VMError::TruncatedInstruction(ip));
+        }
+        instructions.push((ip, instruction));
+        ip += 1 + size;
+    }
+    Ok(instructions)
+}
+
+/// Check that every instruction is valid and complete, that static jump targets land on an instruction,
+/// that every `TailCall` is followed by `Return` and outside of the `Try` regions of its function and that
+/// constants are referenced with the right type. Regions go from `Try` to `EndTry` in the order of the code,
+/// every function, the target of a call, starts outside of them.
+///
+/// Returns the number of local and global slots the code uses.
+/// `ReturnIndex` targets are not checked, jumping past the end finishes the program.
+fn verify(
+    code: &[u8],
+    instructions: &[(usize, Instruction)],
+    constants: &[Constant],
+) -> Result<(usize, usize), VMError> {
+    let mut boundaries = vec![false; code.len() + 1];
+    for &(ip, _) in instructions {
+        boundaries[ip] = true;
+    }
+    boundaries[code.len()] = true;
+
+    let operands = |ip: usize, instruction: Instruction| {
+        &code[ip + 1..ip + 1 + instruction.operand_size(&code[ip + 1..]).unwrap_or(0)]
+    };
+    let mut functions = vec![false; code.len() + 1];
+    for &(ip, instruction) in instructions {
+        if is_call(instruction) {
+            let target = u32::from_le_bytes(operands(ip, instruction)[..4].try_into().unwrap()) as usize;
+            if let Some(function) = functions.get_mut(target) {
+                *function = true;
+            }
+        }
+    }
+
+    let (mut locals, mut globals, mut regions) = (0, 0, 0usize);
+    for (index, &(ip, instruction)) in instructions.iter().enumerate() {
+        let operands = operands(ip, instruction);
+        if functions[ip] {
+            regions = 0;
+        }
+        match instruction {
+            Instruction::Try => regions += 1,
+            Instruction::EndTry => regions = regions.saturating_sub(1),
+            // the frame is reused, the handlers of its regions would be lost
+            Instruction::TailCall
+                if regions > 0 || !matches!(instructions.get(index + 1), Some((_, Instruction::Return))) =>
+            {
+                return Err(VMError::NotInTailPosition(ip));
+            },
+            _ => {},
+        }
+        let target = match instruction {
+            _ if is_jump(instruction) => jump_target(ip, instruction, operands)?,
+            _ if is_call(instruction) || instruction == Instruction::Try => {
+                Some(u32::from_le_bytes(operands[..4].try_into().unwrap()) as usize)
+            },
+            _ => None,
+        };
+        if let Some(target) = target {
+            if !boundaries.get(target).copied().unwrap_or(false) {
+                return Err(VMError::InvalidJumpTarget(ip));
+            }
+        }
+        if instruction == Instruction::Switch
+            && !switch_targets(operands).all(|target| boundaries.get(target).copied().unwrap_or(false))
+        {
+            return Err(VMError::InvalidJumpTarget(ip));
+        }
+
+        let constant = || constants.get(u16::from_le_bytes([operands[0], operands[1]]) as usize);
+        let valid = match instruction {
+            Instruction::LoadConst => constant().is_some(),
+            Instruction::ReadVarConst | Instruction::WriteVarConst => matches!(constant(), Some(Constant::Ident(_))),
+            _ => true,
+        };
+        if !valid {
+            return Err(VMError::InvalidConstant(ip));
+        }
+
+        match instruction {
+            Instruction::LoadLocal | Instruction::StoreLocal => {
+                locals = locals.max(u16::from_le_bytes([operands[0], operands[1]]) as usize + 1);
+            },
+            Instruction::LoadGlobal | Instruction::StoreGlobal => {
+                globals = globals.max(u16::from_le_bytes([operands[0], operands[1]]) as usize + 1);
+            },
+            Instruction::Closure => {
+                for slot in closure_slots(operands) {
+                    locals = locals.max(slot as usize + 1);
+                }
+            },
+            _ => {},
+        }
+    }
+
+    Ok((locals, globals))
+}
@@ -1,19 +1,102 @@
-use std::sync::mpsc::{Sender, Receiver};
+use num_bigint::BigInt;
+
+use crate::channel::{Receiver, Sender};
+use crate::coroutine::Coroutine;
+use crate::decimal::Decimal;
+use crate::error::VMError;
 
 /// Type that represents a value that can be stored in the stack
 #[derive(Debug)]
 pub enum StackValue {
     /// Primitive value
     Int(i64),
-    /// Channel
+    /// Arbitrary precision integer
+    BigInt(BigInt),
+    /// Fixed point decimal
+    Decimal(Decimal),
+    /// UTF-8 string
+    Str(String),
+    /// Channel, the halves sent to and received from by `SendChannel` and `RecvChannel`.
+    ///
+    /// They are the crate's own `supert::channel()`, not `std::sync::mpsc`, so the receiver can be awaited by
+    /// `interpret_async`. Hosts that built them with `std::sync::mpsc::channel()` call `supert::channel()`
+    /// instead, `send`, `recv`, `recv_timeout` and `try_recv` work the same and fail with the `std::sync::mpsc`
+    /// error types.
     Channel(Sender<i64>, Receiver<i64>),
+    /// Function that can be called with `CallValue`
+    Function(Function),
+    /// Coroutine that can be resumed with `Resume`
+    Coroutine(Coroutine),
 }
 
-impl Into<i64> for StackValue {
-    fn into(self) -> i64 {
+/// Function value created by `Closure`
+#[derive(Debug)]
+pub struct Function {
+    /// Index of the decoded instruction the function starts at, `Program::offset` gives its index in the code
+    pub ip: usize,
+    /// Local slots captured from the function that created it and their values, the function starts with them
+    pub captures: Vec<(u16, StackValue)>,
+}
+
+impl Function {
+    fn try_clone(&self) -> Result<Function, VMError> {
+        let captures = self
+            .captures
+            .iter()
+            .map(|(slot, value)| Ok((*slot, value.try_clone()?)))
+            .collect::<Result<_, VMError>>()?;
+        Ok(Function { ip: self.ip, captures })
+    }
+}
+
+impl StackValue {
+    /// Copy the value, used by `Dup`, `Over` and `Pick`.
+    ///
+    /// Channels are move only: the receiver can not be shared, so copying a channel fails.
+    /// Copies of a coroutine share it.
+    pub fn try_clone(&self) -> Result<StackValue, VMError> {
         match self {
+            StackValue::Int(i) => Ok(StackValue::Int(*i)),
+            StackValue::BigInt(i) => Ok(StackValue::BigInt(i.clone())),
+            StackValue::Decimal(d) => Ok(StackValue::Decimal(*d)),
+            StackValue::Str(s) => Ok(StackValue::Str(s.clone())),
+            StackValue::Channel(_, _) => Err(VMError::ChannelNotCopyable),
+            StackValue::Function(function) => Ok(StackValue::Function(function.try_clone()?)),
+            StackValue::Coroutine(coroutine) => Ok(StackValue::Coroutine(coroutine.clone())),
+        }
+    }
+}
+
+impl From<StackValue> for i64 {
+    fn from(value: StackValue) -> i64 {
+        match value {
             StackValue::Int(i) => i,
+            StackValue::BigInt(_) => panic!("Cannot convert big integer to primitive value"),
+            StackValue::Decimal(_) => panic!("Cannot convert decimal to primitive value"),
+            StackValue::Str(_) => panic!("Cannot convert string to primitive value"),
             StackValue::Channel(_, _) => panic!("Cannot convert channel to primitive value"),
+            StackValue::Function(_) => panic!("Cannot convert function to primitive value"),
+            StackValue::Coroutine(_) => panic!("Cannot convert coroutine to primitive value"),
+        }
+    }
+}
+
+/// Two numeric operands popped from the stack, in the order they were pushed.
+///
+/// If either of the operands is a big integer, the other one is promoted as well.
+pub enum Operands {
+    Int(i64, i64),
+    BigInt(BigInt, BigInt),
+}
+
+impl Operands {
+    pub fn new(first: StackValue, second: StackValue) -> Result<Operands, VMError> {
+        match (first, second) {
+            (StackValue::Int(a), StackValue::Int(b)) => Ok(Operands::Int(a, b)),
+            (StackValue::Int(a), StackValue::BigInt(b)) => Ok(Operands::BigInt(a.into(), b)),
+            (StackValue::BigInt(a), StackValue::Int(b)) => Ok(Operands::BigInt(a, b.into())),
+            (StackValue::BigInt(a), StackValue::BigInt(b)) => Ok(Operands::BigInt(a, b)),
+            _ => Err(VMError::TypeMismatch),
         }
     }
 }
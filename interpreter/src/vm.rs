@@ -1,31 +1,170 @@
 
-use std::sync::mpsc::{Sender, Receiver};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::mpsc::RecvTimeoutError;
+use std::task::{Poll, Waker};
+use std::time::{Duration, Instant};
+use std::sync::Arc;
 use std::{collections::HashMap};
 
-use crate::error::VMError;
-use crate::stack::StackValue;
-use crate::instruction::{ Instruction };
+use num_traits::{ToPrimitive, Zero};
+
+use crate::decimal::Decimal;
+use crate::capability::Capabilities;
+use crate::channel::{Receiver, Sender};
+use crate::error::{Payload, VMError};
+use crate::host::HostFunction;
+use crate::interrupt::{InterruptHandle, Timer};
+#[cfg(feature = "jit")]
+use crate::jit::Cell;
+use crate::op::{fused_len, fused_peak, Op};
+use crate::program::Program;
+use crate::coroutine::{Context, Coroutine};
+use crate::stack::{Function, Operands, StackValue};
 
 /// Maximum stack size: 2^16 - 1
-const MAX_STACK_SIZE: usize = 65535;
+pub(crate) const MAX_STACK_SIZE: usize = 65535;
+
+/// Number of instructions `Interpret` runs before handing the thread back to the executor
+const ASYNC_SLICE: usize = 10_000;
+
+/// How long a blocking `RecvChannel` waits before looking at the interrupt and the deadline again
+const INTERRUPT_POLL: Duration = Duration::from_millis(10);
+
+/// Maximum number of nested function calls
+const MAX_CALL_DEPTH: usize = 1024;
+
+/// Call frame of a function called with `Call`
+#[derive(Debug)]
+pub struct Frame {
+    /// Index of the instruction after the `Call`
+    pub return_ip: usize,
+    /// Local variables of the caller, restored on `Return`
+    pub locals: Vec<Option<StackValue>>,
+}
+
+/// Handler of a `Try` region
+#[derive(Debug)]
+pub struct Handler {
+    /// Index of the first instruction of the handler
+    pub ip: usize,
+    /// Number of values on the stack when the region was entered, the stack is unwound to it
+    pub stack: usize,
+    /// Number of frames when the region was entered, the frames of functions called since are unwound
+    pub frames: usize,
+}
+
+/// Empty local variables of a function frame
+fn new_locals(program: &Program) -> Vec<Option<StackValue>> {
+    (0..program.locals).map(|_| None).collect()
+}
+
+/// Empty variables resolved by `Program::resolve_locals`
+fn new_globals(program: &Program) -> Vec<Option<StackValue>> {
+    (0..program.globals).map(|_| None).collect()
+}
 
 /// Data type that represents a Bytecode interpreter.
 /// 
 /// A program is a sequence of instructions. Interpreter is stack based, rather than register based.
+/// The machine holds the execution state only, the program itself is shared, so many machines can run it at once.
 #[derive(Debug)]
-pub struct Bytecode {
-    /// Instructions bytecode
-    pub instructions: Vec<u8>,
+pub struct Machine {
+    /// Program being executed
+    pub program: Arc<Program>,
     /// Program stack
     pub stack: Vec<StackValue>,
     /// Mapping for local variables
-    pub variables: HashMap<String, i64>,
-    /// Current instruction pointer, points to the next instruction to be executed
-    pub ip: usize
+    pub variables: HashMap<String, StackValue>,
+    /// Variables of the current function accessed with `LoadLocal` and `StoreLocal`, indexed by slot
+    pub locals: Vec<Option<StackValue>>,
+    /// Variables resolved with `Program::resolve_locals` accessed with `LoadGlobal` and `StoreGlobal`, shared
+    /// by every function like `variables`, indexed by slot
+    pub globals: Vec<Option<StackValue>>,
+    /// Current instruction pointer, index of the next decoded instruction to be executed.
+    /// `Program::offset` gives its index in the code.
+    pub ip: usize,
+    /// Operations the program is allowed to perform
+    pub capabilities: Capabilities,
+    /// Frames of the functions currently being called
+    pub frames: Vec<Frame>,
+    /// Handlers of the `Try` regions currently entered, innermost last
+    pub handlers: Vec<Handler>,
+    /// Coroutines currently resumed with the context of their resumer, innermost last
+    resumed: Vec<(Coroutine, Context)>,
+    /// Instructions left to run in `run_for`, `None` runs until the program stops
+    budget: Option<usize>,
+    /// Task of the `Interpret` future being polled, woken when a blocked `RecvChannel` can receive
+    waker: Option<Waker>,
+    /// Point in time after which the machine fails with `VMError::Timeout`, shared with spawned threads
+    pub deadline: Option<Instant>,
+    /// Shared with the handles and spawned threads
+    interrupt: InterruptHandle,
+}
+
+/// Where `Machine::run_for` and `Machine::step` stopped
+#[derive(Debug, PartialEq)]
+pub enum Status {
+    /// Program finished with the value on top of the stack
+    Finished(i64),
+    /// Every instruction of the budget ran, the next call continues where it stopped
+    Yielded,
+    /// `RecvChannel` has nothing to receive yet, the next call tries it again
+    BlockedOnChannel,
+    /// Program failed, handlers of `Try` regions did not catch the error
+    Error(VMError),
+}
+
+/// Future returned by `Machine::interpret_async`
+#[derive(Debug)]
+pub struct Interpret<'a> {
+    machine: &'a mut Machine,
+    /// Started the first time the machine waits on a channel with a deadline
+    timer: Option<Timer>,
+}
+
+impl Future for Interpret<'_> {
+    type Output = Result<i64, VMError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<Self::Output> {
+        let Interpret { machine, timer } = self.get_mut();
+        machine.waker = Some(cx.waker().clone());
+        let status = machine.run_for(ASYNC_SLICE);
+        machine.waker = None;
+        match status {
+            Status::Finished(result) => Poll::Ready(Ok(result)),
+            Status::Error(err) => Poll::Ready(Err(err)),
+            // let other tasks run, then continue
+            Status::Yielded => {
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            },
+            // the channel wakes the task when something is sent, the interrupt handle when it is used and the
+            // timer when the deadline passes
+            Status::BlockedOnChannel => {
+                if let Some(deadline) = machine.deadline {
+                    timer.get_or_insert_with(|| Timer::start(deadline)).register(cx.waker());
+                }
+                machine.interrupt.register(cx.waker());
+                if machine.interrupt.is_interrupted() {
+                    cx.waker().wake_by_ref();
+                }
+                Poll::Pending
+            },
+        }
+    }
+}
+
+/// Why `Machine::dispatch` stopped without an error
+#[derive(Debug, PartialEq)]
+enum Exit {
+    Finished,
+    Yielded,
+    Blocked,
 }
 
 /// Macro for executing native operations
-/// +, -, *, /, %, ==, !=, >, <, >=, <=
+/// &, |, ^
 macro_rules! execute_native {
     ($supert_vm:expr, $opcode:tt) => {{
         match $supert_vm.pop_val() {
@@ -35,38 +174,182 @@ macro_rules! execute_native {
                         $supert_vm.stack.push(StackValue::Int((b $opcode a) as i64));
                         None
                     },
-                    _ => Some(VMError::StackUnderflow),
+                    Err(e) => Some(e),
                 }
             },
-            _ => Some(VMError::StackUnderflow),
+            Err(e) => Some(e),
+        }
+    }}
+}
+
+/// Macro for executing comparisons on primitive and big integers
+/// ==, !=, >, <, >=, <=
+macro_rules! execute_compare {
+    ($supert_vm:expr, $opcode:tt) => {{
+        match $supert_vm.pop_operands() {
+            Ok(Operands::Int(b, a)) => {
+                $supert_vm.stack.push(StackValue::Int((b $opcode a) as i64));
+                None
+            },
+            Ok(Operands::BigInt(b, a)) => {
+                $supert_vm.stack.push(StackValue::Int((b $opcode a) as i64));
+                None
+            },
+            Err(e) => Some(e),
+        }
+    }}
+}
+
+/// Macro for executing checked arithmetic operations
+/// Primitive values use the checked method, big integers the operator
+/// (checked_add, +), (checked_sub, -), (checked_mul, *)
+macro_rules! execute_checked {
+    ($supert_vm:expr, $method:ident, $opcode:tt) => {{
+        match $supert_vm.pop_operands() {
+            Ok(Operands::Int(b, a)) => match b.$method(a) {
+                Some(val) => {
+                    $supert_vm.stack.push(StackValue::Int(val));
+                    None
+                },
+                None => Some(VMError::ArithmeticOverflow),
+            },
+            Ok(Operands::BigInt(b, a)) => {
+                $supert_vm.stack.push(StackValue::BigInt(b $opcode a));
+                None
+            },
+            Err(e) => Some(e),
+        }
+    }}
+}
+
+/// Macro for executing logical operations on truthiness of the operands
+/// &&, ||, ^
+macro_rules! execute_logical {
+    ($supert_vm:expr, $opcode:tt) => {{
+        match ($supert_vm.pop_val(), $supert_vm.pop_val()) {
+            (Ok(a), Ok(b)) => {
+                $supert_vm.stack.push(StackValue::Int(((b != 0) $opcode (a != 0)) as i64));
+                None
+            },
+            (Err(e), _) | (_, Err(e)) => Some(e),
         }
     }}
 }
 
-impl Bytecode {
-    pub fn new(instructions: Vec<u8>) -> Bytecode {
-        Bytecode {
-            instructions,
+/// Shift `value` left by `amount` bits.
+/// Amounts outside of `0..64` shift every bit out and produce 0.
+fn shift_left(value: i64, amount: i64) -> i64 {
+    if (0..64).contains(&amount) {
+        value << amount
+    } else {
+        0
+    }
+}
+
+/// Arithmetic shift of `value` right by `amount` bits.
+/// Amounts outside of `0..64` fill the result with the sign bit, i.e produce 0 or -1.
+fn shift_right(value: i64, amount: i64) -> i64 {
+    if (0..64).contains(&amount) {
+        value >> amount
+    } else if value < 0 {
+        -1
+    } else {
+        0
+    }
+}
+
+impl Machine {
+    pub fn new(program: Arc<Program>) -> Machine {
+        Machine {
+            locals: new_locals(&program),
+            globals: new_globals(&program),
+            program,
             stack: Vec::new(),
             variables: HashMap::new(),
             ip: 0,
+            capabilities: Capabilities::all(),
+            frames: Vec::new(),
+            handlers: Vec::new(),
+            resumed: Vec::new(),
+            budget: None,
+            waker: None,
+            deadline: None,
+            interrupt: InterruptHandle::default(),
+        }
+    }
+
+    /// Clear the execution state, so the program can be run again from the start
+    pub fn reset(&mut self) {
+        self.stack.clear();
+        self.variables.clear();
+        self.locals = new_locals(&self.program);
+        self.globals = new_globals(&self.program);
+        self.frames.clear();
+        self.handlers.clear();
+        self.resumed.clear();
+        self.ip = 0;
+    }
+
+    /// Value of a variable resolved with `Program::resolve_locals`
+    pub fn local(&self, name: &str) -> Option<&StackValue> {
+        self.globals.get(self.program.local_slot(name)?)?.as_ref()
+    }
+
+    /// Handle to stop the machine from another thread, see `InterruptHandle`.
+    ///
+    /// Once a handle exists, or with a `deadline`, the JIT is not used: native code can not be stopped.
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.interrupt.share()
+    }
+
+    /// Fail if the machine was interrupted or its deadline passed, `ip` is the index of the current instruction
+    fn check_interrupt(&self, ip: usize) -> Result<(), VMError> {
+        if self.interrupt.is_interrupted() {
+            return Err(VMError::Interrupted(self.program.offsets[ip]));
+        }
+        if self.deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return Err(VMError::Timeout(self.program.offsets[ip]));
+        }
+        Ok(())
+    }
+
+    /// Error of `RecvChannel` at `ip` on a channel without senders: the error of the thread sending to it if it
+    /// failed, unless the interrupt or deadline it shares with the machine stopped it
+    fn closed(&self, receiver: &Receiver<i64>, ip: usize) -> VMError {
+        match (self.check_interrupt(ip), receiver.error()) {
+            (Err(err), _) => err,
+            (Ok(()), Some(err)) => VMError::ChildFailed(Box::new(err)),
+            (Ok(()), None) => VMError::ChannelClosed,
+        }
+    }
+
+    /// Create a child running the same program from `ip` with the capabilities handed down to children
+    fn spawn_child(&self, ip: usize, stack: Vec<StackValue>) -> Machine {
+        Machine {
+            stack,
+            ip,
+            capabilities: self.capabilities.child(),
+            deadline: self.deadline,
+            interrupt: self.interrupt.clone(),
+            ..Machine::new(self.program.clone())
         }
     }
 
-    /// Get next instruction from the program
-    fn next_instruction(&mut self) -> Option<Instruction> {
-        if self.ip >= self.instructions.len() {
-            return None;
+    /// Pop the arguments of a host function, call it and push its results
+    fn call_native(&mut self, function: &HostFunction) -> Result<(), VMError> {
+        self.capabilities.check_native(&function.name)?;
+        if self.stack.len() < function.args {
+            return Err(VMError::StackUnderflow);
         }
-        let instruction = self.instructions[self.ip];
-        println!("Current ip: {} Instruction: {:?}", self.ip, instruction);
-        self.ip += 1;
-        Some(Instruction::from(instruction))
+        let args = self.stack.split_off(self.stack.len() - function.args);
+        for result in function.call(args)? {
+            self.push_value(result)?;
+        }
+        Ok(())
     }
 
     /// Push a value onto the stack
     fn push_val(&mut self, val: i64) -> Result<(), VMError> {
-        dbg!("Pushing value onto stack");
         if self.stack.len() < MAX_STACK_SIZE {
             self.stack.push(StackValue::Int(val));
             Ok(())
@@ -75,80 +358,109 @@ impl Bytecode {
         }
     }
 
+    /// Push any stack value onto the stack
+    fn push_value(&mut self, value: StackValue) -> Result<(), VMError> {
+        if self.stack.len() < MAX_STACK_SIZE {
+            self.stack.push(value);
+            Ok(())
+        } else {
+            Err(VMError::StackOverflow)
+        }
+    }
+
+    /// Copy the n-th value from the top of the stack to the top
+    fn pick(&mut self, n: usize) -> Result<(), VMError> {
+        if n >= self.stack.len() {
+            return Err(VMError::StackUnderflow);
+        }
+        let value = self.stack[self.stack.len() - 1 - n].try_clone()?;
+        self.push_value(value)
+    }
+
     /// Pop a value from the stack
     fn pop_val(&mut self) -> Result<i64, VMError> {
-        if self.stack.len() > 0 {
-            Ok(self.stack.pop().unwrap().into())
-        } else {
-            Err(VMError::StackUnderflow)
+        match self.stack.pop() {
+            Some(StackValue::Int(val)) => Ok(val),
+            Some(_) => Err(VMError::TypeMismatch),
+            None => Err(VMError::StackUnderflow),
         }
     }
 
-    /// Pop channel
-    fn pop_channel(&mut self) -> Result<(Sender<i64>, Receiver<i64>), VMError> {
-        if self.stack.len() > 0 {
-            match self.stack.pop().unwrap() {
-                StackValue::Channel(sender, receiver) => Ok((sender, receiver)),
-                _ => Err(VMError::StackUnderflow),
-            }
-        } else {
-            Err(VMError::StackUnderflow)
+    /// Pop any value from the stack
+    fn pop_value(&mut self) -> Result<StackValue, VMError> {
+        self.stack.pop().ok_or(VMError::StackUnderflow)
+    }
+
+    /// Pop two numeric values from the stack, second value first
+    fn pop_operands(&mut self) -> Result<Operands, VMError> {
+        let a = self.pop_value()?;
+        let b = self.pop_value()?;
+        Operands::new(b, a)
+    }
+
+    /// Peek the value on top of the stack without popping it
+    fn peek_val(&self) -> Result<i64, VMError> {
+        match self.stack.last() {
+            Some(StackValue::Int(val)) => Ok(*val),
+            Some(_) => Err(VMError::TypeMismatch),
+            None => Err(VMError::StackUnderflow),
         }
     }
 
-    /// Pop sender from the stack
-    /// Use this if you don't want to push the channel back onto the stack
-    fn pop_sender(&mut self) -> Result<Sender<i64>, VMError> {
-        if self.stack.len() > 0 {
-            match self.stack.pop().unwrap() {
-                StackValue::Channel(sender, _receiver) => Ok(sender),
-                _ => Err(VMError::StackUnderflow),
-            }
-        } else {
-            Err(VMError::StackUnderflow)
+    /// Pop two values and push the result of `op(second, top)`
+    fn execute_binary(&mut self, op: fn(i64, i64) -> i64) -> Option<VMError> {
+        match (self.pop_val(), self.pop_val()) {
+            (Ok(a), Ok(b)) => {
+                self.stack.push(StackValue::Int(op(b, a)));
+                None
+            },
+            (Err(e), _) | (_, Err(e)) => Some(e),
+        }
+    }
+
+    /// Pop a decimal from the stack, primitive values are converted with scale 0
+    fn pop_decimal(&mut self) -> Result<Decimal, VMError> {
+        match self.pop_value()? {
+            StackValue::Decimal(val) => Ok(val),
+            StackValue::Int(val) => Ok(val.into()),
+            _ => Err(VMError::TypeMismatch),
         }
     }
 
-    /// Pop receiver from the stack
-    /// Use this if you don't want to push the channel back onto the stack
-    fn pop_receiver(&mut self) -> Result<Receiver<i64>, VMError> {
-        if self.stack.len() > 0 {
+    /// Pop two decimals from the stack, second value first
+    fn pop_decimals(&mut self) -> Result<(Decimal, Decimal), VMError> {
+        let a = self.pop_decimal()?;
+        let b = self.pop_decimal()?;
+        Ok((b, a))
+    }
+
+    /// Pop channel
+    fn pop_channel(&mut self) -> Result<(Sender<i64>, Receiver<i64>), VMError> {
+        if !self.stack.is_empty() {
             match self.stack.pop().unwrap() {
-                StackValue::Channel(_sender, receiver) => Ok(receiver),
-                _ => Err(VMError::StackUnderflow),
+                StackValue::Channel(sender, receiver) => Ok((sender, receiver)),
+                _ => Err(VMError::TypeMismatch),
             }
         } else {
             Err(VMError::StackUnderflow)
         }
     }
 
-    /// Read next string from the program
-    /// Variable names are strictly 4 character long
-    fn read_string(&mut self) -> Result<String, VMError> {
-        let string = self.instructions[self.ip..self.ip + 4]
-            .iter()
-            .map(|&byte| byte as char)
-            .collect::<String>();
-        self.ip += 4;
-        Ok(string)
-    }
-
-    /// Read next byte from the program
-    fn read_byte(&mut self) -> Result<u8, VMError> {
-        let byte = self.instructions[self.ip];
-        self.ip += 1;
-        Ok(byte)
+    /// Pop a value and write it to the variable
+    fn write_var(&mut self, var_name: String) -> Result<(), VMError> {
+        let val = self.pop_value()?;
+        self.variables.insert(var_name, val);
+        Ok(())
     }
 
-    /// Read next long integer from the program
-    fn read_long(&mut self) -> Result<i64, VMError> {
-        match self.instructions[self.ip..self.ip + 8].try_into() {
-            Ok(val) => {
-                println!("Reading long {:?}", val);
-                self.ip += 8;
-                Ok(i64::from_le_bytes(val))
+    /// Push a copy of the variable value
+    fn read_var(&mut self, var_name: &str) -> Result<(), VMError> {
+        match self.variables.get(var_name) {
+            Some(val) => {
+                let val = val.try_clone()?;
+                self.push_value(val)
             },
-            Err(_) => Err(VMError::StackOverflow),
+            _ => Err(VMError::StackUnderflow),
         }
     }
 
@@ -156,151 +468,796 @@ impl Bytecode {
     /// 
     /// Runs insructions one by one.
     pub fn interpret(&mut self) -> Result<i64, VMError> {
-        println!("Instructions: {:?}", self.instructions.clone());
+        self.run()?;
+
+        match self.pop_val() {
+            Ok(result) => Ok(result),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Interprets at most `instructions` instructions, so the host can do other work in between.
+    ///
+    /// Every call continues where the previous one stopped. Fused instructions count as one and the JIT is not
+    /// used. `RecvChannel` does not block, it stops with `Status::BlockedOnChannel` when nothing was sent yet.
+    /// After `Finished` or `Error` the machine has to be `reset` to run the program again.
+    pub fn run_for(&mut self, instructions: usize) -> Status {
+        self.budget = Some(instructions);
+        let exit = self.run();
+        self.budget = None;
+        match exit {
+            Ok(Exit::Finished) => match self.pop_val() {
+                Ok(result) => Status::Finished(result),
+                Err(e) => Status::Error(e),
+            },
+            Ok(Exit::Yielded) => Status::Yielded,
+            Ok(Exit::Blocked) => Status::BlockedOnChannel,
+            Err(e) => Status::Error(e),
+        }
+    }
+
+    /// Interprets the program as a future, for hosts running machines in async tasks.
+    ///
+    /// The future runs a slice of instructions each time it is polled. A `RecvChannel` with nothing to
+    /// receive returns `Poll::Pending` instead of blocking the thread, the task is woken by the next send, the
+    /// interrupt handle or, with a deadline, a timer thread when it passes. Threads started with `Spawn` still
+    /// run on threads of their own.
+    pub fn interpret_async(&mut self) -> Interpret<'_> {
+        Interpret { machine: self, timer: None }
+    }
+
+    /// Interprets a single instruction, see `run_for`
+    pub fn step(&mut self) -> Status {
+        self.run_for(1)
+    }
+
+    /// Call an exported function with the given arguments and return its results.
+    ///
+    /// The stack is reset before the call, variables are kept, so the same program can be called repeatedly.
+    pub fn call(&mut self, name: &str, args: &[StackValue]) -> Result<Vec<StackValue>, VMError> {
+        let export = self.program.exports.get(name).ok_or(VMError::UnknownFunction)?.clone();
+        if args.len() != export.arity {
+            return Err(VMError::ArityMismatch);
+        }
+
+        self.stack.clear();
+        self.frames.clear();
+        self.handlers.clear();
+        self.resumed.clear();
+        self.locals = new_locals(&self.program);
+        for arg in args {
+            let arg = arg.try_clone()?;
+            self.push_value(arg)?;
+        }
+        self.ip = self.program.op_index(export.address).ok_or(VMError::InvalidJumpTarget(export.address))?;
+        self.run()?;
+
+        if self.stack.len() < export.returns {
+            return Err(VMError::StackUnderflow);
+        }
+        Ok(self.stack.split_off(self.stack.len() - export.returns))
+    }
+
+    /// Run the native code compiled for the current instruction, if there is any and everything it uses is a
+    /// primitive integer. It stops before an instruction it can not run, the interpreter goes on from there.
+    /// Native code does not count against the budget or look at the deadline and the interrupt handle, so
+    /// the machine only runs it without them.
+    #[cfg(feature = "jit")]
+    fn run_native(&mut self) {
+        if self.budget.is_some() || self.deadline.is_some() || self.interrupt.is_shared() {
+            return;
+        }
+        let program = self.program.clone();
+        let Some(function) = program.native(self.ip) else {
+            return;
+        };
+        // the function works on the values on top of the stack, pushing past the limit fails in the interpreter
+        let Some(bottom) = self.stack.len().checked_sub(function.depth) else {
+            return;
+        };
+        if bottom + function.max_depth > MAX_STACK_SIZE {
+            return;
+        }
+
+        // depth, cell values, whether each cell is set, then the stack
+        let mut state = function.state();
+        let (cells, base) = (function.cells.len(), function.stack_base());
+        for (index, cell) in function.cells.iter().enumerate() {
+            let value = match cell {
+                Cell::Var(name) => self.variables.get(name.as_ref()),
+                Cell::Local(slot) => self.locals[*slot as usize].as_ref(),
+                Cell::Global(slot) => self.globals[*slot as usize].as_ref(),
+            };
+            match value {
+                Some(StackValue::Int(val)) => {
+                    state[1 + index] = *val;
+                    state[1 + cells + index] = 1;
+                },
+                Some(_) => return,
+                None => {},
+            }
+        }
+        for (index, value) in self.stack[bottom..].iter().enumerate() {
+            match value {
+                StackValue::Int(val) => state[base + index] = *val,
+                _ => return,
+            }
+        }
+
+        self.ip = function.call(&mut state);
+        for (index, cell) in function.cells.iter().enumerate() {
+            if state[1 + cells + index] != 0 {
+                let value = StackValue::Int(state[1 + index]);
+                match cell {
+                    Cell::Var(name) => {
+                        self.variables.insert(name.to_string(), value);
+                    },
+                    Cell::Local(slot) => self.locals[*slot as usize] = Some(value),
+                    Cell::Global(slot) => self.globals[*slot as usize] = Some(value),
+                }
+            }
+        }
+        let depth = state[0] as usize;
+        self.stack.truncate(bottom);
+        self.stack.extend(state[base..base + depth].iter().map(|&val| StackValue::Int(val)));
+    }
+
+    /// Unwind the stack and frames to the handler of the innermost `Try` region and continue there with the
+    /// error on the stack: the thrown value, or the error formatted as a string, followed by `VMError::code`.
+    /// Returns the error if no region is entered.
+    fn catch(&mut self, err: VMError) -> Result<(), VMError> {
+        // the host stopping the machine is not for the program to handle
+        if let VMError::Interrupted(_) | VMError::Timeout(_) = err {
+            return Err(err);
+        }
+        // a coroutine without a handler fails, the error goes on to its resumer
+        let handler = loop {
+            if let Some(handler) = self.handlers.pop() {
+                break handler;
+            }
+            let Some((coroutine, context)) = self.resumed.pop() else {
+                return Err(err);
+            };
+            coroutine.finish();
+            self.switch(context);
+        };
+        if let Some(frame) = self.frames.drain(handler.frames..).next() {
+            self.locals = frame.locals;
+        }
+        self.stack.truncate(handler.stack);
+        let code = err.code();
+        let payload = match err {
+            VMError::Uncaught(Payload::Int(val)) => StackValue::Int(val),
+            VMError::Uncaught(Payload::Str(string)) => StackValue::Str(string),
+            err => StackValue::Str(format!("{:?}", err)),
+        };
+        self.push_value(payload)?;
+        self.push_val(code)?;
+        self.ip = handler.ip;
+        Ok(())
+    }
+
+    /// Runs instructions until `Finish`, `Return` from the outermost function or the end of the program,
+    /// handing errors to the handlers of `Try` regions
+    fn run(&mut self) -> Result<Exit, VMError> {
+        #[cfg(feature = "jit")]
+        self.run_native();
         loop {
-            let current_instruction = self.next_instruction();
-            let instruction_res = match current_instruction {
-                Some(instruction) => {
-                    match instruction {
-                        Instruction::LoadVal => {
-                            let val = self.read_long()?;
-                            println!("LoadVal: {}", val);
-                            self.push_val(val)?;
-                            None
-                        },
-                        Instruction::WriteVar => {
-                            let var_name = self.read_string()?;
-                            println!("Varname {}", var_name);
-                            let val = self.pop_val()?;
-                            println!("Val {}", val);
-                            self.variables.insert(var_name, val);
-                            None
-                        },
-                        Instruction::ReadVar => {
-                            let var_name = self.read_string()?;
-                            match self.variables.get(&var_name) {
-                                Some(val) => {
-                                    println!("Pushing var {}", val);
-                                    self.push_val(*val)?;
-                                    None
-                                },
-                                _ => Some(VMError::StackUnderflow),
-                            }
-                        },
-                        Instruction::FuncCall => {
-                            // first two bytes are start index of the function
-                            let start_ip = ((self.read_byte()? as u16) << 8) | (self.read_byte()? as u16);
-                            
-                            // next byte is the number of arguments
-                            let num_args = self.read_byte()? as usize;
-
-                            // read the arguments and push them onto the stack
-                            for _ in 0..num_args {
-                                let arg = self.read_long()?;
-                                println!("Arg {}", arg);
-                                self.push_val(arg)?;
-                            }
+            match self.dispatch() {
+                Ok(Exit::Finished) if self.resumed.is_empty() => return Ok(Exit::Finished),
+                // the coroutine returned, its resumer continues
+                Ok(Exit::Finished) => {
+                    if let Err(err) = self.finish_coroutine() {
+                        self.catch(err)?;
+                    }
+                },
+                Ok(exit) => return Ok(exit),
+                Err(err) => self.catch(err)?,
+            }
+        }
+    }
+
+    /// Replace the execution state with the context, returning the current one
+    fn switch(&mut self, context: Context) -> Context {
+        Context {
+            stack: std::mem::replace(&mut self.stack, context.stack),
+            locals: std::mem::replace(&mut self.locals, context.locals),
+            frames: std::mem::replace(&mut self.frames, context.frames),
+            handlers: std::mem::replace(&mut self.handlers, context.handlers),
+            ip: std::mem::replace(&mut self.ip, context.ip),
+        }
+    }
 
-                            println!("FuncCall: {} {}", start_ip, num_args);
-                            self.ip = start_ip as usize;
+    /// Hand the value on top of the stack of the innermost coroutine, which returned, to its resumer
+    fn finish_coroutine(&mut self) -> Result<(), VMError> {
+        let value = self.pop_value()?;
+        let (coroutine, context) = self.resumed.pop().ok_or(VMError::YieldOutsideCoroutine)?;
+        coroutine.finish();
+        self.switch(context);
+        self.push_value(value)?;
+        self.push_val(0)
+    }
+
+    /// Runs instructions until `Finish`, `Return` from the outermost function, the end of the program, the end
+    /// of the budget, a `RecvChannel` that would block with a budget or an error
+    fn dispatch(&mut self) -> Result<Exit, VMError> {
+        let program = self.program.clone();
+        while let Some(op) = program.ops.get(self.ip) {
+            if let Some(budget) = &mut self.budget {
+                if *budget == 0 {
+                    return Ok(Exit::Yielded);
+                }
+                *budget -= 1;
+            }
+            let ip = self.ip;
+            self.ip += 1;
+            let instruction_res = match op {
+                Op::LoadVal(val) => {
+                    self.push_val(*val)?;
+                    None
+                },
+                Op::WriteVar(var_name) => {
+                    self.write_var(var_name.to_string())?;
+                    None
+                },
+                Op::ReadVar(var_name) => {
+                    self.read_var(var_name)?;
+                    None
+                },
+                Op::LoadLocal(slot) => {
+                    match &self.locals[*slot as usize] {
+                        Some(val) => {
+                            let val = val.try_clone()?;
+                            self.push_value(val)?;
                             None
                         },
-                        Instruction::ReturnIndex => {
-                            let index = ((self.read_byte()? as u16) << 8) | (self.read_byte()? as u16);
-                            println!("ReturnIndex: {}", index);
-                            self.ip = index as usize;
-                            None
-                        }
-                        Instruction::Jump => {
-                            let offset = self.read_byte()? as usize;
-                            self.ip += offset;
+                        _ => Some(VMError::StackUnderflow),
+                    }
+                },
+                Op::StoreLocal(slot) => {
+                    self.locals[*slot as usize] = Some(self.pop_value()?);
+                    None
+                },
+                Op::LoadGlobal(slot) => {
+                    match &self.globals[*slot as usize] {
+                        Some(val) => {
+                            let val = val.try_clone()?;
+                            self.push_value(val)?;
                             None
                         },
-                        Instruction::JumpBack => {
-                            let offset = self.read_byte()? as usize;
-                            println!("JumpBack {}", offset);
-                            self.ip -= offset;
-                            None
+                        _ => Some(VMError::StackUnderflow),
+                    }
+                },
+                Op::StoreGlobal(slot) => {
+                    self.globals[*slot as usize] = Some(self.pop_value()?);
+                    None
+                },
+                Op::FuncCall(args, target) => {
+                    // push the arguments onto the stack
+                    for arg in args.iter() {
+                        self.push_val(*arg)?;
+                    }
+                    self.ip = *target as usize;
+                    None
+                },
+                Op::ReturnIndex(index) => {
+                    self.ip = index.ok_or(VMError::InvalidJumpTarget(program.offsets[self.ip - 1]))?;
+                    None
+                }
+                Op::Jump(target) => {
+                    self.ip = *target;
+                    None
+                },
+                Op::JumpIfFalse(target) => {
+                    let val = self.pop_val()?;
+                    if val == 0 {
+                        self.ip = *target;
+                    }
+                    None
+                },
+                Op::JumpIfTrue(target) => {
+                    let val = self.pop_val()?;
+                    if val != 0 {
+                        self.ip = *target;
+                    }
+                    None
+                },
+                Op::JumpIfFalseOrPop(target) => {
+                    if self.peek_val()? == 0 {
+                        self.ip = *target;
+                    } else {
+                        self.pop_val()?;
+                    }
+                    None
+                },
+                Op::JumpIfTrueOrPop(target) => {
+                    if self.peek_val()? != 0 {
+                        self.ip = *target;
+                    } else {
+                        self.pop_val()?;
+                    }
+                    None
+                },
+                Op::Dup => {
+                    self.pick(0)?;
+                    None
+                },
+                Op::Over => {
+                    self.pick(1)?;
+                    None
+                },
+                Op::Pick(n) => {
+                    self.pick(*n as usize)?;
+                    None
+                },
+                Op::Swap => {
+                    let len = self.stack.len();
+                    if len < 2 {
+                        return Err(VMError::StackUnderflow);
+                    }
+                    self.stack.swap(len - 1, len - 2);
+                    None
+                },
+                Op::Rot => {
+                    let len = self.stack.len();
+                    if len < 3 {
+                        return Err(VMError::StackUnderflow);
+                    }
+                    self.stack[len - 3..].rotate_left(1);
+                    None
+                },
+                Op::Drop => {
+                    match self.stack.pop() {
+                        Some(_) => None,
+                        None => Some(VMError::StackUnderflow),
+                    }
+                },
+                Op::Add => execute_checked!(self, checked_add, +),
+                Op::Sub => execute_checked!(self, checked_sub, -),
+                Op::Mul => execute_checked!(self, checked_mul, *),
+                Op::Div => {
+                    match self.pop_operands()? {
+                        Operands::Int(b, a) => {
+                            if b == 0 {
+                                Some(VMError::DivisionByZero)
+                            } else if let Some(val) = a.checked_div(b) {
+                                self.stack.push(StackValue::Int(val));
+                                None
+                            } else {
+                                Some(VMError::ArithmeticOverflow)
+                            }
                         },
-                        Instruction::JumpIfFalse => {
-                            let offset = self.read_byte()? as usize;
-                            let val = self.pop_val()?;
-                            println!("JumpIfFalse: {}", val);
-                            if val == 0 {
-                                self.ip += offset;
+                        Operands::BigInt(b, a) => {
+                            if b.is_zero() {
+                                Some(VMError::DivisionByZero)
+                            } else {
+                                self.stack.push(StackValue::BigInt(a / b));
+                                None
                             }
-                            None
                         },
-                        Instruction::JumpIfTrue => {
-                            let offset = self.read_byte()? as usize;
-                            let val = self.pop_val()?;
-                            if val != 0 {
-                                self.ip += offset;
+                    }
+                },
+                Op::Mod => {
+                    match self.pop_operands()? {
+                        Operands::Int(b, a) => {
+                            if a == 0 {
+                                Some(VMError::DivisionByZero)
+                            } else if let Some(val) = b.checked_rem(a) {
+                                self.stack.push(StackValue::Int(val));
+                                None
+                            } else {
+                                Some(VMError::ArithmeticOverflow)
                             }
-                            None
                         },
-                        Instruction::Add => execute_native!(self, +),
-                        Instruction::Sub => execute_native!(self, -),
-                        Instruction::Mul => execute_native!(self, *),
-                        Instruction::Div => {
-                            if let (Ok(a), Ok(b)) = (self.pop_val(), self.pop_val()) {
-                                if b == 0 {
-                                    Some(VMError::DivisionByZero)
-                                } else {
-                                    self.stack.push(StackValue::Int(a / b));
-                                    None
-                                }
+                        Operands::BigInt(b, a) => {
+                            if a.is_zero() {
+                                Some(VMError::DivisionByZero)
                             } else {
-                                Some(VMError::StackUnderflow)
+                                self.stack.push(StackValue::BigInt(b % a));
+                                None
                             }
                         },
-                        Instruction::Mod => execute_native!(self, %),
-                        Instruction::Eq => execute_native!(self, ==),
-                        Instruction::NotEq => execute_native!(self, !=),
-                        Instruction::Lt => execute_native!(self, <),
-                        Instruction::Gt => execute_native!(self, >),
-                        Instruction::Lte => execute_native!(self, <=),
-                        Instruction::Gte => execute_native!(self, >=),
-                        Instruction::SendChannel => {
-                            let value = self.pop_val()?;
-                            let (sender, receiver) = self.pop_channel()?;
-                            match sender.send(value) {
-                                Ok(_) => {},
-                                Err(e) => println!("Error sending: {}", e),
-                            };
-                            // push the channel back onto the stack
-                            // so it can be used again
-                            self.stack.push(StackValue::Channel(sender, receiver));
-                            None
+                    }
+                },
+                Op::LoadBig(val) => {
+                    self.push_value(StackValue::BigInt(val.as_ref().clone()))?;
+                    None
+                },
+                Op::ToBig => {
+                    match self.pop_value()? {
+                        StackValue::Int(val) => self.push_value(StackValue::BigInt(val.into()))?,
+                        val @ StackValue::BigInt(_) => self.push_value(val)?,
+                        _ => return Err(VMError::TypeMismatch),
+                    }
+                    None
+                },
+                Op::ToInt => {
+                    match self.pop_value()? {
+                        StackValue::BigInt(val) => match val.to_i64() {
+                            Some(val) => self.push_val(val)?,
+                            None => return Err(VMError::ArithmeticOverflow),
+                        },
+                        val @ StackValue::Int(_) => self.push_value(val)?,
+                        _ => return Err(VMError::TypeMismatch),
+                    }
+                    None
+                },
+                Op::WrappingAdd => self.execute_binary(i64::wrapping_add),
+                Op::WrappingSub => self.execute_binary(i64::wrapping_sub),
+                Op::WrappingMul => self.execute_binary(i64::wrapping_mul),
+                Op::SaturatingAdd => self.execute_binary(i64::saturating_add),
+                Op::SaturatingSub => self.execute_binary(i64::saturating_sub),
+                Op::SaturatingMul => self.execute_binary(i64::saturating_mul),
+                Op::Eq => execute_compare!(self, ==),
+                Op::NotEq => execute_compare!(self, !=),
+                Op::Lt => execute_compare!(self, <),
+                Op::Gt => execute_compare!(self, >),
+                Op::Lte => execute_compare!(self, <=),
+                Op::Gte => execute_compare!(self, >=),
+                Op::And => execute_logical!(self, &&),
+                Op::Or => execute_logical!(self, ||),
+                Op::Xor => execute_logical!(self, ^),
+                Op::Not => {
+                    let val = self.pop_val()?;
+                    self.push_val((val == 0) as i64)?;
+                    None
+                },
+                Op::Shl => self.execute_binary(shift_left),
+                Op::Shr => self.execute_binary(shift_right),
+                Op::BitAnd => execute_native!(self, &),
+                Op::BitOr => execute_native!(self, |),
+                Op::BitXor => execute_native!(self, ^),
+                Op::Neg => {
+                    match self.pop_value()? {
+                        StackValue::Int(val) => match val.checked_neg() {
+                            Some(val) => {
+                                self.push_val(val)?;
+                                None
+                            },
+                            None => Some(VMError::ArithmeticOverflow),
                         },
-                        Instruction::RecvChannel => {
-                            let (sender, receiver) = self.pop_channel()?;
-                            let value = receiver.recv().unwrap();
-                            // push the channel back onto the stack
-                            // so it can be used again
-                            self.stack.push(StackValue::Channel(sender, receiver));
-                            self.push_val(value)?;
+                        StackValue::BigInt(val) => {
+                            self.push_value(StackValue::BigInt(-val))?;
                             None
                         },
-                        Instruction::Spawn => {
-                            unimplemented!()
+                        _ => Some(VMError::TypeMismatch),
+                    }
+                },
+                Op::LoadDec(mantissa, scale) => {
+                    self.push_value(StackValue::Decimal(Decimal::new(*mantissa as i128, *scale)?))?;
+                    None
+                },
+                Op::ToDec => {
+                    let val = self.pop_decimal()?;
+                    self.push_value(StackValue::Decimal(val))?;
+                    None
+                },
+                Op::DecToInt(mode) => {
+                    let val = self.pop_decimal()?;
+                    self.push_val(val.to_i64(*mode)?)?;
+                    None
+                },
+                Op::DecAdd => {
+                    let (b, a) = self.pop_decimals()?;
+                    self.push_value(StackValue::Decimal(b.checked_add(&a)?))?;
+                    None
+                },
+                Op::DecSub => {
+                    let (b, a) = self.pop_decimals()?;
+                    self.push_value(StackValue::Decimal(b.checked_sub(&a)?))?;
+                    None
+                },
+                Op::DecMul(scale, mode) => {
+                    let (b, a) = self.pop_decimals()?;
+                    self.push_value(StackValue::Decimal(b.checked_mul(&a, *scale, *mode)?))?;
+                    None
+                },
+                Op::DecDiv(scale, mode) => {
+                    let (b, a) = self.pop_decimals()?;
+                    self.push_value(StackValue::Decimal(b.checked_div(&a, *scale, *mode)?))?;
+                    None
+                },
+                Op::DecRescale(scale, mode) => {
+                    let val = self.pop_decimal()?;
+                    self.push_value(StackValue::Decimal(val.rescale(*scale, *mode)?))?;
+                    None
+                },
+                Op::DecCmp => {
+                    let (b, a) = self.pop_decimals()?;
+                    self.push_val(b.cmp(&a) as i64)?;
+                    None
+                },
+                Op::LoadStr(string) => {
+                    self.push_value(StackValue::Str(string.to_string()))?;
+                    None
+                },
+                Op::DecToStr => {
+                    let val = self.pop_decimal()?;
+                    self.push_value(StackValue::Str(val.to_string()))?;
+                    None
+                },
+                Op::StrToDec => {
+                    match self.pop_value()? {
+                        StackValue::Str(string) => {
+                            self.push_value(StackValue::Decimal(string.parse()?))?;
+                            None
                         },
-                        Instruction::Finish => break,
+                        _ => Some(VMError::TypeMismatch),
                     }
                 },
-                None => break,
-            };
-
-            // If instruction fails to execute, return error.
-            if let Some(err) = instruction_res {
-                return Err(err);
-            }
-        }
-
-        match self.pop_val() {
-            Ok(result) => Ok(result),
-            Err(e) => Err(e),
+                Op::CallNative(id) => {
+                    self.call_native(program.host_functions.get(*id)?)?;
+                    None
+                },
+                Op::CallNativeNamed(name) => {
+                    self.call_native(program.host_functions.get_by_name(name)?)?;
+                    None
+                },
+                Op::SendChannel => {
+                    self.capabilities.check_send()?;
+                    let value = self.pop_val()?;
+                    let (sender, receiver) = self.pop_channel()?;
+                    // the receiver is gone, e.g. a host task or a spawned thread that finished
+                    sender.send(value).map_err(|_| VMError::ChannelClosed)?;
+                    // push the channel back onto the stack
+                    // so it can be used again
+                    self.stack.push(StackValue::Channel(sender, receiver));
+                    None
+                },
+                Op::RecvChannel => {
+                    self.capabilities.check_recv()?;
+                    let (sender, receiver) = self.pop_channel()?;
+                    let value = match self.budget {
+                        None if self.deadline.is_none() && !self.interrupt.is_shared() => {
+                            receiver.recv().map_err(|_| self.closed(&receiver, ip))?
+                        },
+                        None => loop {
+                            match receiver.recv_timeout(INTERRUPT_POLL) {
+                                Ok(value) => break value,
+                                Err(RecvTimeoutError::Timeout) => {
+                                    if let Err(err) = self.check_interrupt(ip) {
+                                        // waits again when run after the interrupt is cleared
+                                        self.stack.push(StackValue::Channel(sender, receiver));
+                                        self.ip = ip;
+                                        return Err(err);
+                                    }
+                                },
+                                Err(RecvTimeoutError::Disconnected) => return Err(self.closed(&receiver, ip)),
+                            }
+                        },
+                        Some(_) => {
+                            let waker = self.waker.as_ref().unwrap_or(Waker::noop());
+                            match receiver.poll_recv(&mut std::task::Context::from_waker(waker)) {
+                                Poll::Ready(value) => value.map_err(|_| self.closed(&receiver, ip))?,
+                                Poll::Pending => {
+                                    // run it again on the next call
+                                    self.stack.push(StackValue::Channel(sender, receiver));
+                                    self.ip = ip;
+                                    self.check_interrupt(ip)?;
+                                    return Ok(Exit::Blocked);
+                                },
+                            }
+                        },
+                    };
+                    // push the channel back onto the stack
+                    // so it can be used again
+                    self.stack.push(StackValue::Channel(sender, receiver));
+                    self.push_val(value)?;
+                    None
+                },
+                Op::Spawn(start_ip) => {
+                    self.capabilities.check_spawn()?;
+                    // two queues crossed, so each side receives what the other sends
+                    let (parent_sender, child_receiver) = crate::channel::channel();
+                    let (child_sender, parent_receiver) = crate::channel::channel();
+                    // the thread keeps the channel to the parent open until it recorded how the child stopped
+                    let failure = child_sender.clone();
+                    let mut child = self.spawn_child(*start_ip, vec![StackValue::Channel(child_sender, child_receiver)]);
+                    std::thread::spawn(move || {
+                        if let Err(err) = child.interpret() {
+                            failure.fail(err);
+                        }
+                    });
+                    self.push_value(StackValue::Channel(parent_sender, parent_receiver))?;
+                    None
+                },
+                Op::Call(address) => {
+                    if self.frames.len() >= MAX_CALL_DEPTH {
+                        return Err(VMError::StackOverflow);
+                    }
+                    let locals = std::mem::replace(&mut self.locals, new_locals(&self.program));
+                    self.frames.push(Frame { return_ip: self.ip, locals });
+                    self.ip = *address;
+                    None
+                },
+                Op::Closure(address, slots) => {
+                    let mut captures = Vec::with_capacity(slots.len());
+                    for slot in slots.iter() {
+                        if let Some(val) = &self.locals[*slot as usize] {
+                            captures.push((*slot, val.try_clone()?));
+                        }
+                    }
+                    self.push_value(StackValue::Function(Function { ip: *address as usize, captures }))?;
+                    None
+                },
+                Op::CallValue => {
+                    let function = match self.pop_value()? {
+                        StackValue::Function(function) => function,
+                        _ => return Err(VMError::TypeMismatch),
+                    };
+                    if self.frames.len() >= MAX_CALL_DEPTH {
+                        return Err(VMError::StackOverflow);
+                    }
+                    let mut locals = new_locals(&self.program);
+                    for (slot, val) in function.captures {
+                        locals[slot as usize] = Some(val);
+                    }
+                    let locals = std::mem::replace(&mut self.locals, locals);
+                    self.frames.push(Frame { return_ip: self.ip, locals });
+                    self.ip = function.ip;
+                    None
+                },
+                Op::Coroutine(address) => {
+                    let context = Context {
+                        stack: Vec::new(),
+                        locals: new_locals(&self.program),
+                        frames: Vec::new(),
+                        handlers: Vec::new(),
+                        ip: *address,
+                    };
+                    self.push_value(StackValue::Coroutine(Coroutine::new(context)))?;
+                    None
+                },
+                Op::Resume => {
+                    let coroutine = match self.pop_value()? {
+                        StackValue::Coroutine(coroutine) => coroutine,
+                        _ => return Err(VMError::TypeMismatch),
+                    };
+                    let value = self.pop_value()?;
+                    if self.resumed.len() >= MAX_CALL_DEPTH {
+                        return Err(VMError::StackOverflow);
+                    }
+                    let context = self.switch(coroutine.resume()?);
+                    self.resumed.push((coroutine, context));
+                    self.push_value(value)?;
+                    None
+                },
+                Op::Yield => {
+                    let value = self.pop_value()?;
+                    let (coroutine, context) = self.resumed.pop().ok_or(VMError::YieldOutsideCoroutine)?;
+                    coroutine.suspend(self.switch(context));
+                    self.push_value(value)?;
+                    self.push_val(1)?;
+                    None
+                },
+                Op::TailCall(address) => {
+                    // the frame is reused, a region of the current function entered by jumping past the
+                    // `EndTry` the verifier saw would lose its handler
+                    if self.handlers.last().is_some_and(|handler| handler.frames >= self.frames.len()) {
+                        Some(VMError::NotInTailPosition(program.offsets[ip]))
+                    } else {
+                        self.locals.iter_mut().for_each(|local| *local = None);
+                        self.ip = *address;
+                        None
+                    }
+                },
+                Op::Return => {
+                    match self.frames.pop() {
+                        Some(frame) => {
+                            self.ip = frame.return_ip;
+                            self.locals = frame.locals;
+                            // regions of the function that are still entered end with it
+                            while self.handlers.last().is_some_and(|handler| handler.frames > self.frames.len()) {
+                                self.handlers.pop();
+                            }
+                            None
+                        },
+                        // returning from the outermost function finishes the program
+                        None => break,
+                    }
+                },
+                Op::Finish => break,
+                Op::Nop => None,
+                Op::Try(handler) => {
+                    self.handlers.push(Handler { ip: *handler, stack: self.stack.len(), frames: self.frames.len() });
+                    None
+                },
+                Op::EndTry => {
+                    match self.handlers.last() {
+                        Some(handler) if handler.frames == self.frames.len() => {
+                            self.handlers.pop();
+                            None
+                        },
+                        _ => Some(VMError::UnbalancedBlock(program.offsets[self.ip - 1])),
+                    }
+                },
+                Op::Throw => {
+                    let payload = match self.pop_value()? {
+                        StackValue::Int(val) => Payload::Int(val),
+                        StackValue::Str(string) => Payload::Str(string),
+                        _ => return Err(VMError::TypeMismatch),
+                    };
+                    Some(VMError::Uncaught(payload))
+                },
+                Op::Switch(targets, default) => {
+                    let val = self.pop_val()?;
+                    let target = usize::try_from(val).ok().and_then(|val| targets.get(val)).unwrap_or(default);
+                    self.ip = *target as usize;
+                    None
+                },
+                Op::AddVar(var_name, n) => {
+                    let fits = self.stack.len() + fused_peak(op) <= MAX_STACK_SIZE;
+                    match self.variables.get_mut(var_name.as_ref()) {
+                        Some(StackValue::Int(val)) if fits => {
+                            *val = val.checked_add(*n as i64).ok_or(VMError::ArithmeticOverflow)?;
+                            self.ip += fused_len(op) - 1;
+                        },
+                        _ => self.read_var(var_name)?,
+                    }
+                    None
+                },
+                Op::AddLocal(slot, n) => {
+                    let fits = self.stack.len() + fused_peak(op) <= MAX_STACK_SIZE;
+                    match &mut self.locals[*slot as usize] {
+                        Some(StackValue::Int(val)) if fits => {
+                            *val = val.checked_add(*n as i64).ok_or(VMError::ArithmeticOverflow)?;
+                            self.ip += fused_len(op) - 1;
+                        },
+                        Some(val) => {
+                            let val = val.try_clone()?;
+                            self.push_value(val)?;
+                        },
+                        None => return Err(VMError::StackUnderflow),
+                    }
+                    None
+                },
+                Op::AddGlobal(slot, n) => {
+                    let fits = self.stack.len() + fused_peak(op) <= MAX_STACK_SIZE;
+                    match &mut self.globals[*slot as usize] {
+                        Some(StackValue::Int(val)) if fits => {
+                            *val = val.checked_add(*n as i64).ok_or(VMError::ArithmeticOverflow)?;
+                            self.ip += fused_len(op) - 1;
+                        },
+                        Some(val) => {
+                            let val = val.try_clone()?;
+                            self.push_value(val)?;
+                        },
+                        None => return Err(VMError::StackUnderflow),
+                    }
+                    None
+                },
+                Op::CompareJumpIfFalse(comparison, n, target) => {
+                    let fits = self.stack.len() + fused_peak(op) <= MAX_STACK_SIZE;
+                    match self.stack.last() {
+                        Some(StackValue::Int(val)) if fits => {
+                            let val = *val;
+                            self.stack.pop();
+                            if comparison.test(val, *n) {
+                                self.ip += fused_len(op) - 1;
+                            } else {
+                                self.ip = *target as usize;
+                            }
+                        },
+                        _ => self.push_val(*n)?,
+                    }
+                    None
+                },
+            };
+
+            // If instruction fails to execute, return error.
+            if let Some(err) = instruction_res {
+                return Err(err);
+            }
+            // every loop goes through a backward jump or a call
+            if self.ip <= ip || matches!(op, Op::Call(_) | Op::CallValue | Op::TailCall(_)) {
+                self.check_interrupt(ip)?;
+                // loops and functions may be compiled
+                #[cfg(feature = "jit")]
+                self.run_native();
+            }
         }
+
+        Ok(Exit::Finished)
     }
 }
 
@@ -308,6 +1265,29 @@ impl Bytecode {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use num_bigint::BigInt;
+    use crate::assembler::Assembler;
+    use crate::decimal::RoundingMode;
+    use crate::error::Payload;
+    use crate::instruction::Instruction;
+    use crate::op::Comparison;
+    use crate::program::{Constant, Export};
+    use crate::module::{CustomSection, LineEntry, Module};
+
+    /// Load a program and create a machine to run it
+    fn machine(code: Vec<u8>) -> Machine {
+        Machine::new(Arc::new(Program::new(code).unwrap()))
+    }
+
+    /// Interpret the code with and without fused instructions, checking both give the same result
+    fn interpret_both_ways(code: Vec<u8>) -> Result<i64, VMError> {
+        let fused = machine(code.clone()).interpret();
+        let mut program = Program::new(code).unwrap();
+        program.set_fusion(false).unwrap();
+        let unfused = Machine::new(Arc::new(program)).interpret();
+        assert_eq!(fused, unfused);
+        fused
+    }
 
     #[test]
     fn test_arithmetic() {
@@ -315,7 +1295,7 @@ mod tests {
         // let x = 1
         // let y = 2
         // return (x + 1) * y
-        let mut vm = Bytecode::new(vec![
+        let mut vm = machine(vec![
             Instruction::LoadVal.into(), 0x01, 0, 0, 0, 0, 0, 0, 0,
             Instruction::WriteVar.into(), 0x78, 0x00, 0x00, 0x00,
             Instruction::LoadVal.into(), 0x02, 0, 0, 0, 0, 0, 0, 0,
@@ -335,7 +1315,7 @@ mod tests {
         // let y = 8
         // let z = x * y
         // z / 2
-        let mut vm_1 = Bytecode::new(vec![
+        let mut vm_1 = machine(vec![
             Instruction::LoadVal.into(), 0x05, 0, 0, 0, 0, 0, 0, 0,
             Instruction::WriteVar.into(), 0x78, 0x00, 0x00, 0x00,
             Instruction::LoadVal.into(), 0x08, 0, 0, 0, 0, 0, 0, 0,
@@ -355,7 +1335,7 @@ mod tests {
 
     #[test]
     fn test_zero_division() {
-        let mut vm = Bytecode::new(vec![
+        let mut vm = machine(vec![
             Instruction::LoadVal.into(), 0x00, 0, 0, 0, 0, 0, 0, 0,
             Instruction::LoadVal.into(), 0x01, 0, 0, 0, 0, 0, 0, 0,
             Instruction::Div.into(),
@@ -373,7 +1353,7 @@ mod tests {
         // while test < 10:
         //    test += 1
         // i
-        let mut vm = Bytecode::new(vec![
+        let code = vec![
             Instruction::LoadVal.into(), 0x01, 0, 0, 0, 0, 0, 0, 0, // 1
             Instruction::LoadVal.into(), 0x05, 0, 0, 0, 0, 0, 0, 0, // 5
             Instruction::Add.into(), // 6
@@ -389,9 +1369,9 @@ mod tests {
             Instruction::JumpBack.into(), 0x27, // Jump back to loop condition
             Instruction::ReadVar.into(), 74, 65, 73, 74, // "test"
             Instruction::Finish.into(), 
-        ]);
+        ];
 
-        assert_eq!(vm.interpret().unwrap(), 10);
+        assert_eq!(interpret_both_ways(code).unwrap(), 10);
     }
 
     #[test]
@@ -401,7 +1381,7 @@ mod tests {
         // for temp in 1..11:
         //    test += temp * temp
         // test => 385
-        let mut vm = Bytecode::new(vec![
+        let code = vec![
             Instruction::LoadVal.into(), 0, 0, 0, 0, 0, 0, 0, 0,
             Instruction::WriteVar.into(), 0x74, 0x65, 0x73, 0x74, // "test"
             Instruction::LoadVal.into(), 0x01, 0, 0, 0, 0, 0, 0, 0,
@@ -423,98 +1403,1920 @@ mod tests {
             Instruction::JumpBack.into(), 61, // Jump back to the start of the loop (condition)
             Instruction::ReadVar.into(), 0x74, 0x65, 0x73, 0x74, 
             Instruction::Finish.into(),
-        ]);
+        ];
 
-        assert_eq!(vm.interpret().unwrap(), 385);
+        assert_eq!(interpret_both_ways(code).unwrap(), 385);
     }
 
     #[test]
-    fn test_more_loop() {
-        let mut vm = Bytecode::new(vec![
-            Instruction::LoadVal.into(), 0x01, 0, 0, 0, 0, 0, 0, 0, // 1
-            Instruction::LoadVal.into(), 0x05, 0, 0, 0, 0, 0, 0, 0, // 5
-            Instruction::Add.into(), // 6
-            Instruction::WriteVar.into(), 74, 65, 73, 74, // "test"
-            Instruction::ReadVar.into(), 74, 65, 73, 74, // "test"
-            Instruction::LoadVal.into(), 0x64, 0, 0, 0, 0, 0, 0, 0, // 100
-            Instruction::Lt.into(), // test < 100
-            Instruction::JumpIfFalse.into(), 0x16, // Skip to instruction after `JumpBack` if false
-            Instruction::ReadVar.into(), 74, 65, 73, 74, // "test"
-            Instruction::LoadVal.into(), 0x01, 0, 0, 0, 0, 0, 0, 0, // 1
-            Instruction::Add.into(), // test += 1
-            Instruction::WriteVar.into(), 74, 65, 73, 74, // "test"
-            Instruction::JumpBack.into(), 0x27, // Jump back to loop condition
-            Instruction::ReadVar.into(), 74, 65, 73, 74, // "test"
-            Instruction::Finish.into(), 
-        ]);
+    fn test_decoded_instructions() {
+        assert!(std::mem::size_of::<Op>() <= 24);
+
+        let program = Program::new(vec![
+            Instruction::LoadVal.into(), 0x01, 0, 0, 0, 0, 0, 0, 0,
+            Instruction::Dup.into(),
+            Instruction::JumpIfTrue.into(), 0x0E,
+            Instruction::LoadVal.into(), 0x02, 0, 0, 0, 0, 0, 0, 0,
+            Instruction::ReturnIndex.into(), 0x04, 0x00, 0x00, 0x00, // into the middle of `LoadVal`
+            Instruction::Finish.into(),
+        ]).unwrap();
+        assert!(matches!(
+            program.ops[..],
+            [Op::LoadVal(1), Op::Dup, Op::JumpIfTrue(5), Op::LoadVal(2), Op::ReturnIndex(None), Op::Finish]
+        ));
+        assert_eq!((program.offset(4), program.offset(6)), (Some(21), Some(27)));
+
+        let mut vm = Machine::new(Arc::new(program));
+        assert_eq!(vm.interpret().unwrap(), 1);
+        vm.reset();
+        vm.ip = 3;
+        assert_eq!(vm.interpret().unwrap_err(), VMError::InvalidJumpTarget(21));
 
-        assert_eq!(vm.interpret().unwrap(), 100);
+        // operands are parsed when the program is loaded
+        assert_eq!(Program::new(vec![Instruction::DecToInt.into(), 0x07]).unwrap_err(), VMError::InvalidOperand);
+        assert_eq!(Program::new(vec![Instruction::LoadStr.into(), 0x01, 0xFF]).unwrap_err(), VMError::InvalidOperand);
+        assert_eq!(Program::new(vec![Instruction::CallNativeNamed.into(), 0x01, 0xFF]).unwrap_err(), VMError::InvalidOperand);
     }
 
     #[test]
-    fn test_channel() {
-        let instructions = vec![
+    fn test_fusion() {
+        // while n < 10 { n += 3 }, with a jump into the middle of `n += 3`
+        let code = vec![
             Instruction::LoadVal.into(), 0x01, 0, 0, 0, 0, 0, 0, 0,
-            Instruction::SendChannel.into(),
-            Instruction::RecvChannel.into(),
+            Instruction::WriteVar.into(), b'n', 0, 0, 0,
+            Instruction::ReadVar.into(), b'n', 0, 0, 0,
+            Instruction::LoadVal.into(), 0x0A, 0, 0, 0, 0, 0, 0, 0,
+            Instruction::Lt.into(),
+            Instruction::JumpIfFalse.into(), 0x16,
+            Instruction::ReadVar.into(), b'n', 0, 0, 0,
+            Instruction::LoadVal.into(), 0x03, 0, 0, 0, 0, 0, 0, 0,
+            Instruction::Add.into(),
+            Instruction::WriteVar.into(), b'n', 0, 0, 0,
+            Instruction::JumpBack.into(), 0x27,
+            Instruction::ReadVar.into(), b'n', 0, 0, 0,
             Instruction::Finish.into(),
         ];
+        let program = Program::new(code.clone()).unwrap();
+        assert!(matches!(program.ops[3], Op::CompareJumpIfFalse(Comparison::Lt, 10, 11)));
+        assert!(matches!(program.ops[6], Op::AddVar(ref name, 3) if name.as_ref() == "n"));
+        assert_eq!(interpret_both_ways(code.clone()).unwrap(), 10);
 
-        let (sender, receiver): (Sender<i64>, Receiver<i64>) = std::sync::mpsc::channel();
+        let mut resolved = Program::new(code.clone()).unwrap();
+        resolved.resolve_locals().unwrap();
+        assert!(matches!(resolved.ops[6], Op::AddGlobal(0, 3)));
+        assert_eq!(Machine::new(Arc::new(resolved)).interpret().unwrap(), 10);
 
-        let mut vm = Bytecode {
-            instructions,
-            stack: vec![StackValue::Channel(sender, receiver)],
-            ip: 0,
-            variables: HashMap::new(),
-        };
+        // entering at `LoadVal 3` with a value on the stack runs the original instructions, 5 + 3 then 8 + 3
+        let mut vm = machine(code.clone());
+        vm.variables.insert("n".to_string(), StackValue::Int(20));
+        vm.stack.push(StackValue::Int(5));
+        vm.ip = 7;
+        assert_eq!(vm.interpret().unwrap(), 11);
 
-        assert_eq!(vm.interpret().unwrap(), 1);
+        // big integers and overflow take the same way as the original instructions
+        let mut big = vec![
+            Instruction::LoadVal.into(), 0x01, 0, 0, 0, 0, 0, 0, 0,
+            Instruction::ToBig.into(),
+            Instruction::WriteVar.into(), b'n', 0, 0, 0,
+            Instruction::ReadVar.into(), b'n', 0, 0, 0,
+            Instruction::LoadVal.into(), 0x02, 0, 0, 0, 0, 0, 0, 0,
+            Instruction::Add.into(),
+            Instruction::WriteVar.into(), b'n', 0, 0, 0,
+            Instruction::ReadVar.into(), b'n', 0, 0, 0,
+            Instruction::LoadVal.into(), 0x03, 0, 0, 0, 0, 0, 0, 0,
+            Instruction::Eq.into(),
+            Instruction::JumpIfFalse.into(), 0x00,
+            Instruction::LoadVal.into(), 0x01, 0, 0, 0, 0, 0, 0, 0,
+            Instruction::Finish.into(),
+        ];
+        assert_eq!(interpret_both_ways(big.clone()).unwrap(), 1);
+        big[1..9].copy_from_slice(&i64::MAX.to_le_bytes());
+        big.remove(9);
+        assert_eq!(interpret_both_ways(big).unwrap_err(), VMError::ArithmeticOverflow);
+
+        // so does a stack without room for the values the original instructions push, n += 3 on its own
+        // and the loop, whose comparison pushes 10
+        let add = vec![
+            Instruction::LoadVal.into(), 0x01, 0, 0, 0, 0, 0, 0, 0,
+            Instruction::WriteVar.into(), b'n', 0, 0, 0,
+            Instruction::ReadVar.into(), b'n', 0, 0, 0,
+            Instruction::LoadVal.into(), 0x03, 0, 0, 0, 0, 0, 0, 0,
+            Instruction::Add.into(),
+            Instruction::WriteVar.into(), b'n', 0, 0, 0,
+            Instruction::ReadVar.into(), b'n', 0, 0, 0,
+            Instruction::Finish.into(),
+        ];
+        for code in [add, code] {
+            for (fusion, resolve) in [(false, false), (true, false), (true, true)] {
+                let mut program = Program::new(code.clone()).unwrap();
+                program.set_fusion(fusion).unwrap();
+                if resolve {
+                    program.resolve_locals().unwrap();
+                }
+                #[cfg(feature = "jit")]
+                program.compile_jit().unwrap();
+                let mut vm = Machine::new(Arc::new(program));
+                vm.stack = (1..MAX_STACK_SIZE).map(|_| StackValue::Int(0)).collect();
+                assert_eq!(vm.interpret().unwrap_err(), VMError::StackOverflow);
+            }
+        }
+    }
+
+    /// x = 0
+    /// while x < n:
+    ///    `padding` pairs of LoadVal, Drop
+    ///    x += 1
+    /// x
+    fn counting_loop(n: i64, padding: usize) -> Assembler {
+        let mut asm = Assembler::new();
+        let (start, end) = (asm.label(), asm.label());
+        asm.load_val(0);
+        asm.emit(Instruction::WriteVar, b"x\0\0\0");
+        asm.bind(start);
+        asm.emit(Instruction::ReadVar, b"x\0\0\0");
+        asm.load_val(n);
+        asm.instruction(Instruction::Lt);
+        asm.jump_if_false(end);
+        for _ in 0..padding {
+            asm.load_val(0);
+            asm.instruction(Instruction::Drop);
+        }
+        asm.emit(Instruction::ReadVar, b"x\0\0\0");
+        asm.load_val(1);
+        asm.instruction(Instruction::Add);
+        asm.emit(Instruction::WriteVar, b"x\0\0\0");
+        asm.jump(start);
+        asm.bind(end);
+        asm.emit(Instruction::ReadVar, b"x\0\0\0");
+        asm.instruction(Instruction::Finish);
+        asm
     }
 
     #[test]
-    fn test_func_call() {
-        // fn add(x: i64, y: i64) -> i64 {
-        //     x + y
-        // }
-        let fn_add = vec![
-            Instruction::WriteVar.into(), 0x78, 0x61, 0x64, 0x64, // "xadd"
-            Instruction::WriteVar.into(), 0x79, 0x61, 0x64, 0x64, // "yadd"
-            Instruction::ReadVar.into(), 0x78, 0x61, 0x64, 0x64, // "xadd"
-            Instruction::ReadVar.into(), 0x79, 0x61, 0x64, 0x64, // "yadd"
-            Instruction::Add.into(), // xadd + yadd
+    fn test_wide_jumps() {
+        // short loops get the `u8` forms
+        let mut asm = counting_loop(3, 2);
+        let code = asm.assemble().unwrap();
+        assert_eq!((code[29], code[71]), (Instruction::JumpIfFalse.into(), Instruction::JumpBack.into()));
+        assert_eq!(interpret_both_ways(code).unwrap(), 3);
+
+        // a body of 300 bytes needs the `i16` forms, backward jumps have a negative offset
+        let mut asm = counting_loop(3, 30);
+        let code = asm.assemble().unwrap();
+        assert_eq!(code[29], Instruction::JumpIfFalseWide.into());
+        assert_eq!(code[352], Instruction::JumpWide.into());
+        assert_eq!(i16::from_le_bytes([code[353], code[354]]), -341);
+        assert_eq!(interpret_both_ways(code.clone()).unwrap(), 3);
+        let mut program = Program::new(code).unwrap();
+        program.resolve_locals().unwrap();
+        assert_eq!(program.code()[25], Instruction::JumpIfFalseWide.into());
+        assert_eq!(Machine::new(Arc::new(program)).interpret().unwrap(), 3);
+
+        // and one of 330 kB the `i32` forms
+        let code = counting_loop(2, 33_000).assemble().unwrap();
+        assert_eq!(code[29], Instruction::JumpIfFalseLong.into());
+        assert_eq!(interpret_both_ways(code).unwrap(), 2);
+
+        // calls take `u32` addresses, so they reach functions past 64 kB
+        let mut asm = Assembler::new();
+        let function = asm.label();
+        asm.call(function);
+        asm.instruction(Instruction::Finish);
+        for _ in 0..10_000 {
+            asm.load_val(0);
+            asm.instruction(Instruction::Drop);
+        }
+        asm.bind(function);
+        asm.load_val(7);
+        asm.instruction(Instruction::Return);
+        let code = asm.assemble().unwrap();
+        assert_eq!(code[..5], [Instruction::Call.into(), 0xA6, 0x86, 0x01, 0x00]);
+        assert_eq!(interpret_both_ways(code).unwrap(), 7);
+
+        // absolute targets
+        let code = vec![
+            Instruction::LoadVal.into(), 1, 0, 0, 0, 0, 0, 0, 0,
+            Instruction::JumpIfTrueAbs.into(), 24, 0, 0, 0,
+            Instruction::LoadVal.into(), 5, 0, 0, 0, 0, 0, 0, 0,
+            Instruction::Finish.into(),
+            Instruction::LoadVal.into(), 7, 0, 0, 0, 0, 0, 0, 0,
+            Instruction::JumpAbs.into(), 23, 0, 0, 0,
         ];
+        assert_eq!(machine(code).interpret().unwrap(), 7);
 
-        let fn_call = vec![
-            Instruction::FuncCall.into(), 0x00, 0x00, 0x02, 
-            0x0A, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // x: 522
-            0x01, 0x01, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, // y: 65793
+        // the verifier checks targets in both directions
+        assert_eq!(Program::new(vec![Instruction::JumpWide.into(), 0xFF, 0xFF]).unwrap_err(), VMError::InvalidJumpTarget(0));
+        assert_eq!(Program::new(vec![Instruction::JumpLong.into(), 0xFA, 0xFF, 0xFF, 0xFF]).unwrap_err(), VMError::InvalidJumpTarget(0));
+        assert_eq!(Program::new(vec![Instruction::JumpAbs.into(), 6, 0, 0, 0]).unwrap_err(), VMError::InvalidJumpTarget(0));
+        assert!(Program::new(vec![Instruction::JumpLong.into(), 0xFB, 0xFF, 0xFF, 0xFF]).is_ok());
+
+        // labels have to be bound and in reach of the jump
+        let mut asm = Assembler::new();
+        let label = asm.label();
+        asm.jump(label);
+        assert_eq!(asm.assemble().unwrap_err(), VMError::InvalidJumpTarget(0));
+        let mut asm = Assembler::new();
+        let label = asm.label();
+        asm.load_val(0);
+        asm.jump_if_false_or_pop(label);
+        asm.emit(Instruction::LoadStr, &[255; 256]);
+        asm.bind(label);
+        assert_eq!(asm.assemble().unwrap_err(), VMError::InvalidJumpTarget(9));
+        assert_eq!(asm.address(label), None);
+    }
+
+    #[test]
+    fn test_structured_control() {
+        // the loop of `test_for_loop` without any offsets
+        let code = vec![
+            Instruction::LoadVal.into(), 0, 0, 0, 0, 0, 0, 0, 0,
+            Instruction::WriteVar.into(), b't', b'e', b's', b't',
+            Instruction::LoadVal.into(), 0x01, 0, 0, 0, 0, 0, 0, 0,
+            Instruction::WriteVar.into(), b't', b'e', b'm', b'p',
+            Instruction::Loop.into(),
+            Instruction::ReadVar.into(), b't', b'e', b'm', b'p',
+            Instruction::LoadVal.into(), 0x0A, 0, 0, 0, 0, 0, 0, 0,
+            Instruction::Lte.into(),
+            Instruction::If.into(),
+            Instruction::ReadVar.into(), b't', b'e', b'm', b'p',
+            Instruction::Dup.into(),
+            Instruction::Mul.into(),
+            Instruction::ReadVar.into(), b't', b'e', b's', b't',
+            Instruction::Add.into(),
+            Instruction::WriteVar.into(), b't', b'e', b's', b't',
+            Instruction::ReadVar.into(), b't', b'e', b'm', b'p',
+            Instruction::LoadVal.into(), 0x01, 0, 0, 0, 0, 0, 0, 0,
+            Instruction::Add.into(),
+            Instruction::WriteVar.into(), b't', b'e', b'm', b'p',
+            Instruction::Continue.into(), 1, // the loop, 0 would be the `If`
+            Instruction::End.into(),
+            Instruction::End.into(),
+            Instruction::ReadVar.into(), b't', b'e', b's', b't',
+            Instruction::Finish.into(),
         ];
-        
-        let return_index = (fn_add.len() + fn_call.len() + 3) as u16;
+        let program = Program::new(code.clone()).unwrap();
+        assert!(matches!(program.ops[4..8], [Op::Nop, Op::ReadVar(_), Op::CompareJumpIfFalse(Comparison::Lte, 10, 21), _]));
+        assert!(matches!(program.ops[19..22], [Op::Jump(5), Op::Nop, Op::Nop]));
+        assert_eq!(interpret_both_ways(code.clone()).unwrap(), 385);
+        let mut program = Program::new(code).unwrap();
+        program.resolve_locals().unwrap();
+        assert_eq!(Machine::new(Arc::new(program)).interpret().unwrap(), 385);
 
-        // merge fn_add and main bytecode
-        // add(522, 65793)
-        // => 66315
-        let instructions = vec![
-            fn_add.clone(),
-            vec![
-                Instruction::ReturnIndex.into(),
-            ],
-            return_index.to_le_bytes().to_vec(),
-            fn_call.clone(),
-            vec![
-                Instruction::Finish.into(),
-            ],
-        ].concat();
+        // |x|
+        let abs = |x: i64| {
+            machine([
+                vec![Instruction::LoadVal.into()],
+                x.to_le_bytes().to_vec(),
+                vec![Instruction::Dup.into(), Instruction::LoadVal.into()],
+                0i64.to_le_bytes().to_vec(),
+                vec![
+                    Instruction::Lt.into(),
+                    Instruction::If.into(),
+                    Instruction::Neg.into(),
+                    Instruction::Else.into(),
+                    Instruction::LoadVal.into(), 0, 0, 0, 0, 0, 0, 0, 0,
+                    Instruction::Add.into(),
+                    Instruction::End.into(),
+                    Instruction::Finish.into(),
+                ],
+            ].concat()).interpret()
+        };
+        assert_eq!((abs(-5), abs(7)), (Ok(5), Ok(7)));
 
-        let mut vm = Bytecode {
-            instructions,
-            stack: vec![],
-            ip: fn_add.len() + 3,
-            variables: HashMap::new(),
+        // `Break` leaves the given block, here the loop around the inner block
+        let code = vec![
+            Instruction::LoadVal.into(), 1, 0, 0, 0, 0, 0, 0, 0,
+            Instruction::Loop.into(),
+            Instruction::Block.into(),
+            Instruction::Break.into(), 1,
+            Instruction::End.into(),
+            Instruction::LoadVal.into(), 2, 0, 0, 0, 0, 0, 0, 0,
+            Instruction::Continue.into(), 0,
+            Instruction::End.into(),
+            Instruction::Finish.into(),
+        ];
+        assert_eq!(machine(code).interpret().unwrap(), 1);
+
+        // blocks have to be nested properly
+        let end = Instruction::End.into();
+        assert_eq!(Program::new(vec![end]).unwrap_err(), VMError::UnbalancedBlock(0));
+        assert_eq!(Program::new(vec![Instruction::Block.into(), Instruction::Loop.into(), end]).unwrap_err(), VMError::UnbalancedBlock(0));
+        assert_eq!(Program::new(vec![Instruction::Block.into(), Instruction::Else.into(), end]).unwrap_err(), VMError::UnbalancedBlock(1));
+        let code = vec![Instruction::If.into(), Instruction::Else.into(), Instruction::Else.into(), end];
+        assert_eq!(Program::new(code).unwrap_err(), VMError::UnbalancedBlock(2));
+        // and `Break` and `Continue` refer to an enclosing block, `Continue` to a loop
+        assert_eq!(Program::new(vec![Instruction::Loop.into(), Instruction::Break.into(), 1, end]).unwrap_err(), VMError::InvalidJumpTarget(1));
+        assert_eq!(Program::new(vec![Instruction::Block.into(), Instruction::Continue.into(), 0, end]).unwrap_err(), VMError::InvalidJumpTarget(1));
+    }
+
+    #[test]
+    fn test_switch() {
+        // x => 10 * (x + 1) for x in 0..3, -1 otherwise
+        let switch = |x: i64| {
+            let mut code = vec![Instruction::LoadVal.into()];
+            code.extend(x.to_le_bytes());
+            code.extend([Instruction::Switch.into(), 3, 0]);
+            // default and the table, the arms start at 28
+            for target in [58u32, 28, 38, 48] {
+                code.extend(target.to_le_bytes());
+            }
+            for val in [10i64, 20, 30, -1] {
+                code.push(Instruction::LoadVal.into());
+                code.extend(val.to_le_bytes());
+                code.push(Instruction::Finish.into());
+            }
+            code
         };
+        for (x, result) in [(0, 10), (1, 20), (2, 30), (3, -1), (-1, -1), (i64::MIN, -1)] {
+            assert_eq!(machine(switch(x)).interpret().unwrap(), result);
+        }
 
-        assert_eq!(vm.interpret().unwrap(), 66315);
+        // targets are verified like any other jump
+        let mut code = switch(0);
+        code[16] = 29;
+        assert_eq!(Program::new(code).unwrap_err(), VMError::InvalidJumpTarget(9));
+        assert_eq!(Program::new(switch(0)[..20].to_vec()).unwrap_err(), VMError::TruncatedInstruction(9));
+
+        // and moved by `resolve_locals`
+        let mut code = vec![Instruction::LoadVal.into(), 1, 0, 0, 0, 0, 0, 0, 0, Instruction::WriteVar.into(), b'x', 0, 0, 0];
+        code.extend(switch(2));
+        for target in code[26..42].chunks_mut(4) {
+            let moved = u32::from_le_bytes(target.try_into().unwrap()) + 14;
+            target.copy_from_slice(&moved.to_le_bytes());
+        }
+        let mut program = Program::new(code).unwrap();
+        program.resolve_locals().unwrap();
+        assert_eq!(u32::from_le_bytes(program.code()[24..28].try_into().unwrap()), 70);
+        assert_eq!(Machine::new(Arc::new(program)).interpret().unwrap(), 30);
+    }
+
+    #[test]
+    fn test_match_int() {
+        // match x { arms => index of the arm, _ => -1 }
+        let assemble = |arms: &[i64]| {
+            let mut asm = Assembler::new();
+            let labels = arms.iter().map(|_| asm.label()).collect::<Vec<_>>();
+            let default = asm.label();
+            asm.emit(Instruction::ReadVar, b"x\0\0\0");
+            let arms = arms.iter().copied().zip(labels.iter().copied()).collect::<Vec<_>>();
+            asm.match_int(&arms, default);
+            for (index, label) in labels.into_iter().enumerate() {
+                asm.bind(label);
+                asm.load_val(index as i64);
+                asm.instruction(Instruction::Finish);
+            }
+            asm.bind(default);
+            asm.load_val(-1);
+            asm.instruction(Instruction::Finish);
+            Arc::new(Program::new(asm.assemble().unwrap()).unwrap())
+        };
+        let run = |program: &Arc<Program>, x: i64| {
+            let mut vm = Machine::new(program.clone());
+            vm.variables.insert("x".to_string(), StackValue::Int(x));
+            vm.interpret().unwrap()
+        };
+        let has_switch = |program: &Arc<Program>| program.ops.iter().any(|op| matches!(op, Op::Switch(..)));
+
+        // dense values use a jump table, the first arm with a value wins
+        let dense = assemble(&[3, 1, 2, 6, 2]);
+        assert!(has_switch(&dense));
+        for (x, result) in [(1, 1), (2, 2), (3, 0), (4, -1), (6, 3), (0, -1), (7, -1), (i64::MIN, -1), (i64::MAX, -1)] {
+            assert_eq!(run(&dense, x), result);
+        }
+        let extremes = assemble(&[i64::MAX, i64::MAX - 1, i64::MAX - 2]);
+        assert!(has_switch(&extremes));
+        assert_eq!((run(&extremes, i64::MAX - 1), run(&extremes, i64::MIN)), (1, -1));
+
+        // sparse ones a chain of comparisons
+        let sparse = assemble(&[1, 1000, -1_000_000_000_000]);
+        assert!(!has_switch(&sparse));
+        for (x, result) in [(1, 0), (1000, 1), (-1_000_000_000_000, 2), (2, -1)] {
+            assert_eq!(run(&sparse, x), result);
+        }
+        // arms spanning all of `i64`
+        let full = assemble(&[i64::MIN, 0, i64::MAX]);
+        assert!(!has_switch(&full));
+        for (x, result) in [(i64::MIN, 0), (0, 1), (i64::MAX, 2), (1, -1), (i64::MIN + 1, -1)] {
+            assert_eq!(run(&full, x), result);
+        }
+        assert_eq!(run(&assemble(&[]), 0), -1);
+    }
+
+    #[test]
+    fn test_try() {
+        // 100, then a region dividing by zero, the handler sees the error and its code
+        let mut asm = Assembler::new();
+        let (handler, after) = (asm.label(), asm.label());
+        asm.load_val(100);
+        asm.try_region(handler);
+        asm.load_val(5);
+        asm.load_val(0);
+        asm.load_val(7);
+        asm.instruction(Instruction::Div);
+        asm.instruction(Instruction::EndTry);
+        asm.jump(after);
+        asm.bind(handler);
+        asm.instruction(Instruction::Finish);
+        asm.bind(after);
+        asm.load_val(-1);
+        asm.instruction(Instruction::Finish);
+        let mut vm = machine(asm.assemble().unwrap());
+        assert_eq!(vm.interpret().unwrap(), VMError::DivisionByZero.code());
+        assert!(matches!(&vm.stack[..], [StackValue::Int(100), StackValue::Str(error)] if error == "DivisionByZero"));
+        assert!(vm.handlers.is_empty());
+
+        // thrown values reach the handler as they are, with code 0
+        let throw = |payload: Vec<u8>, caught: bool| {
+            let mut asm = Assembler::new();
+            let handler = asm.label();
+            if caught {
+                asm.try_region(handler);
+            }
+            asm.emit(payload[0].try_into().unwrap(), &payload[1..]);
+            asm.instruction(Instruction::Throw);
+            asm.bind(handler);
+            asm.instruction(Instruction::Finish);
+            let mut vm = machine(asm.assemble().unwrap());
+            vm.interpret().map(|code| (code, vm.stack))
+        };
+        let int = [vec![Instruction::LoadVal.into()], 42i64.to_le_bytes().to_vec()].concat();
+        let string = vec![Instruction::LoadStr.into(), 2, b'o', b'h'];
+        assert!(matches!(throw(int.clone(), true).unwrap(), (0, stack) if matches!(stack[..], [StackValue::Int(42)])));
+        assert!(matches!(throw(string.clone(), true).unwrap(), (0, stack) if matches!(&stack[..], [StackValue::Str(s)] if s == "oh")));
+        assert_eq!(throw(int, false).unwrap_err(), VMError::Uncaught(Payload::Int(42)));
+        assert_eq!(throw(string, false).unwrap_err(), VMError::Uncaught(Payload::Str("oh".to_string())));
+        let decimal = vec![Instruction::LoadDec.into(), 1, 0, 0, 0, 0, 0, 0, 0, 2];
+        assert_eq!(throw(decimal, false).unwrap_err(), VMError::TypeMismatch);
+    }
+
+    #[test]
+    fn test_try_unwinding() {
+        // x = 1, then in a region call f, which sets x and calls g, which throws;
+        // the handler rethrows to the outer region, which pushes x, a variable by name or a local slot
+        let unwinding = |local: bool| {
+            let (write, read, x) = if local {
+                (Instruction::StoreLocal, Instruction::LoadLocal, &[0, 0][..])
+            } else {
+                (Instruction::WriteVar, Instruction::ReadVar, &b"x\0\0\0"[..])
+        };
+        let mut asm = Assembler::new();
+        let (f, g, inner, outer) = (asm.label(), asm.label(), asm.label(), asm.label());
+        asm.load_val(1);
+        asm.emit(write, x);
+        asm.try_region(outer);
+        asm.load_val(7);
+        asm.try_region(inner);
+        asm.load_val(8);
+        asm.call(f);
+        asm.instruction(Instruction::Finish);
+        asm.bind(inner);
+        // the code, then the payload is thrown again
+        asm.instruction(Instruction::Drop);
+        asm.instruction(Instruction::Throw);
+        asm.bind(outer);
+        asm.instruction(Instruction::Drop);
+        asm.emit(read, x);
+        asm.instruction(Instruction::Add);
+        asm.instruction(Instruction::Finish);
+        asm.bind(f);
+        asm.load_val(2);
+        asm.emit(write, x);
+        asm.call(g);
+        asm.instruction(Instruction::Return);
+        asm.bind(g);
+        asm.load_val(40);
+        asm.instruction(Instruction::Throw);
+        asm.assemble().unwrap()
+        };
+
+        // variables by name are shared, so `x` is the one written by `f`
+        let mut vm = machine(unwinding(false));
+        assert_eq!(vm.interpret().unwrap(), 42);
+        assert!(vm.frames.is_empty() && vm.handlers.is_empty());
+        assert!(matches!(vm.stack[..], []));
+        // local slots are restored to the ones of the function with the handler
+        assert_eq!(machine(unwinding(true)).interpret().unwrap(), 41);
+
+        // a function returning inside a region leaves it
+        let mut asm = Assembler::new();
+        let (f, handler) = (asm.label(), asm.label());
+        asm.call(f);
+        asm.load_val(1);
+        asm.instruction(Instruction::Throw);
+        asm.bind(f);
+        asm.try_region(handler);
+        asm.instruction(Instruction::Return);
+        asm.bind(handler);
+        asm.instruction(Instruction::Finish);
+        assert_eq!(machine(asm.assemble().unwrap()).interpret().unwrap_err(), VMError::Uncaught(Payload::Int(1)));
+
+        // `EndTry` closes a region of the same function, the handler has to be an instruction
+        assert_eq!(machine(vec![Instruction::EndTry.into()]).interpret().unwrap_err(), VMError::UnbalancedBlock(0));
+        assert_eq!(Program::new(vec![Instruction::Try.into(), 2, 0, 0, 0]).unwrap_err(), VMError::InvalidJumpTarget(0));
+    }
+
+    #[test]
+    fn test_closures() {
+        // n = 5, add_n captures it, then n = 10 and apply_twice(add_n, 1) gives 11
+        let mut asm = Assembler::new();
+        let (add_n, apply_twice) = (asm.label(), asm.label());
+        asm.load_val(5);
+        asm.emit(Instruction::StoreLocal, &[0, 0]);
+        asm.closure(add_n, &[0]);
+        asm.emit(Instruction::StoreLocal, &[1, 0]);
+        asm.load_val(10);
+        asm.emit(Instruction::StoreLocal, &[0, 0]);
+        asm.load_val(1);
+        asm.emit(Instruction::LoadLocal, &[1, 0]);
+        asm.call(apply_twice);
+        asm.instruction(Instruction::Finish);
+        // f on top of x: f(f(x))
+        asm.bind(apply_twice);
+        asm.emit(Instruction::StoreLocal, &[0, 0]);
+        asm.emit(Instruction::LoadLocal, &[0, 0]);
+        asm.instruction(Instruction::CallValue);
+        asm.emit(Instruction::LoadLocal, &[0, 0]);
+        asm.instruction(Instruction::CallValue);
+        asm.instruction(Instruction::Return);
+        // slot 1 is not set in the caller, so it is not captured
+        asm.bind(add_n);
+        asm.emit(Instruction::LoadLocal, &[0, 0]);
+        asm.instruction(Instruction::Add);
+        asm.instruction(Instruction::Return);
+        let code = asm.assemble().unwrap();
+        assert_eq!(interpret_both_ways(code.clone()), Ok(11));
+
+        // the function value keeps its index when `resolve_locals` moves the code
+        let mut program = Program::new(code).unwrap();
+        program.resolve_locals().unwrap();
+        assert_eq!(Machine::new(Arc::new(program)).interpret().unwrap(), 11);
+
+        // only function values can be called
+        let mut asm = Assembler::new();
+        asm.load_val(1);
+        asm.instruction(Instruction::CallValue);
+        assert_eq!(machine(asm.assemble().unwrap()).interpret().unwrap_err(), VMError::TypeMismatch);
+
+        // a channel cannot be copied into a closure
+        let mut asm = Assembler::new();
+        let (f, thread) = (asm.label(), asm.label());
+        asm.spawn(thread);
+        asm.emit(Instruction::StoreLocal, &[0, 0]);
+        asm.closure(f, &[0]);
+        asm.bind(f);
+        asm.bind(thread);
+        asm.instruction(Instruction::Finish);
+        assert_eq!(machine(asm.assemble().unwrap()).interpret().unwrap_err(), VMError::ChannelNotCopyable);
+        assert_eq!(Program::new(vec![Instruction::Closure.into(), 2, 0, 0, 0, 0]).unwrap_err(), VMError::InvalidJumpTarget(0));
+    }
+
+    #[test]
+    fn test_tail_calls() {
+        // countdown(acc, n) = if n == 0 { acc } else { countdown(acc + 1, n - 1) }
+        let countdown = |n: i64| {
+            let mut asm = Assembler::new();
+            let (countdown, done) = (asm.label(), asm.label());
+            asm.load_val(0);
+            asm.load_val(n);
+            asm.call(countdown);
+            asm.instruction(Instruction::Finish);
+            asm.bind(countdown);
+            asm.instruction(Instruction::Dup);
+            asm.jump_if_false(done);
+            asm.load_val(-1);
+            asm.instruction(Instruction::Add);
+            asm.instruction(Instruction::Swap);
+            asm.load_val(1);
+            asm.instruction(Instruction::Add);
+            asm.instruction(Instruction::Swap);
+            asm.call(countdown);
+            asm.instruction(Instruction::Return);
+            asm.bind(done);
+            asm.instruction(Instruction::Drop);
+            asm.instruction(Instruction::Return);
+            asm
+        };
+        let code = countdown(1_000_000).assemble().unwrap();
+        assert!(code.contains(&Instruction::TailCall.into()));
+        assert_eq!(interpret_both_ways(code), Ok(1_000_000));
+
+        // even(n) = n == 0 || odd(n - 1), odd(n) = n != 0 && even(n - 1)
+        let mut asm = Assembler::new();
+        let (even, odd, yes, no) = (asm.label(), asm.label(), asm.label(), asm.label());
+        asm.load_val(1_000_001);
+        asm.call(even);
+        asm.instruction(Instruction::Finish);
+        for (function, other, zero, result) in [(even, odd, yes, 1), (odd, even, no, 0)] {
+            asm.bind(function);
+            asm.instruction(Instruction::Dup);
+            asm.jump_if_false(zero);
+            asm.load_val(-1);
+            asm.instruction(Instruction::Add);
+            asm.call(other);
+            asm.instruction(Instruction::Return);
+            asm.bind(zero);
+            asm.instruction(Instruction::Drop);
+            asm.load_val(result);
+            asm.instruction(Instruction::Return);
+        }
+        let code = asm.assemble().unwrap();
+        let mut program = Program::new(code).unwrap();
+        program.resolve_locals().unwrap();
+        assert_eq!(Machine::new(Arc::new(program)).interpret(), Ok(0));
+
+        // a `Try` region in the caller does not keep the calls of the callee
+        let mut asm = Assembler::new();
+        let (countdown, done, handler) = (asm.label(), asm.label(), asm.label());
+        asm.try_region(handler);
+        asm.load_val(0);
+        asm.load_val(1_000_000);
+        asm.call(countdown);
+        asm.instruction(Instruction::EndTry);
+        asm.bind(handler);
+        asm.instruction(Instruction::Finish);
+        asm.bind(countdown);
+        asm.instruction(Instruction::Dup);
+        asm.jump_if_false(done);
+        asm.load_val(-1);
+        asm.instruction(Instruction::Add);
+        asm.call(countdown);
+        asm.instruction(Instruction::Return);
+        asm.bind(done);
+        asm.instruction(Instruction::Return);
+        assert_eq!(interpret_both_ways(asm.assemble().unwrap()), Ok(0));
+
+        // a call in a region of its function stays a call, so the region catches what the callee throws
+        let mut asm = Assembler::new();
+        let (function, handler, thrower) = (asm.label(), asm.label(), asm.label());
+        asm.call(function);
+        asm.instruction(Instruction::Finish);
+        asm.bind(function);
+        asm.try_region(handler);
+        asm.call(thrower);
+        asm.instruction(Instruction::Return);
+        asm.bind(handler);
+        asm.instruction(Instruction::Drop);
+        asm.instruction(Instruction::Return);
+        asm.bind(thrower);
+        asm.load_val(7);
+        asm.instruction(Instruction::Throw);
+        let code = asm.assemble().unwrap();
+        let program = Program::new(code.clone()).unwrap();
+        assert!(!program.ops.iter().any(|op| matches!(op, Op::TailCall(_))));
+        assert_eq!(interpret_both_ways(code), Ok(7));
+
+        // the verifier rejects a tail call in a region, after `EndTry` it is fine
+        let code = vec![
+            Instruction::Try.into(), 0x0B, 0x00, 0x00, 0x00,
+            Instruction::TailCall.into(), 0x00, 0x00, 0x00, 0x00,
+            Instruction::Return.into(),
+        ];
+        assert_eq!(Program::new(code).unwrap_err(), VMError::NotInTailPosition(5));
+        let code = vec![
+            Instruction::Try.into(), 0x0C, 0x00, 0x00, 0x00,
+            Instruction::EndTry.into(),
+            Instruction::TailCall.into(), 0x00, 0x00, 0x00, 0x00,
+            Instruction::Return.into(),
+        ];
+        assert!(Program::new(code).is_ok());
+
+        // one entered by jumping past its `EndTry` fails when the tail call runs, the region catches that
+        let mut asm = Assembler::new();
+        let (tail, handler, function) = (asm.label(), asm.label(), asm.label());
+        asm.try_region(handler);
+        asm.jump(tail);
+        asm.instruction(Instruction::EndTry);
+        asm.bind(tail);
+        asm.tail_call(function);
+        asm.instruction(Instruction::Return);
+        asm.bind(handler);
+        asm.instruction(Instruction::Finish);
+        asm.bind(function);
+        asm.load_val(1);
+        asm.instruction(Instruction::Return);
+        assert_eq!(interpret_both_ways(asm.assemble().unwrap()), Ok(VMError::NotInTailPosition(0).code()));
+
+        // a tail call has to be followed by `Return`
+        let code = vec![Instruction::TailCall.into(), 0x00, 0x00, 0x00, 0x00, Instruction::Finish.into()];
+        assert_eq!(Program::new(code).unwrap_err(), VMError::NotInTailPosition(0));
+        let code = vec![Instruction::TailCall.into(), 0x00, 0x00, 0x00, 0x00];
+        assert_eq!(Program::new(code).unwrap_err(), VMError::NotInTailPosition(0));
+    }
+
+    #[test]
+    fn test_coroutines() {
+        // generator of the squares of 1..=n, summed by the main function
+        let mut asm = Assembler::new();
+        let (squares, next, end, top, done) = (asm.label(), asm.label(), asm.label(), asm.label(), asm.label());
+        asm.coroutine(squares);
+        asm.emit(Instruction::StoreLocal, &[0, 0]);
+        asm.load_val(0);
+        asm.emit(Instruction::StoreLocal, &[1, 0]);
+        asm.load_val(4);
+        asm.bind(next);
+        asm.emit(Instruction::LoadLocal, &[0, 0]);
+        asm.instruction(Instruction::Resume);
+        asm.jump_if_false(end);
+        asm.emit(Instruction::LoadLocal, &[1, 0]);
+        asm.instruction(Instruction::Add);
+        asm.emit(Instruction::StoreLocal, &[1, 0]);
+        asm.load_val(0);
+        asm.jump(next);
+        asm.bind(end);
+        asm.instruction(Instruction::Drop);
+        asm.emit(Instruction::LoadLocal, &[1, 0]);
+        asm.instruction(Instruction::Finish);
+        // the first value resumed with is n, slots of the coroutine are its own
+        asm.bind(squares);
+        asm.emit(Instruction::StoreLocal, &[0, 0]);
+        asm.load_val(1);
+        asm.emit(Instruction::StoreLocal, &[1, 0]);
+        asm.bind(top);
+        asm.emit(Instruction::LoadLocal, &[1, 0]);
+        asm.emit(Instruction::LoadLocal, &[0, 0]);
+        asm.instruction(Instruction::Gt);
+        asm.jump_if_true(done);
+        asm.emit(Instruction::LoadLocal, &[1, 0]);
+        asm.instruction(Instruction::Dup);
+        asm.instruction(Instruction::Mul);
+        asm.instruction(Instruction::Yield);
+        asm.instruction(Instruction::Drop);
+        asm.emit(Instruction::LoadLocal, &[1, 0]);
+        asm.load_val(1);
+        asm.instruction(Instruction::Add);
+        asm.emit(Instruction::StoreLocal, &[1, 0]);
+        asm.jump(top);
+        asm.bind(done);
+        asm.load_val(-1);
+        asm.instruction(Instruction::Return);
+        let code = asm.assemble().unwrap();
+        assert_eq!(interpret_both_ways(code.clone()), Ok(30));
+        let mut vm = machine(code);
+        assert_eq!(vm.interpret(), Ok(30));
+        assert!(vm.resumed.is_empty() && vm.frames.is_empty());
+        let Some(StackValue::Coroutine(coroutine)) = &vm.locals[0] else {
+            panic!("expected a coroutine");
+        };
+        assert!(coroutine.is_finished());
+
+        // a finished coroutine can not be resumed, `Yield` needs a coroutine
+        let mut asm = Assembler::new();
+        let f = asm.label();
+        asm.coroutine(f);
+        asm.emit(Instruction::StoreLocal, &[0, 0]);
+        for _ in 0..2 {
+            asm.load_val(0);
+            asm.emit(Instruction::LoadLocal, &[0, 0]);
+            asm.instruction(Instruction::Resume);
+        }
+        asm.instruction(Instruction::Finish);
+        asm.bind(f);
+        asm.instruction(Instruction::Return);
+        assert_eq!(machine(asm.assemble().unwrap()).interpret(), Err(VMError::CoroutineNotResumable));
+        let code = vec![Instruction::LoadVal.into(), 1, 0, 0, 0, 0, 0, 0, 0, Instruction::Yield.into()];
+        assert_eq!(machine(code).interpret(), Err(VMError::YieldOutsideCoroutine));
+
+        // errors the coroutine does not handle go on to its resumer, whose stack is left as it was
+        let throwing = |handled: bool| {
+            let mut asm = Assembler::new();
+            let (f, handler) = (asm.label(), asm.label());
+            asm.load_val(5);
+            if handled {
+                asm.try_region(handler);
+            }
+            asm.load_val(0);
+            asm.coroutine(f);
+            asm.instruction(Instruction::Resume);
+            asm.instruction(Instruction::Finish);
+            asm.bind(handler);
+            asm.instruction(Instruction::Drop);
+            asm.instruction(Instruction::Add);
+            asm.instruction(Instruction::Finish);
+            asm.bind(f);
+            asm.load_val(1);
+            asm.load_val(37);
+            asm.instruction(Instruction::Throw);
+            asm.assemble().unwrap()
+        };
+        assert_eq!(machine(throwing(true)).interpret(), Ok(42));
+        let mut vm = machine(throwing(false));
+        assert_eq!(vm.interpret(), Err(VMError::Uncaught(Payload::Int(37))));
+        assert!(vm.resumed.is_empty() && matches!(vm.stack[..], [StackValue::Int(5)]));
+        assert_eq!(machine(vec![Instruction::Resume.into()]).interpret(), Err(VMError::StackUnderflow));
+    }
+
+    #[test]
+    fn test_run_for() {
+        // one instruction per step, unfused
+        let mut program = Program::new(vec![
+            Instruction::LoadVal.into(), 0x02, 0, 0, 0, 0, 0, 0, 0,
+            Instruction::LoadVal.into(), 0x03, 0, 0, 0, 0, 0, 0, 0,
+            Instruction::Add.into(),
+            Instruction::Finish.into(),
+        ]).unwrap();
+        program.set_fusion(false).unwrap();
+        let mut vm = Machine::new(Arc::new(program));
+        assert_eq!([vm.step(), vm.step(), vm.step()], [Status::Yielded, Status::Yielded, Status::Yielded]);
+        assert_eq!(vm.ip, 3);
+        assert_eq!(vm.step(), Status::Finished(5));
+
+        // two machines time sliced by the host finish like they would alone
+        let mut vms = [machine(counting_loop(1000, 0).assemble().unwrap()), machine(counting_loop(500, 3).assemble().unwrap())];
+        let mut results = [None, None];
+        let mut slices = 0;
+        while results.contains(&None) {
+            for (vm, result) in vms.iter_mut().zip(results.iter_mut()).filter(|(_, result)| result.is_none()) {
+                match vm.run_for(100) {
+                    Status::Yielded => slices += 1,
+                    status => *result = Some(status),
+                }
+            }
+        }
+        assert_eq!(results, [Some(Status::Finished(1000)), Some(Status::Finished(500))]);
+        assert!(slices > 20);
+
+        // a receive that would block hands control back until something is sent
+        let (host_sender, receiver) = crate::channel::channel();
+        let (sender, host_receiver) = crate::channel::channel();
+        let mut asm = Assembler::new();
+        asm.instruction(Instruction::RecvChannel);
+        asm.load_val(1);
+        asm.instruction(Instruction::Add);
+        asm.instruction(Instruction::Swap);
+        asm.load_val(7);
+        asm.instruction(Instruction::SendChannel);
+        asm.instruction(Instruction::Drop);
+        asm.instruction(Instruction::Finish);
+        let code = asm.assemble().unwrap();
+        let mut vm = machine(code.clone());
+        vm.stack.push(StackValue::Channel(sender, receiver));
+        assert_eq!(vm.run_for(100), Status::BlockedOnChannel);
+        assert_eq!(vm.run_for(100), Status::BlockedOnChannel);
+        host_sender.send(41).unwrap();
+        assert_eq!(vm.run_for(100), Status::Finished(42));
+        assert_eq!(host_receiver.recv(), Ok(7));
+
+        // errors are returned as a status
+        let (_, receiver) = crate::channel::channel();
+        let (sender, _) = crate::channel::channel();
+        let mut vm = machine(code);
+        vm.stack.push(StackValue::Channel(sender, receiver));
+        assert_eq!(vm.run_for(100), Status::Error(VMError::ChannelClosed));
+    }
+
+    /// Minimal executor polling every future on the current thread until all of them are ready
+    fn block_on_all<T>(mut futures: Vec<Pin<Box<dyn Future<Output = T> + '_>>>) -> Vec<T> {
+        struct Unpark(std::thread::Thread);
+        impl std::task::Wake for Unpark {
+            fn wake(self: Arc<Self>) {
+                self.0.unpark();
+            }
+        }
+        let waker = Waker::from(Arc::new(Unpark(std::thread::current())));
+        let mut cx = std::task::Context::from_waker(&waker);
+        let mut results: Vec<Option<T>> = futures.iter().map(|_| None).collect();
+        while results.iter().any(Option::is_none) {
+            for (future, result) in futures.iter_mut().zip(results.iter_mut()).filter(|(_, result)| result.is_none()) {
+                if let Poll::Ready(value) = future.as_mut().poll(&mut cx) {
+                    *result = Some(value);
+                }
+            }
+            if results.iter().any(Option::is_none) {
+                std::thread::park();
+            }
+        }
+        results.into_iter().map(Option::unwrap).collect()
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_interpret_async() {
+        // two machines talking over channels and a long loop share one thread
+        let (a_sender, b_receiver) = crate::channel::channel();
+        let (b_sender, a_receiver) = crate::channel::channel();
+        // receives x, sends x + 1 and returns what it gets back
+        let mut asm = Assembler::new();
+        asm.instruction(Instruction::RecvChannel);
+        asm.load_val(1);
+        asm.instruction(Instruction::Add);
+        asm.instruction(Instruction::SendChannel);
+        asm.instruction(Instruction::RecvChannel);
+        asm.instruction(Instruction::Finish);
+        let mut a = machine(asm.assemble().unwrap());
+        a.stack.push(StackValue::Channel(a_sender, a_receiver));
+        // sends 41, echoes what it receives and returns 0
+        let mut asm = Assembler::new();
+        asm.load_val(41);
+        asm.instruction(Instruction::SendChannel);
+        asm.instruction(Instruction::RecvChannel);
+        asm.instruction(Instruction::SendChannel);
+        asm.load_val(0);
+        asm.instruction(Instruction::Finish);
+        let mut b = machine(asm.assemble().unwrap());
+        b.stack.push(StackValue::Channel(b_sender, b_receiver));
+        let mut counter = machine(counting_loop(100_000, 0).assemble().unwrap());
+
+        let results = block_on_all(vec![
+            Box::pin(a.interpret_async()),
+            Box::pin(counter.interpret_async()),
+            Box::pin(b.interpret_async()),
+        ]);
+        assert_eq!(results, [Ok(42), Ok(100_000), Ok(0)]);
+
+        // the host awaits what a machine sends, the machine's receive fails as nobody sends to it
+        let (vm_sender, host_values) = crate::channel::channel();
+        let (_, vm_receiver) = crate::channel::channel();
+        let mut asm = Assembler::new();
+        asm.load_val(7);
+        asm.instruction(Instruction::SendChannel);
+        asm.instruction(Instruction::RecvChannel);
+        let mut vm = machine(asm.assemble().unwrap());
+        vm.stack.push(StackValue::Channel(vm_sender, vm_receiver));
+        let host = async { host_values.recv_async().await.map_err(|_| VMError::ChannelClosed) };
+        let results = block_on_all(vec![Box::pin(vm.interpret_async()), Box::pin(host)]);
+        assert_eq!(results, [Err(VMError::ChannelClosed), Ok(7)]);
+    }
+
+    #[test]
+    fn test_interrupt() {
+        // a loop that never ends, in a region that does not catch the interrupt
+        let mut asm = Assembler::new();
+        let (start, handler) = (asm.label(), asm.label());
+        asm.try_region(handler);
+        asm.bind(start);
+        asm.jump(start);
+        asm.bind(handler);
+        asm.instruction(Instruction::Finish);
+        let code = asm.assemble().unwrap();
+        let mut vm = machine(code.clone());
+        let handle = vm.interrupt_handle();
+        let thread = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            handle.interrupt();
+        });
+        assert_eq!(vm.interpret(), Err(VMError::Interrupted(5)));
+        thread.join().unwrap();
+
+        let mut vm = machine(code);
+        vm.deadline = Some(Instant::now() + Duration::from_millis(20));
+        assert_eq!(vm.interpret(), Err(VMError::Timeout(5)));
+
+        // calls look at the interrupt as well
+        let mut asm = Assembler::new();
+        let f = asm.label();
+        asm.load_val(1);
+        asm.call(f);
+        asm.instruction(Instruction::Finish);
+        asm.bind(f);
+        asm.instruction(Instruction::Return);
+        let mut vm = machine(asm.assemble().unwrap());
+        vm.interrupt_handle().interrupt();
+        assert_eq!(vm.interpret(), Err(VMError::Interrupted(9)));
+
+        // a child stopped with the parent is not a failure of the child
+        let mut asm = Assembler::new();
+        let (child, start) = (asm.label(), asm.label());
+        asm.spawn(child);
+        asm.instruction(Instruction::RecvChannel);
+        asm.instruction(Instruction::Finish);
+        asm.bind(child);
+        asm.bind(start);
+        asm.jump(start);
+        let mut vm = machine(asm.assemble().unwrap());
+        let handle = vm.interrupt_handle();
+        let thread = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            handle.interrupt();
+        });
+        assert_eq!(vm.interpret(), Err(VMError::Interrupted(5)));
+        thread.join().unwrap();
+
+        // so do channel waits, the machine waits again once the interrupt is cleared
+        let (sender, receiver) = crate::channel::channel();
+        let (vm_sender, _host_receiver) = crate::channel::channel();
+        let mut vm = machine(vec![Instruction::RecvChannel.into(), Instruction::Finish.into()]);
+        vm.stack.push(StackValue::Channel(vm_sender, receiver));
+        let handle = vm.interrupt_handle();
+        let interrupt = handle.clone();
+        let thread = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            interrupt.interrupt();
+        });
+        assert_eq!(vm.interpret(), Err(VMError::Interrupted(0)));
+        thread.join().unwrap();
+        handle.clear();
+        sender.send(5).unwrap();
+        assert_eq!(vm.interpret(), Ok(5));
+
+        // a machine blocked between time slices stops on the next one
+        vm.ip = 0;
+        assert_eq!(vm.run_for(10), Status::BlockedOnChannel);
+        handle.interrupt();
+        assert_eq!(vm.run_for(10), Status::Error(VMError::Interrupted(0)));
+
+        // an async machine waiting for a value that never comes is woken at its deadline
+        let (_sender, receiver) = crate::channel::channel();
+        let (vm_sender, _host_receiver) = crate::channel::channel();
+        let mut vm = machine(vec![Instruction::RecvChannel.into(), Instruction::Finish.into()]);
+        vm.stack.push(StackValue::Channel(vm_sender, receiver));
+        vm.deadline = Some(Instant::now() + Duration::from_millis(20));
+        assert_eq!(block_on_all(vec![Box::pin(vm.interpret_async())]), [Err(VMError::Timeout(0))]);
+    }
+
+    #[test]
+    fn test_resolve_locals() {
+        // same loop as in `test_for_loop`, with jumps moved to the shorter instructions
+        let mut program = Program::new(vec![
+            Instruction::LoadVal.into(), 0, 0, 0, 0, 0, 0, 0, 0,
+            Instruction::WriteVar.into(), 0x74, 0x65, 0x73, 0x74, // "test"
+            Instruction::LoadVal.into(), 0x01, 0, 0, 0, 0, 0, 0, 0,
+            Instruction::WriteVar.into(), 0x74, 0x65, 0x6d, 0x70, // "temp"
+            Instruction::ReadVar.into(), 0x74, 0x65, 0x6d, 0x70,
+            Instruction::LoadVal.into(), 0x0A, 0, 0, 0, 0, 0, 0, 0,
+            Instruction::Lte.into(),
+            Instruction::JumpIfFalse.into(), 44,
+            Instruction::ReadVar.into(), 0x74, 0x65, 0x6d, 0x70,
+            Instruction::ReadVar.into(), 0x74, 0x65, 0x6d, 0x70,
+            Instruction::Mul.into(),
+            Instruction::ReadVar.into(), 0x74, 0x65, 0x73, 0x74,
+            Instruction::Add.into(),
+            Instruction::WriteVar.into(), 0x74, 0x65, 0x73, 0x74,
+            Instruction::ReadVar.into(), 0x74, 0x65, 0x6d, 0x70,
+            Instruction::LoadVal.into(), 0x01, 0, 0, 0, 0, 0, 0, 0,
+            Instruction::Add.into(),
+            Instruction::WriteVar.into(), 0x74, 0x65, 0x6d, 0x70,
+            Instruction::JumpBack.into(), 61,
+            Instruction::ReadVar.into(), 0x74, 0x65, 0x73, 0x74,
+            Instruction::Finish.into(),
+        ]).unwrap();
+        program.resolve_locals().unwrap();
+        let mut vm = Machine::new(Arc::new(program));
+
+        assert_eq!(vm.interpret().unwrap(), 385);
+        assert!(matches!(vm.local("temp"), Some(StackValue::Int(11))));
+        assert!(vm.variables.is_empty());
+
+        // x = 7; f(1) + x where f(y) { x = y + x; x }, variables are shared by every function once resolved
+        let code = vec![
+            Instruction::LoadVal.into(), 0x07, 0, 0, 0, 0, 0, 0, 0,
+            Instruction::WriteVar.into(), b'x', 0, 0, 0,
+            Instruction::LoadVal.into(), 0x01, 0, 0, 0, 0, 0, 0, 0,
+            Instruction::Call.into(), 0x23, 0x00, 0x00, 0x00,
+            Instruction::ReadVar.into(), b'x', 0, 0, 0,
+            Instruction::Add.into(),
+            Instruction::Finish.into(),
+            // f, index 35
+            Instruction::ReadVar.into(), b'x', 0, 0, 0,
+            Instruction::Add.into(),
+            Instruction::Dup.into(),
+            Instruction::WriteVar.into(), b'x', 0, 0, 0,
+            Instruction::Return.into(),
+        ];
+        let mut named = Program::new(code.clone()).unwrap();
+        named.export("f", 35, 1, 1).unwrap();
+        let mut resolved = Program::new(code).unwrap();
+        resolved.export("f", 35, 1, 1).unwrap();
+        resolved.resolve_locals().unwrap();
+        assert_eq!(resolved.exports()["f"].address, 31);
+
+        for program in [named, resolved] {
+            let mut vm = Machine::new(Arc::new(program));
+            assert_eq!(vm.interpret().unwrap(), 16);
+            // variables are kept between calls
+            assert!(matches!(vm.call("f", &[StackValue::Int(4)]).unwrap()[..], [StackValue::Int(12)]));
+            assert!(matches!(vm.call("f", &[StackValue::Int(4)]).unwrap()[..], [StackValue::Int(16)]));
+        }
+    }
+
+    #[test]
+    fn test_more_loop() {
+        let code = vec![
+            Instruction::LoadVal.into(), 0x01, 0, 0, 0, 0, 0, 0, 0, // 1
+            Instruction::LoadVal.into(), 0x05, 0, 0, 0, 0, 0, 0, 0, // 5
+            Instruction::Add.into(), // 6
+            Instruction::WriteVar.into(), 74, 65, 73, 74, // "test"
+            Instruction::ReadVar.into(), 74, 65, 73, 74, // "test"
+            Instruction::LoadVal.into(), 0x64, 0, 0, 0, 0, 0, 0, 0, // 100
+            Instruction::Lt.into(), // test < 100
+            Instruction::JumpIfFalse.into(), 0x16, // Skip to instruction after `JumpBack` if false
+            Instruction::ReadVar.into(), 74, 65, 73, 74, // "test"
+            Instruction::LoadVal.into(), 0x01, 0, 0, 0, 0, 0, 0, 0, // 1
+            Instruction::Add.into(), // test += 1
+            Instruction::WriteVar.into(), 74, 65, 73, 74, // "test"
+            Instruction::JumpBack.into(), 0x27, // Jump back to loop condition
+            Instruction::ReadVar.into(), 74, 65, 73, 74, // "test"
+            Instruction::Finish.into(), 
+        ];
+
+        assert_eq!(interpret_both_ways(code).unwrap(), 100);
+    }
+
+    #[test]
+    fn test_channel() {
+        let instructions = vec![
+            Instruction::LoadVal.into(), 0x01, 0, 0, 0, 0, 0, 0, 0,
+            Instruction::SendChannel.into(),
+            Instruction::RecvChannel.into(),
+            Instruction::Finish.into(),
+        ];
+
+        let (sender, receiver): (Sender<i64>, Receiver<i64>) = crate::channel::channel();
+
+        let mut vm = Machine {
+            stack: vec![StackValue::Channel(sender, receiver)],
+            ip: 0,
+            ..machine(instructions)
+        };
+
+        assert_eq!(vm.interpret().unwrap(), 1);
+
+        // sending fails once nothing can receive
+        let (sender, receiver) = crate::channel::channel();
+        let (_, closed) = crate::channel::channel::<i64>();
+        drop(receiver);
+        let mut vm = machine(vec![
+            Instruction::LoadVal.into(), 0x01, 0, 0, 0, 0, 0, 0, 0,
+            Instruction::SendChannel.into(),
+            Instruction::Finish.into(),
+        ]);
+        vm.stack.push(StackValue::Channel(sender, closed));
+        assert_eq!(vm.interpret(), Err(VMError::ChannelClosed));
+    }
+
+    #[test]
+    fn test_func_call() {
+        // fn add(x: i64, y: i64) -> i64 {
+        //     x + y
+        // }
+        let fn_add = vec![
+            Instruction::WriteVar.into(), 0x78, 0x61, 0x64, 0x64, // "xadd"
+            Instruction::WriteVar.into(), 0x79, 0x61, 0x64, 0x64, // "yadd"
+            Instruction::ReadVar.into(), 0x78, 0x61, 0x64, 0x64, // "xadd"
+            Instruction::ReadVar.into(), 0x79, 0x61, 0x64, 0x64, // "yadd"
+            Instruction::Add.into(), // xadd + yadd
+        ];
+
+        let fn_call = vec![
+            Instruction::FuncCall.into(), 0x00, 0x00, 0x00, 0x00, 0x02,
+            0x0A, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // x: 522
+            0x01, 0x01, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, // y: 65793
+        ];
+        
+        let return_index = (fn_add.len() + fn_call.len() + 5) as u32;
+
+        // merge fn_add and main bytecode
+        // add(522, 65793)
+        // => 66315
+        let instructions = [
+            fn_add.clone(),
+            vec![
+                Instruction::ReturnIndex.into(),
+            ],
+            return_index.to_le_bytes().to_vec(),
+            fn_call.clone(),
+            vec![
+                Instruction::Finish.into(),
+            ],
+        ].concat();
+
+        let mut vm = machine(instructions);
+        // start at `FuncCall`, the instruction pointer indexes decoded instructions
+        vm.ip = vm.program.op_index(fn_add.len() + 5).unwrap();
+
+        assert_eq!(vm.ip, 6);
+        assert_eq!(vm.interpret().unwrap(), 66315);
+    }
+
+    #[test]
+    fn test_logical() {
+        // (1 && 0) || !0 => 1
+        let mut vm = machine(vec![
+            Instruction::LoadVal.into(), 0x01, 0, 0, 0, 0, 0, 0, 0,
+            Instruction::LoadVal.into(), 0x00, 0, 0, 0, 0, 0, 0, 0,
+            Instruction::And.into(),
+            Instruction::LoadVal.into(), 0x00, 0, 0, 0, 0, 0, 0, 0,
+            Instruction::Not.into(),
+            Instruction::Or.into(),
+            Instruction::Finish.into(),
+        ]);
+
+        assert_eq!(vm.interpret().unwrap(), 1);
+
+        // 5 xor 7 is false, both operands are truthy
+        let mut vm_1 = machine(vec![
+            Instruction::LoadVal.into(), 0x05, 0, 0, 0, 0, 0, 0, 0,
+            Instruction::LoadVal.into(), 0x07, 0, 0, 0, 0, 0, 0, 0,
+            Instruction::Xor.into(),
+            Instruction::Finish.into(),
+        ]);
+
+        assert_eq!(vm_1.interpret().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_bitwise() {
+        // -((0b1100 & 0b1010) | (0b0110 ^ 0b0011)) => -13
+        let mut vm = machine(vec![
+            Instruction::LoadVal.into(), 0x0C, 0, 0, 0, 0, 0, 0, 0,
+            Instruction::LoadVal.into(), 0x0A, 0, 0, 0, 0, 0, 0, 0,
+            Instruction::BitAnd.into(), // 0b1000
+            Instruction::LoadVal.into(), 0x06, 0, 0, 0, 0, 0, 0, 0,
+            Instruction::LoadVal.into(), 0x03, 0, 0, 0, 0, 0, 0, 0,
+            Instruction::BitXor.into(), // 0b0101
+            Instruction::BitOr.into(), // 0b1101
+            Instruction::Neg.into(),
+            Instruction::Finish.into(),
+        ]);
+
+        assert_eq!(vm.interpret().unwrap(), -13);
+    }
+
+    #[test]
+    fn test_shift() {
+        let shift = |value: i64, amount: i64, instruction: Instruction| {
+            let mut vm = machine([
+                vec![Instruction::LoadVal.into()],
+                value.to_le_bytes().to_vec(),
+                vec![Instruction::LoadVal.into()],
+                amount.to_le_bytes().to_vec(),
+                vec![instruction.into(), Instruction::Finish.into()],
+            ].concat());
+            vm.interpret().unwrap()
+        };
+
+        assert_eq!(shift(3, 4, Instruction::Shl), 48);
+        assert_eq!(shift(-48, 4, Instruction::Shr), -3);
+        // out of range amounts shift every bit out
+        assert_eq!(shift(3, 64, Instruction::Shl), 0);
+        assert_eq!(shift(3, -1, Instruction::Shl), 0);
+        assert_eq!(shift(48, 100, Instruction::Shr), 0);
+        assert_eq!(shift(-48, 64, Instruction::Shr), -1);
+    }
+
+    #[test]
+    fn test_short_circuit_jump() {
+        // 0 && (1 / 0) => 0, the division is never evaluated
+        let mut vm = machine(vec![
+            Instruction::LoadVal.into(), 0x00, 0, 0, 0, 0, 0, 0, 0,
+            Instruction::JumpIfFalseOrPop.into(), 19, // skip the right hand side
+            Instruction::LoadVal.into(), 0x00, 0, 0, 0, 0, 0, 0, 0,
+            Instruction::LoadVal.into(), 0x01, 0, 0, 0, 0, 0, 0, 0,
+            Instruction::Div.into(),
+            Instruction::Finish.into(),
+        ]);
+
+        assert_eq!(vm.interpret().unwrap(), 0);
+
+        // 0 || 7 => 7
+        let mut vm_1 = machine(vec![
+            Instruction::LoadVal.into(), 0x00, 0, 0, 0, 0, 0, 0, 0,
+            Instruction::JumpIfTrueOrPop.into(), 9,
+            Instruction::LoadVal.into(), 0x07, 0, 0, 0, 0, 0, 0, 0,
+            Instruction::Finish.into(),
+        ]);
+
+        assert_eq!(vm_1.interpret().unwrap(), 7);
+
+        // the condition has to be an integer
+        let mut vm_2 = machine(vec![Instruction::JumpIfTrueOrPop.into(), 0]);
+        assert_eq!(vm_2.interpret().unwrap_err(), VMError::StackUnderflow);
+        let mut vm_3 = machine(vec![Instruction::JumpIfTrueOrPop.into(), 0]);
+        vm_3.stack.push(StackValue::Str("1".to_string()));
+        assert_eq!(vm_3.interpret().unwrap_err(), VMError::TypeMismatch);
+    }
+
+    #[test]
+    fn test_stack_manipulation() {
+        // Sum of squares without variables
+        // 3 dup * 4 dup * + => 25
+        let mut vm = machine(vec![
+            Instruction::LoadVal.into(), 0x03, 0, 0, 0, 0, 0, 0, 0,
+            Instruction::Dup.into(),
+            Instruction::Mul.into(),
+            Instruction::LoadVal.into(), 0x04, 0, 0, 0, 0, 0, 0, 0,
+            Instruction::Dup.into(),
+            Instruction::Mul.into(),
+            Instruction::Add.into(),
+            Instruction::Finish.into(),
+        ]);
+
+        assert_eq!(vm.interpret().unwrap(), 25);
+
+        // 1 2 3 rot => 2 3 1, swap => 2 1 3, over => 2 1 3 1, pick 3 => 2 1 3 1 2
+        // drop => 2 1 3 1, sub sub sub => 2 - (1 - (3 - 1)) = 3
+        let mut vm_1 = machine(vec![
+            Instruction::LoadVal.into(), 0x01, 0, 0, 0, 0, 0, 0, 0,
+            Instruction::LoadVal.into(), 0x02, 0, 0, 0, 0, 0, 0, 0,
+            Instruction::LoadVal.into(), 0x03, 0, 0, 0, 0, 0, 0, 0,
+            Instruction::Rot.into(),
+            Instruction::Swap.into(),
+            Instruction::Over.into(),
+            Instruction::Pick.into(), 0x03,
+            Instruction::Drop.into(),
+            Instruction::Sub.into(),
+            Instruction::Sub.into(),
+            Instruction::Sub.into(),
+            Instruction::Finish.into(),
+        ]);
+
+        assert_eq!(vm_1.interpret().unwrap(), 3);
+
+        let mut vm_2 = machine(vec![
+            Instruction::LoadVal.into(), 0x01, 0, 0, 0, 0, 0, 0, 0,
+            Instruction::Pick.into(), 0x01,
+            Instruction::Finish.into(),
+        ]);
+
+        assert_eq!(vm_2.interpret().unwrap_err(), VMError::StackUnderflow);
+    }
+
+    #[test]
+    fn test_stack_manipulation_channel() {
+        // channels can be moved around but not copied
+        let (sender, receiver) = crate::channel::channel();
+        let mut vm = machine(vec![
+            Instruction::LoadVal.into(), 0x05, 0, 0, 0, 0, 0, 0, 0,
+            Instruction::Swap.into(),
+            Instruction::Swap.into(),
+            Instruction::SendChannel.into(),
+            Instruction::RecvChannel.into(),
+            Instruction::Swap.into(),
+            Instruction::Drop.into(),
+            Instruction::Finish.into(),
+        ]);
+        vm.stack.push(StackValue::Channel(sender, receiver));
+
+        assert_eq!(vm.interpret().unwrap(), 5);
+
+        let (sender, receiver) = crate::channel::channel();
+        let mut vm_1 = machine(vec![
+            Instruction::Dup.into(),
+            Instruction::Finish.into(),
+        ]);
+        vm_1.stack.push(StackValue::Channel(sender, receiver));
+
+        assert_eq!(vm_1.interpret().unwrap_err(), VMError::ChannelNotCopyable);
+    }
+
+    #[test]
+    fn test_arithmetic_overflow() {
+        let binary = |b: i64, a: i64, instruction: Instruction| {
+            let mut vm = machine([
+                vec![Instruction::LoadVal.into()],
+                b.to_le_bytes().to_vec(),
+                vec![Instruction::LoadVal.into()],
+                a.to_le_bytes().to_vec(),
+                vec![instruction.into(), Instruction::Finish.into()],
+            ].concat());
+            vm.interpret()
+        };
+
+        // checked operations are the default
+        assert_eq!(binary(i64::MAX, 1, Instruction::Add), Err(VMError::ArithmeticOverflow));
+        assert_eq!(binary(i64::MIN, 1, Instruction::Sub), Err(VMError::ArithmeticOverflow));
+        assert_eq!(binary(i64::MAX, 2, Instruction::Mul), Err(VMError::ArithmeticOverflow));
+        // `Div` divides the top value by the second one
+        assert_eq!(binary(-1, i64::MIN, Instruction::Div), Err(VMError::ArithmeticOverflow));
+        assert_eq!(binary(i64::MIN, -1, Instruction::Mod), Err(VMError::ArithmeticOverflow));
+        assert_eq!(binary(7, 0, Instruction::Mod), Err(VMError::DivisionByZero));
+        assert_eq!(binary(7, 3, Instruction::Mod), Ok(1));
+
+        assert_eq!(binary(i64::MAX, 1, Instruction::WrappingAdd), Ok(i64::MIN));
+        assert_eq!(binary(i64::MIN, 1, Instruction::WrappingSub), Ok(i64::MAX));
+        assert_eq!(binary(i64::MAX, 2, Instruction::WrappingMul), Ok(-2));
+        assert_eq!(binary(i64::MAX, 1, Instruction::SaturatingAdd), Ok(i64::MAX));
+        assert_eq!(binary(i64::MIN, 1, Instruction::SaturatingSub), Ok(i64::MIN));
+        assert_eq!(binary(i64::MIN, 2, Instruction::SaturatingMul), Ok(i64::MIN));
+
+        let mut vm = machine([
+            vec![Instruction::LoadVal.into()],
+            i64::MIN.to_le_bytes().to_vec(),
+            vec![Instruction::Neg.into(), Instruction::Finish.into()],
+        ].concat());
+
+        assert_eq!(vm.interpret(), Err(VMError::ArithmeticOverflow));
+    }
+
+    #[test]
+    fn test_big_int() {
+        // let fact = big(1)
+        // let n = 1
+        // while n <= 25:
+        //    fact *= n
+        //    n += 1
+        // fact == 15511210043330985984000000
+        let mut vm = machine(vec![
+            Instruction::LoadVal.into(), 0x01, 0, 0, 0, 0, 0, 0, 0,
+            Instruction::ToBig.into(),
+            Instruction::WriteVar.into(), 0x66, 0x61, 0x63, 0x74, // "fact"
+            Instruction::LoadVal.into(), 0x01, 0, 0, 0, 0, 0, 0, 0,
+            Instruction::WriteVar.into(), 0x6E, 0x00, 0x00, 0x00, // "n"
+            Instruction::ReadVar.into(), 0x6E, 0x00, 0x00, 0x00,
+            Instruction::LoadVal.into(), 0x19, 0, 0, 0, 0, 0, 0, 0, // 25
+            Instruction::Lte.into(),
+            Instruction::JumpIfFalse.into(), 38,
+            Instruction::ReadVar.into(), 0x66, 0x61, 0x63, 0x74,
+            Instruction::ReadVar.into(), 0x6E, 0x00, 0x00, 0x00,
+            Instruction::Mul.into(), // big * int => big
+            Instruction::WriteVar.into(), 0x66, 0x61, 0x63, 0x74,
+            Instruction::ReadVar.into(), 0x6E, 0x00, 0x00, 0x00,
+            Instruction::LoadVal.into(), 0x01, 0, 0, 0, 0, 0, 0, 0,
+            Instruction::Add.into(),
+            Instruction::WriteVar.into(), 0x6E, 0x00, 0x00, 0x00,
+            Instruction::JumpBack.into(), 55,
+            Instruction::ReadVar.into(), 0x66, 0x61, 0x63, 0x74,
+            Instruction::LoadBig.into(), 11, 0x00, 0x00, 0xC0, 0x7B, 0x90, 0xB0, 0x9F, 0x61, 0xA0, 0xD4, 0x0C,
+            Instruction::Eq.into(),
+            Instruction::Finish.into(),
+        ]);
+
+        assert_eq!(vm.interpret().unwrap(), 1);
+
+        // i64::MAX promoted explicitly does not overflow
+        // big(i64::MAX) + 1 - 2^63 => 0
+        let mut vm_1 = machine([
+            vec![Instruction::LoadVal.into()],
+            i64::MAX.to_le_bytes().to_vec(),
+            vec![
+                Instruction::ToBig.into(),
+                Instruction::LoadVal.into(), 0x01, 0, 0, 0, 0, 0, 0, 0,
+                Instruction::Add.into(),
+                Instruction::LoadBig.into(), 9, 0, 0, 0, 0, 0, 0, 0, 0x80, 0x00,
+                Instruction::Sub.into(),
+                Instruction::ToInt.into(),
+                Instruction::Finish.into(),
+            ],
+        ].concat());
+
+        assert_eq!(vm_1.interpret().unwrap(), 0);
+
+        // 2^64 does not fit into i64
+        let mut vm_2 = machine(vec![
+            Instruction::LoadBig.into(), 9, 0, 0, 0, 0, 0, 0, 0, 0, 0x01,
+            Instruction::ToInt.into(),
+            Instruction::Finish.into(),
+        ]);
+
+        assert_eq!(vm_2.interpret().unwrap_err(), VMError::ArithmeticOverflow);
+    }
+
+    #[test]
+    fn test_decimal() {
+        // let subtotal = decimal("19.99") * 3
+        // let total = subtotal + round(subtotal * 0.0725, 2)
+        // total == 64.32, formatted as a string
+        let mut vm = machine(vec![
+            Instruction::LoadStr.into(), 5, 0x31, 0x39, 0x2E, 0x39, 0x39, // "19.99"
+            Instruction::StrToDec.into(),
+            Instruction::LoadVal.into(), 0x03, 0, 0, 0, 0, 0, 0, 0,
+            Instruction::DecMul.into(), 2, RoundingMode::HalfEven.into(), // 59.97
+            Instruction::Dup.into(),
+            Instruction::LoadDec.into(), 0xD5, 0x02, 0, 0, 0, 0, 0, 0, 4, // 0.0725
+            Instruction::DecMul.into(), 2, RoundingMode::HalfEven.into(), // 4.347825 => 4.35
+            Instruction::DecAdd.into(),
+            Instruction::Dup.into(),
+            Instruction::DecToStr.into(),
+            Instruction::Swap.into(),
+            Instruction::LoadDec.into(), 0x20, 0x19, 0, 0, 0, 0, 0, 0, 2, // 64.32
+            Instruction::DecCmp.into(),
+            Instruction::Finish.into(),
+        ]);
+
+        assert_eq!(vm.interpret().unwrap(), 0);
+        assert!(matches!(&vm.stack[..], [StackValue::Str(total)] if total == "64.32"));
+
+        // 100 / 3 => 33.34 rounded up, => 33 as an integer
+        let mut vm_1 = machine(vec![
+            Instruction::LoadVal.into(), 0x64, 0, 0, 0, 0, 0, 0, 0,
+            Instruction::ToDec.into(),
+            Instruction::LoadVal.into(), 0x03, 0, 0, 0, 0, 0, 0, 0,
+            Instruction::DecDiv.into(), 2, RoundingMode::Up.into(),
+            Instruction::DecToInt.into(), RoundingMode::HalfUp.into(),
+            Instruction::Finish.into(),
+        ]);
+
+        assert_eq!(vm_1.interpret().unwrap(), 33);
+
+        let mut vm_2 = machine(vec![
+            Instruction::LoadDec.into(), 0x01, 0, 0, 0, 0, 0, 0, 0, 2,
+            Instruction::LoadDec.into(), 0x00, 0, 0, 0, 0, 0, 0, 0, 2,
+            Instruction::DecDiv.into(), 2, RoundingMode::Down.into(),
+            Instruction::Finish.into(),
+        ]);
+
+        assert_eq!(vm_2.interpret().unwrap_err(), VMError::DivisionByZero);
+    }
+
+    #[test]
+    fn test_call_native() {
+        // hypot(3, 4) * 2 => 10
+        let mut program = Program::new(vec![
+            Instruction::LoadVal.into(), 0x03, 0, 0, 0, 0, 0, 0, 0,
+            Instruction::LoadVal.into(), 0x04, 0, 0, 0, 0, 0, 0, 0,
+            Instruction::CallNative.into(), 0x01, 0x00, // hypot
+            Instruction::LoadVal.into(), 0x02, 0, 0, 0, 0, 0, 0, 0,
+            Instruction::CallNativeNamed.into(), 3, 0x6D, 0x75, 0x6C, // "mul"
+            Instruction::Finish.into(),
+        ]).unwrap();
+        program.register_native("mul", 2, 1, |args| match args[..] {
+            [StackValue::Int(a), StackValue::Int(b)] => Ok(vec![StackValue::Int(a * b)]),
+            _ => Err("mul expects two integers".to_string()),
+        });
+        let hypot = program.register_native("hypot", 2, 1, |args| match args[..] {
+            [StackValue::Int(a), StackValue::Int(b)] => Ok(vec![StackValue::Int(((a * a + b * b) as f64).sqrt() as i64)]),
+            _ => Err("hypot expects two integers".to_string()),
+        });
+        let mut vm = Machine::new(Arc::new(program));
+
+        assert_eq!(hypot, 1);
+        assert_eq!(vm.interpret().unwrap(), 10);
+    }
+
+    #[test]
+    fn test_call_native_error() {
+        let mut program = Program::new(vec![
+            Instruction::LoadVal.into(), 0x00, 0, 0, 0, 0, 0, 0, 0,
+            Instruction::CallNative.into(), 0x00, 0x00,
+            Instruction::Finish.into(),
+        ]).unwrap();
+        program.register_native("sqrt", 1, 1, |args| match args[..] {
+            [StackValue::Int(a)] if a > 0 => Ok(vec![StackValue::Int((a as f64).sqrt() as i64)]),
+            _ => Err("sqrt expects a positive integer".to_string()),
+        });
+        let mut vm = Machine::new(Arc::new(program));
+
+        assert_eq!(vm.interpret().unwrap_err(), VMError::HostError("sqrt expects a positive integer".to_string()));
+
+        // signature says one result, function returns none
+        let mut program_1 = Program::new(vec![
+            Instruction::CallNative.into(), 0x00, 0x00,
+            Instruction::Finish.into(),
+        ]).unwrap();
+        program_1.register_native("noop", 0, 1, |_| Ok(vec![]));
+        let mut vm_1 = Machine::new(Arc::new(program_1));
+
+        assert!(matches!(vm_1.interpret().unwrap_err(), VMError::HostError(_)));
+
+        let mut vm_2 = machine(vec![
+            Instruction::CallNative.into(), 0x05, 0x00,
+            Instruction::Finish.into(),
+        ]);
+
+        assert_eq!(vm_2.interpret().unwrap_err(), VMError::UnknownHostFunction);
+    }
+
+    #[test]
+    #[should_panic(expected = "more than 65536 host functions registered")]
+    fn test_register_native_ids() {
+        let mut program = Program::new(vec![Instruction::Finish.into()]).unwrap();
+        for id in 0..=u16::MAX {
+            assert_eq!(program.register_native(&id.to_string(), 0, 0, |_| Ok(vec![])), id);
+        }
+        // a name registered again keeps its id, a new one has none left
+        assert_eq!(program.register_native("0", 0, 0, |_| Ok(vec![])), 0);
+        program.register_native("last", 0, 0, |_| Ok(vec![]));
+    }
+
+    #[test]
+    fn test_spawn() {
+        // child doubles whatever it receives and sends it back
+        let mut vm = machine(vec![
+            Instruction::Spawn.into(), 0x11, 0x00, 0x00, 0x00,
+            Instruction::LoadVal.into(), 0x15, 0, 0, 0, 0, 0, 0, 0, // 21
+            Instruction::SendChannel.into(),
+            Instruction::RecvChannel.into(),
+            Instruction::Finish.into(),
+            // child, index 17
+            Instruction::RecvChannel.into(),
+            Instruction::LoadVal.into(), 0x02, 0, 0, 0, 0, 0, 0, 0,
+            Instruction::Mul.into(),
+            Instruction::SendChannel.into(),
+            Instruction::Finish.into(),
+        ]);
+
+        // the child waiting for 21 holds the interrupt, without a host handle the machine still runs
+        // natively and waits on channels without polling
+        assert_eq!(vm.step(), Status::Yielded);
+        assert!(!vm.interrupt.is_shared());
+        assert_eq!(vm.interpret().unwrap(), 42);
+        let _handle = vm.interrupt_handle();
+        assert!(vm.interrupt.is_shared());
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let mut program = Program::new(vec![
+            Instruction::LoadVal.into(), 0x02, 0, 0, 0, 0, 0, 0, 0,
+            Instruction::CallNativeNamed.into(), 3, 0x69, 0x6E, 0x63, // "inc"
+            Instruction::CallNativeNamed.into(), 3, 0x6C, 0x6F, 0x67, // "log"
+            Instruction::Finish.into(),
+        ]).unwrap();
+        program.register_native("inc", 1, 1, |args| match args[..] {
+            [StackValue::Int(a)] => Ok(vec![StackValue::Int(a + 1)]),
+            _ => Err("inc expects an integer".to_string()),
+        });
+        program.register_native("log", 1, 1, Ok);
+        let program = Arc::new(program);
+
+        // capabilities belong to the machine, the program is shared
+        let mut vm = Machine::new(program.clone());
+        vm.capabilities.deny_native("log");
+
+        assert_eq!(vm.interpret().unwrap_err(), VMError::PermissionDenied);
+
+        let mut vm_1 = Machine::new(program);
+        vm_1.capabilities = Capabilities::none();
+        vm_1.capabilities.allow_native("inc");
+        vm_1.capabilities.allow_native("log");
+
+        assert_eq!(vm_1.interpret().unwrap(), 3);
+
+        let (sender, receiver) = crate::channel::channel();
+        let mut vm_2 = machine(vec![
+            Instruction::LoadVal.into(), 0x01, 0, 0, 0, 0, 0, 0, 0,
+            Instruction::SendChannel.into(),
+            Instruction::Finish.into(),
+        ]);
+        vm_2.stack.push(StackValue::Channel(sender, receiver));
+        vm_2.capabilities.send = false;
+
+        assert_eq!(vm_2.interpret().unwrap_err(), VMError::PermissionDenied);
+    }
+
+    #[test]
+    fn test_capabilities_attenuated_child() {
+        // child tries to send, but children are not allowed to
+        let mut vm = machine(vec![
+            Instruction::Spawn.into(), 0x07, 0x00, 0x00, 0x00,
+            Instruction::RecvChannel.into(),
+            Instruction::Finish.into(),
+            // child, index 7
+            Instruction::LoadVal.into(), 0x01, 0, 0, 0, 0, 0, 0, 0,
+            Instruction::SendChannel.into(),
+            Instruction::Finish.into(),
+        ]);
+        let mut children = Capabilities::all();
+        children.send = false;
+        vm.capabilities.children = Some(Box::new(children));
+
+        // child fails, the parent waiting on it gets the error
+        assert_eq!(vm.interpret().unwrap_err(), VMError::ChildFailed(Box::new(VMError::PermissionDenied)));
+
+        // so does one waiting in a task, when the child calls a host function it is not allowed to
+        let mut program = Program::new(vec![
+            Instruction::Spawn.into(), 0x07, 0x00, 0x00, 0x00,
+            Instruction::RecvChannel.into(),
+            Instruction::Finish.into(),
+            // child, index 7
+            Instruction::LoadVal.into(), 0x01, 0, 0, 0, 0, 0, 0, 0,
+            Instruction::CallNativeNamed.into(), 3, 0x69, 0x6E, 0x63, // "inc"
+            Instruction::SendChannel.into(),
+            Instruction::Finish.into(),
+        ]).unwrap();
+        program.register_native("inc", 1, 1, |args| match args[..] {
+            [StackValue::Int(a)] => Ok(vec![StackValue::Int(a + 1)]),
+            _ => Err("inc expects an integer".to_string()),
+        });
+        let program = Arc::new(program);
+        let mut vm = Machine::new(program.clone());
+        assert_eq!(vm.interpret().unwrap(), 2);
+
+        let mut vm = Machine::new(program);
+        let mut children = Capabilities::all();
+        children.deny_native("inc");
+        vm.capabilities.children = Some(Box::new(children));
+        assert_eq!(
+            block_on_all(vec![Box::pin(vm.interpret_async())]),
+            [Err(VMError::ChildFailed(Box::new(VMError::PermissionDenied)))]
+        );
+
+        // children never get more than their parent
+        let mut parent = Capabilities::none();
+        parent.allow_native("inc");
+        parent.children = Some(Box::new(Capabilities::all()));
+        let child = parent.child();
+
+        assert!(!child.send && !child.spawn);
+        assert_eq!(child.check_native("inc"), Ok(()));
+        assert_eq!(child.check_native("log"), Err(VMError::PermissionDenied));
+    }
+
+    #[test]
+    fn test_call_export() {
+        // fn square(x) { x * x }
+        // fn sum_of_squares(x, y) { square(x) + square(y) }
+        let mut program = Program::new(vec![
+            // square, index 0
+            Instruction::Dup.into(),
+            Instruction::Mul.into(),
+            Instruction::Return.into(),
+            // sum_of_squares, index 3
+            Instruction::Call.into(), 0x00, 0x00, 0x00, 0x00,
+            Instruction::Swap.into(),
+            Instruction::Call.into(), 0x00, 0x00, 0x00, 0x00,
+            Instruction::Add.into(),
+            Instruction::Return.into(),
+        ]).unwrap();
+        program.export("square", 0, 1, 1).unwrap();
+        program.export("sum_of_squares", 3, 2, 1).unwrap();
+        let mut vm = Machine::new(Arc::new(program));
+
+        assert!(matches!(vm.call("square", &[StackValue::Int(9)]).unwrap()[..], [StackValue::Int(81)]));
+        // the same program can be called again
+        for (x, y) in [(3, 4), (5, 12), (-2, 0)] {
+            let results = vm.call("sum_of_squares", &[StackValue::Int(x), StackValue::Int(y)]).unwrap();
+            assert!(matches!(results[..], [StackValue::Int(sum)] if sum == x * x + y * y));
+        }
+
+        assert_eq!(vm.call("square", &[]).unwrap_err(), VMError::ArityMismatch);
+        assert_eq!(vm.call("cube", &[StackValue::Int(2)]).unwrap_err(), VMError::UnknownFunction);
+    }
+
+    #[test]
+    fn test_call_depth() {
+        // fn forever() { forever() }
+        let mut program = Program::new(vec![
+            Instruction::Call.into(), 0x00, 0x00, 0x00, 0x00,
+            Instruction::Return.into(),
+        ]).unwrap();
+        program.export("forever", 0, 0, 0).unwrap();
+        let mut vm = Machine::new(Arc::new(program));
+
+        assert_eq!(vm.call("forever", &[]).unwrap_err(), VMError::StackOverflow);
+    }
+
+    #[test]
+    fn test_verify() {
+        assert_eq!(Program::new(vec![0xFF]).unwrap_err(), VMError::InvalidInstruction(0xFF));
+        assert_eq!(
+            Program::new(vec![Instruction::LoadVal.into(), 0x01, 0x00]).unwrap_err(),
+            VMError::TruncatedInstruction(0),
+        );
+        assert_eq!(
+            Program::new(vec![Instruction::LoadStr.into(), 0x05, 0x61]).unwrap_err(),
+            VMError::TruncatedInstruction(0),
+        );
+        // jumps into the middle of `LoadVal`
+        assert_eq!(
+            Program::new(vec![
+                Instruction::Jump.into(), 0x01,
+                Instruction::LoadVal.into(), 0x01, 0, 0, 0, 0, 0, 0, 0,
+                Instruction::Finish.into(),
+            ]).unwrap_err(),
+            VMError::InvalidJumpTarget(0),
+        );
+        assert_eq!(
+            Program::new(vec![Instruction::JumpBack.into(), 0x03]).unwrap_err(),
+            VMError::InvalidJumpTarget(0),
+        );
+
+        let mut program = Program::new(vec![
+            Instruction::LoadVal.into(), 0x01, 0, 0, 0, 0, 0, 0, 0,
+            Instruction::Return.into(),
+        ]).unwrap();
+
+        assert_eq!(program.export("one", 3, 0, 1).unwrap_err(), VMError::InvalidJumpTarget(3));
+    }
+
+    #[test]
+    fn test_shared_program() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Program>();
+
+        // fn triangle(n) { sum of 1..=n }
+        let mut program = Program::new(vec![
+            Instruction::WriteVar.into(), 0x6E, 0x00, 0x00, 0x00, // "n"
+            Instruction::LoadVal.into(), 0x00, 0, 0, 0, 0, 0, 0, 0,
+            Instruction::WriteVar.into(), 0x73, 0x00, 0x00, 0x00, // "s"
+            Instruction::ReadVar.into(), 0x6E, 0x00, 0x00, 0x00,
+            Instruction::JumpIfFalse.into(), 38,
+            Instruction::ReadVar.into(), 0x73, 0x00, 0x00, 0x00,
+            Instruction::ReadVar.into(), 0x6E, 0x00, 0x00, 0x00,
+            Instruction::Add.into(),
+            Instruction::WriteVar.into(), 0x73, 0x00, 0x00, 0x00,
+            Instruction::ReadVar.into(), 0x6E, 0x00, 0x00, 0x00,
+            Instruction::LoadVal.into(), 0x01, 0, 0, 0, 0, 0, 0, 0,
+            Instruction::Sub.into(),
+            Instruction::WriteVar.into(), 0x6E, 0x00, 0x00, 0x00,
+            Instruction::JumpBack.into(), 45,
+            Instruction::ReadVar.into(), 0x73, 0x00, 0x00, 0x00,
+            Instruction::Return.into(),
+        ]).unwrap();
+        program.export("triangle", 0, 1, 1).unwrap();
+        let program = Arc::new(program);
+
+        let handles = (1..=8)
+            .map(|n| {
+                let program = program.clone();
+                std::thread::spawn(move || {
+                    let mut vm = Machine::new(program);
+                    vm.call("triangle", &[StackValue::Int(n * 10)]).unwrap()
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for (n, handle) in (1..=8).zip(handles) {
+            assert!(matches!(handle.join().unwrap()[..], [StackValue::Int(sum)] if sum == n * 10 * (n * 10 + 1) / 2));
+        }
+
+        let mut vm = Machine::new(program);
+        vm.call("triangle", &[StackValue::Int(3)]).unwrap();
+        vm.reset();
+
+        assert!(vm.stack.is_empty() && vm.variables.is_empty() && vm.ip == 0);
+    }
+
+    #[test]
+    fn test_module() {
+        let mut program = Program::new(vec![
+            Instruction::LoadVal.into(), 0x07, 0, 0, 0, 0, 0, 0, 0,
+            Instruction::Mul.into(),
+            Instruction::Return.into(),
+        ]).unwrap();
+        program.export("times_seven", 0, 1, 1).unwrap();
+        let mut module = program.to_module();
+        module.debug_info = vec![LineEntry { ip: 0, line: 1 }, LineEntry { ip: 10, line: 2 }];
+        module.custom_sections.push(CustomSection { name: "source".to_string(), data: b"x * 7".to_vec() });
+
+        let bytes = module.write().unwrap();
+        assert_eq!(bytes[..4], *b"\0spt");
+        assert_eq!(Module::read(&bytes).unwrap(), module);
+
+        let program = Program::load(&bytes).unwrap();
+        assert_eq!(program.line(9), Some(1));
+        assert_eq!(program.line(10), Some(2));
+        assert_eq!(program.custom_sections()[0].name, "source");
+
+        let mut vm = Machine::new(Arc::new(program));
+        assert!(matches!(vm.call("times_seven", &[StackValue::Int(6)]).unwrap()[..], [StackValue::Int(42)]));
+    }
+
+    #[test]
+    fn test_constant_pool() {
+        let big = BigInt::from(u64::MAX) * 3u8;
+        let constants = vec![
+            Constant::Ident("counter".to_string()),
+            Constant::Int(i64::MAX),
+            Constant::BigInt(big.clone()),
+            Constant::Str("hello".to_string()),
+            Constant::Ident("n".to_string()),
+        ];
+        let code = vec![
+            Instruction::LoadConst.into(), 1, 0,
+            Instruction::WriteVarConst.into(), 0, 0,
+            Instruction::LoadVal.into(), 0x05, 0, 0, 0, 0, 0, 0, 0,
+            Instruction::WriteVar.into(), b'n', 0, 0, 0,
+            Instruction::LoadConst.into(), 3, 0,
+            Instruction::LoadConst.into(), 2, 0,
+            Instruction::ReadVarConst.into(), 0, 0,
+            Instruction::ReadVarConst.into(), 4, 0,
+            Instruction::Finish.into(),
+        ];
+        let program = Program::with_constants(code, constants).unwrap();
+        let bytes = program.to_module().write().unwrap();
+        assert_eq!(Module::read(&bytes).unwrap().constants, program.constants());
+
+        let mut vm = Machine::new(Arc::new(Program::load(&bytes).unwrap()));
+        vm.run().unwrap();
+        assert!(matches!(vm.stack[..], [
+            StackValue::Str(ref s), StackValue::BigInt(ref b), StackValue::Int(i64::MAX), StackValue::Int(5)
+        ] if s == "hello" && *b == big));
+        assert!(vm.variables.contains_key("counter") && vm.variables.contains_key("n"));
+
+        // out of the pool, and a variable named by something else than an identifier
+        let constants = vec![Constant::Int(1)];
+        let out_of_pool = vec![Instruction::LoadConst.into(), 1, 0];
+        assert_eq!(Program::with_constants(out_of_pool, constants.clone()).unwrap_err(), VMError::InvalidConstant(0));
+        let not_ident = vec![Instruction::Finish.into(), Instruction::ReadVarConst.into(), 0, 0];
+        assert_eq!(Program::with_constants(not_ident, constants).unwrap_err(), VMError::InvalidConstant(1));
+    }
+
+    #[test]
+    fn test_module_errors() {
+        let bytes = Module { code: vec![Instruction::Finish.into()], ..Module::default() }.write().unwrap();
+
+        assert_eq!(Module::read(&bytes[..3]).unwrap_err(), VMError::BadMagic);
+        assert_eq!(Module::read(b"#!/bin/sh\n").unwrap_err(), VMError::BadMagic);
+
+        let mut future = bytes.clone();
+        future[4..6].copy_from_slice(&3u16.to_le_bytes());
+        assert_eq!(Module::read(&future).unwrap_err(), VMError::UnsupportedVersion(3));
+        // version 1 encoded call addresses as big endian `u16`
+        let mut old = bytes.clone();
+        old[4..6].copy_from_slice(&1u16.to_le_bytes());
+        assert_eq!(Module::read(&old).unwrap_err(), VMError::UnsupportedVersion(1));
+
+        // code section runs past the end of the module
+        assert_eq!(Module::read(&bytes[..bytes.len() - 1]).unwrap_err(), VMError::MalformedModule);
+
+        // code is verified when the module is loaded
+        let invalid = Module { code: vec![0xFF], ..Module::default() }.write().unwrap();
+        assert_eq!(Program::load(&invalid).unwrap_err(), VMError::InvalidInstruction(0xFF));
+
+        // counts and addresses that do not fit their fields are not truncated
+        let export = Export { address: 0, arity: 0, returns: 0 };
+        let exports = (0..=u16::MAX as usize).map(|i| (i.to_string(), export.clone())).collect();
+        assert_eq!(Module { exports, ..Module::default() }.write().unwrap_err(), VMError::MalformedModule);
+        let exports = [("f".to_string(), Export { address: 1 << 32, ..export })].into();
+        assert_eq!(Module { exports, ..Module::default() }.write().unwrap_err(), VMError::MalformedModule);
+    }
+}